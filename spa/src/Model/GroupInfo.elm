@@ -12,6 +12,9 @@ type alias GroupInfo =
     { name : String
     , description : Maybe String
     , policyStatements : List PolicyStatement
+    , policies : List String
+    , parents : List String
+    , revision : Maybe Int
     }
 
 
@@ -21,6 +24,9 @@ decoder =
         |> required "name" D.string
         |> optional "description" (maybe D.string) Nothing
         |> required "policy_statements" (list PolicyStatement.decoder)
+        |> optional "policies" (list D.string) []
+        |> optional "parents" (list D.string) []
+        |> optional "revision" (maybe D.int) Nothing
 
 
 encoder : GroupInfo -> Value
@@ -30,6 +36,9 @@ encoder u =
             [ Just ( "name", E.string u.name )
             , Maybe.map (\v -> ( "description", E.string v )) u.description
             , Just ( "policy_statements", E.list PolicyStatement.encoder u.policyStatements )
+            , Just ( "policies", E.list E.string u.policies )
+            , Just ( "parents", E.list E.string u.parents )
+            , Maybe.map (\v -> ( "revision", E.int v )) u.revision
             ]
         )
 
@@ -39,6 +48,9 @@ new =
     { name = ""
     , description = Nothing
     , policyStatements = []
+    , policies = []
+    , parents = []
+    , revision = Nothing
     }
 
 
@@ -13,6 +13,8 @@ type alias UserInfo =
     , fullName : Maybe String
     , groups : List String
     , policyStatements : List PolicyStatement
+    , policies : List String
+    , revision : Maybe Int
     }
 
 
@@ -23,6 +25,8 @@ decoder =
         |> optional "full_name" (maybe D.string) Nothing
         |> required "groups" (list D.string)
         |> required "policy_statements" (list PolicyStatement.decoder)
+        |> optional "policies" (list D.string) []
+        |> optional "revision" (maybe D.int) Nothing
 
 
 encoder : UserInfo -> Value
@@ -33,6 +37,8 @@ encoder u =
             , Maybe.map (\v -> ( "full_name", E.string v )) u.fullName
             , Just ( "groups", E.list E.string u.groups )
             , Just ( "policy_statements", E.list PolicyStatement.encoder u.policyStatements )
+            , Just ( "policies", E.list E.string u.policies )
+            , Maybe.map (\v -> ( "revision", E.int v )) u.revision
             ]
         )
 
@@ -148,7 +148,7 @@ update sharedModel req msg model =
                 |> R.update req response
 
         CreateClicked ->
-            startEditing model (Creating { loginName = "", fullName = Nothing, groups = [], policyStatements = [] })
+            startEditing model (Creating { loginName = "", fullName = Nothing, groups = [], policyStatements = [], policies = [], revision = Nothing })
 
         UserClicked user ->
             { model
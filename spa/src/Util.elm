@@ -88,6 +88,15 @@ httpErrorToString error =
         Http.NetworkError ->
             "Network Error"
 
+        Http.BadStatus 401 ->
+            "You are not logged in"
+
+        Http.BadStatus 403 ->
+            "Access denied"
+
+        Http.BadStatus 404 ->
+            "Not found"
+
         Http.BadStatus i ->
             "Server returned status " ++ String.fromInt i
 
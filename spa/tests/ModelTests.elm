@@ -28,6 +28,8 @@ userA =
     , fullName = Just "User A"
     , groups = [ "groupa", "groupb" ]
     , policyStatements = [ allowEverything ]
+    , policies = [ "SharedPolicy" ]
+    , revision = Just 3
     }
 
 
@@ -36,6 +38,9 @@ groupA =
     { name = "groupa"
     , description = Just "Group A"
     , policyStatements = [ allowEverything ]
+    , policies = [ "SharedPolicy" ]
+    , parents = [ "groupb" ]
+    , revision = Just 3
     }
 
 
@@ -0,0 +1,470 @@
+use crate::auth::bundle::PlannedChange;
+use crate::auth::condition;
+use crate::auth::session::Session;
+use crate::util::now_as_secs;
+use log::warn;
+use rocket::http::Status;
+use rocket::outcome::IntoOutcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::{self, Value};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+#[cfg(test)]
+#[path = "audit_tests.rs"]
+mod audit_tests;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub enum AuditCategory {
+    Authentication,
+    Denial,
+    PolicyChange,
+    File,
+}
+
+/// A field of a policy store document which was changed by a write.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldChange {
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditEvent {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub category: AuditCategory,
+    /// What was done, e.g. `Login`, `UpdateUser` or `file:Write`.
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    /// What it was done to: a file path or a resource ID like `user:dan`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+impl AuditEvent {
+    pub fn new(category: AuditCategory, action: &str) -> AuditEvent {
+        AuditEvent {
+            time: now_as_secs().unwrap_or(0),
+            category,
+            action: String::from(action),
+            user: None,
+            client_ip: None,
+            resource: None,
+            size: None,
+            changes: BTreeMap::new(),
+        }
+    }
+
+    pub fn user(self, user: Option<&str>) -> AuditEvent {
+        AuditEvent {
+            user: user.map(String::from),
+            ..self
+        }
+    }
+
+    pub fn client_ip(self, client_ip: Option<IpAddr>) -> AuditEvent {
+        AuditEvent { client_ip, ..self }
+    }
+
+    pub fn resource<R: Into<String>>(self, resource: R) -> AuditEvent {
+        AuditEvent {
+            resource: Some(resource.into()),
+            ..self
+        }
+    }
+
+    pub fn size(self, size: Option<u64>) -> AuditEvent {
+        AuditEvent { size, ..self }
+    }
+
+    /// Records the top-level fields which differ between two versions of a
+    /// document. `None` stands for a document which doesn't exist.
+    pub fn changes<T: Serialize>(self, before: Option<&T>, after: Option<&T>) -> AuditEvent {
+        AuditEvent {
+            changes: diff(before, after),
+            ..self
+        }
+    }
+}
+
+fn fields<T: Serialize>(doc: Option<&T>) -> BTreeMap<String, Value> {
+    match doc.and_then(|d| json::to_value(d).ok()) {
+        Some(Value::Object(fields)) => fields.into_iter().collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// The top-level fields which differ between two versions of a document.
+pub fn diff<T: Serialize>(before: Option<&T>, after: Option<&T>) -> BTreeMap<String, FieldChange> {
+    let before = fields(before);
+    let after = fields(after);
+    before
+        .keys()
+        .chain(after.keys())
+        .filter(|k| before.get(*k) != after.get(*k))
+        .map(|k| {
+            (
+                k.clone(),
+                FieldChange {
+                    before: before.get(k).cloned(),
+                    after: after.get(k).cloned(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Criteria for selecting audit events. Every criterion which is present must
+/// match.
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    pub action: Option<String>,
+    pub path_prefix: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.user
+            .as_ref()
+            .map(|u| event.user.as_ref() == Some(u))
+            .unwrap_or(true)
+            && self
+                .action
+                .as_ref()
+                .map(|a| &event.action == a)
+                .unwrap_or(true)
+            && self
+                .path_prefix
+                .as_ref()
+                .map(|p| matches!(&event.resource, Some(r) if r.starts_with(p.as_str())))
+                .unwrap_or(true)
+            && self.since.map(|s| event.time >= s).unwrap_or(true)
+            && self.until.map(|u| event.time < u).unwrap_or(true)
+    }
+}
+
+/// An append-only log of audit events, one JSON document per line. When the
+/// log grows past `max_bytes` it is rotated to `<path>.1`, `<path>.2` and so
+/// on, keeping at most `keep` old files. Events are written by a thread of
+/// the log's own so that recording one, e.g. from a request guard, never does
+/// file I/O on the caller's thread.
+#[derive(Clone)]
+pub struct AuditLog {
+    files: LogFiles,
+    writer: Arc<Writer>,
+}
+
+#[derive(Clone)]
+struct LogFiles {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+}
+
+/// A log file opened for reading, along with how much of it had been
+/// written when it was opened.
+type Opened = (PathBuf, File, u64);
+
+enum Command {
+    Append(AuditEvent),
+    /// Opens every log file, oldest first, once the events sent before have
+    /// been written.
+    Open(mpsc::Sender<io::Result<Vec<Opened>>>),
+}
+
+/// The writer thread, which finishes writing the events sent to it once the
+/// last copy of the log is dropped.
+struct Writer {
+    sender: Option<mpsc::Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl LogFiles {
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let size = match fs::metadata(&self.path) {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if size < self.max_bytes {
+            return Ok(());
+        }
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn append(&self, event: &AuditEvent) -> io::Result<()> {
+        self.rotate()?;
+        let mut line = json::to_string(event).map_err(io::Error::other)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    fn open(&self) -> io::Result<Vec<Opened>> {
+        let mut paths = (1..=self.keep)
+            .rev()
+            .map(|n| self.rotated(n))
+            .collect::<Vec<PathBuf>>();
+        paths.push(self.path.clone());
+        let mut opened = Vec::new();
+        for path in paths {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let len = file.metadata()?.len();
+            opened.push((path, file, len));
+        }
+        Ok(opened)
+    }
+
+    fn write(&self, commands: mpsc::Receiver<Command>) {
+        for command in commands {
+            match command {
+                Command::Append(event) => {
+                    if let Err(e) = self.append(&event) {
+                        warn!("Failed to write audit event {event:?}: {e}");
+                    }
+                }
+                Command::Open(reply) => {
+                    let _ = reply.send(self.open());
+                }
+            }
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P, max_bytes: u64, keep: usize) -> io::Result<AuditLog> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let files = LogFiles {
+            path,
+            max_bytes,
+            keep,
+        };
+        let (sender, commands) = mpsc::channel();
+        let writer = files.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("audit-log"))
+            .spawn(move || writer.write(commands))?;
+        Ok(AuditLog {
+            files,
+            writer: Arc::new(Writer {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.writer
+            .sender
+            .as_ref()
+            .and_then(|sender| sender.send(command).ok())
+            .ok_or_else(|| io::Error::other("The audit log writer has stopped"))
+    }
+
+    /// Appends an event to the log. The event is written in the background
+    /// and a failure to write it is logged but doesn't interrupt the request
+    /// being audited.
+    pub fn record(&self, event: AuditEvent) {
+        if let Err(e) = self.send(Command::Append(event)) {
+            warn!("Failed to write an audit event: {e}");
+        }
+    }
+
+    /// Events matching the query, oldest first, including every event
+    /// recorded before the query was made. The files are read through handles
+    /// of the query's own so the writer carries on meanwhile. This blocks, so
+    /// use `Audit::query` from async code.
+    pub fn query(&self, query: &AuditQuery) -> io::Result<Vec<AuditEvent>> {
+        let (reply, opened) = mpsc::channel();
+        self.send(Command::Open(reply))?;
+        let opened = opened.recv().map_err(io::Error::other)??;
+        let mut events = Vec::new();
+        for (path, file, len) in opened {
+            // Only what was written when the file was opened, so a line
+            // being appended isn't read half-written.
+            for line in BufReader::new(file.take(len)).lines() {
+                match json::from_str::<AuditEvent>(&line?) {
+                    Ok(event) if query.matches(&event) => events.push(event),
+                    Ok(_) => (),
+                    Err(e) => warn!("Skipping unreadable audit event in {path:?}: {e}"),
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// The audit log along with who is making the current request and from where.
+pub struct Audit {
+    log: AuditLog,
+    user: Option<String>,
+    client_ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Audit, ()> {
+        let user = request
+            .guard::<Session>()
+            .await
+            .succeeded()
+            .map(|s| s.user.login_name.into());
+        request
+            .rocket()
+            .state::<AuditLog>()
+            .cloned()
+            .map(|log| Audit {
+                log,
+                user,
+                client_ip: condition::client_ip(request),
+            })
+            .into_outcome((Status::InternalServerError, ()))
+    }
+}
+
+impl Audit {
+    /// Attributes events to `login_name` rather than the session user, for
+    /// requests authorized some other way.
+    pub fn attributed_to(self, login_name: &str) -> Audit {
+        Audit {
+            user: Some(String::from(login_name)),
+            ..self
+        }
+    }
+
+    /// For changes made outside of a request, e.g. from the command line.
+    pub fn local(log: AuditLog, user: Option<String>) -> Audit {
+        Audit {
+            log,
+            user,
+            client_ip: None,
+        }
+    }
+
+    fn event(&self, category: AuditCategory, action: &str) -> AuditEvent {
+        AuditEvent::new(category, action)
+            .user(self.user.as_deref())
+            .client_ip(self.client_ip)
+    }
+
+    /// Records a login attempt by `login_name`, who isn't the session user yet.
+    pub fn login(&self, login_name: &str, succeeded: bool) {
+        let action = if succeeded { "Login" } else { "LoginFailed" };
+        self.log.record(
+            self.event(AuditCategory::Authentication, action)
+                .user(Some(login_name)),
+        );
+    }
+
+    pub fn logout(&self) {
+        self.log
+            .record(self.event(AuditCategory::Authentication, "Logout"));
+    }
+
+    pub fn policy_change<T: Serialize>(
+        &self,
+        action: &str,
+        resource: &str,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) {
+        self.log.record(
+            self.event(AuditCategory::PolicyChange, action)
+                .resource(resource)
+                .changes(before, after),
+        );
+    }
+
+    /// Records a document changed by importing a bundle. The changes are the
+    /// import's own, which leave out password hashes.
+    pub fn import(&self, planned: &PlannedChange) {
+        self.planned(planned, "ImportPolicyStore");
+    }
+
+    /// Records a document changed to match the declared policy.
+    pub fn reconcile(&self, planned: &PlannedChange) {
+        self.planned(planned, "ReconcilePolicyStore");
+    }
+
+    fn planned(&self, planned: &PlannedChange, action: &str) {
+        let event = self
+            .event(AuditCategory::PolicyChange, action)
+            .resource(format!("{}:{}", planned.kind, planned.name));
+        self.log.record(AuditEvent {
+            changes: planned.changes.clone(),
+            ..event
+        });
+    }
+
+    pub fn file<P: AsRef<Path>>(&self, action: &str, logical_path: P, size: Option<u64>) {
+        self.log.record(
+            self.event(AuditCategory::File, action)
+                .resource(logical_path.as_ref().to_string_lossy())
+                .size(size),
+        );
+    }
+
+    pub async fn query(&self, query: AuditQuery) -> io::Result<Vec<AuditEvent>> {
+        let log = self.log.clone();
+        rocket::tokio::task::spawn_blocking(move || log.query(&query))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
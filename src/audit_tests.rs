@@ -0,0 +1,110 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static LOG_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_log(max_bytes: u64, keep: usize) -> AuditLog {
+    let dir = std::env::temp_dir().join(format!(
+        "swaf-audit-{}-{}",
+        std::process::id(),
+        LOG_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    AuditLog::new(dir.join("audit.log"), max_bytes, keep).unwrap()
+}
+
+fn event(user: &str, action: &str, resource: &str, time: u64) -> AuditEvent {
+    AuditEvent {
+        time,
+        ..AuditEvent::new(AuditCategory::File, action)
+            .user(Some(user))
+            .resource(resource)
+    }
+}
+
+#[test]
+fn test_query_filters() {
+    let log = temp_log(1024 * 1024, 1);
+    log.record(event("dan", "file:Read", "docs/a.txt", 100));
+    log.record(event("dan", "file:Write", "docs/b.txt", 200));
+    log.record(event("bob", "file:Read", "home/bob/c.txt", 300));
+
+    let count = |query: AuditQuery| log.query(&query).unwrap().len();
+    assert_eq!(3, count(AuditQuery::default()));
+    assert_eq!(
+        2,
+        count(AuditQuery {
+            user: Some(String::from("dan")),
+            ..Default::default()
+        })
+    );
+    assert_eq!(
+        2,
+        count(AuditQuery {
+            action: Some(String::from("file:Read")),
+            ..Default::default()
+        })
+    );
+    assert_eq!(
+        1,
+        count(AuditQuery {
+            path_prefix: Some(String::from("home/")),
+            ..Default::default()
+        })
+    );
+    assert_eq!(
+        1,
+        count(AuditQuery {
+            since: Some(150),
+            until: Some(300),
+            ..Default::default()
+        })
+    );
+}
+
+#[test]
+fn test_events_are_written_before_the_log_is_dropped() {
+    let log = temp_log(1024 * 1024, 1);
+    let path = log.files.path.clone();
+    for t in 0..100 {
+        log.record(event("dan", "file:Read", "a.txt", t));
+    }
+    drop(log);
+
+    let reopened = AuditLog::new(&path, 1024 * 1024, 1).unwrap();
+    assert_eq!(100, reopened.query(&AuditQuery::default()).unwrap().len());
+}
+
+#[test]
+fn test_rotation_keeps_limited_history() {
+    let log = temp_log(1, 2);
+    for t in 0..5 {
+        log.record(event("dan", "file:Read", "a.txt", t));
+    }
+    let times = log
+        .query(&AuditQuery::default())
+        .unwrap()
+        .iter()
+        .map(|e| e.time)
+        .collect::<Vec<u64>>();
+    assert_eq!(vec![2, 3, 4], times);
+    assert!(log.files.rotated(1).exists());
+    assert!(log.files.rotated(2).exists());
+    assert!(!log.files.rotated(3).exists());
+}
+
+#[test]
+fn test_changes_only_include_differing_fields() {
+    let before = json::json!({"name": "admins", "parents": [], "policies": ["A"]});
+    let after = json::json!({"name": "admins", "parents": [], "policies": ["A", "B"]});
+    let event = AuditEvent::new(AuditCategory::PolicyChange, "UpdateGroup")
+        .changes(Some(&before), Some(&after));
+    assert_eq!(
+        vec!["policies"],
+        event.changes.keys().collect::<Vec<&String>>()
+    );
+    let created =
+        AuditEvent::new(AuditCategory::PolicyChange, "CreateGroup").changes(None, Some(&after));
+    assert_eq!(3, created.changes.len());
+    assert!(created.changes.values().all(|c| c.before.is_none()));
+}
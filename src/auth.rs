@@ -1,9 +1,18 @@
 pub mod authorizor;
+pub mod bundle;
+pub mod condition;
+pub mod declared;
+pub mod lockout;
+pub mod name;
 pub mod policy;
+pub mod revision;
 pub mod session;
+pub mod signed_url;
 pub mod store;
+pub mod validation;
 
 use crate::auth::authorizor::RequestAuthorizor;
+use crate::error::guard_failure;
 use crate::files::RequestedFile;
 use crate::meta::{file_children, metadata_for_file, FileMetadata};
 use rocket::http::Status;
@@ -25,12 +34,9 @@ impl<'r> FromRequest<'r> for RequestedFileDataReadable {
         request: &'r Request<'_>,
     ) -> Outcome<RequestedFileDataReadable, &'static str> {
         let file = try_outcome!(request.guard::<RequestedFile>().await);
-        let authorizor = try_outcome!(request
-            .guard::<RequestAuthorizor>()
-            .await
-            .map_failure(|(s, _)| (s, "No session authorizor")));
+        let authorizor = try_outcome!(request.guard::<RequestAuthorizor>().await);
         authorizor
-            .require("file:Read", &file.real_path) // TODO: I think this should be the logical_path
+            .require("file:Read", &file.logical_path)
             .ok()
             .map(|_| {
                 Outcome::Success(RequestedFileDataReadable {
@@ -38,7 +44,7 @@ impl<'r> FromRequest<'r> for RequestedFileDataReadable {
                     logical_path: file.logical_path,
                 })
             })
-            .unwrap_or_else(|e| Outcome::Failure((e, "Access Denied")))
+            .unwrap_or_else(|e| guard_failure(request, e, "Access denied"))
     }
 }
 
@@ -55,12 +61,9 @@ impl<'r> FromRequest<'r> for RequestedFileDataWritable {
         request: &'r Request<'_>,
     ) -> Outcome<RequestedFileDataWritable, &'static str> {
         let file = try_outcome!(request.guard::<RequestedFile>().await);
-        let authorizor = try_outcome!(request
-            .guard::<RequestAuthorizor>()
-            .await
-            .map_failure(|(s, _)| (s, "No session authorizor")));
+        let authorizor = try_outcome!(request.guard::<RequestAuthorizor>().await);
         authorizor
-            .require("file:Write", &file.real_path) // TODO: I think this should be the logical_path
+            .require("file:Write", &file.logical_path)
             .ok()
             .map(|_| {
                 Outcome::Success(RequestedFileDataWritable {
@@ -68,12 +71,13 @@ impl<'r> FromRequest<'r> for RequestedFileDataWritable {
                     logical_path: file.logical_path,
                 })
             })
-            .unwrap_or_else(|e| Outcome::Failure((e, "Access Denied")))
+            .unwrap_or_else(|e| guard_failure(request, e, "Access denied"))
     }
 }
 
 pub struct RequestedRegularFileDataReadable {
     pub real_path: PathBuf,
+    pub logical_path: PathBuf,
 }
 
 #[rocket::async_trait]
@@ -90,12 +94,14 @@ impl<'r> FromRequest<'r> for RequestedRegularFileDataReadable {
                 if f.real_path.is_file() {
                     Outcome::Success(RequestedRegularFileDataReadable {
                         real_path: f.real_path,
+                        logical_path: f.logical_path,
                     })
                 } else {
-                    Outcome::Failure((
+                    guard_failure(
+                        request,
                         Status::NotFound,
                         "Requested path does not exist or is not a regular file.",
-                    ))
+                    )
                 }
             })
     }
@@ -121,10 +127,11 @@ impl<'r> FromRequest<'r> for RequestedDirectoryWritable {
                         real_path: f.real_path,
                     })
                 } else {
-                    Outcome::Failure((
+                    guard_failure(
+                        request,
                         Status::NotFound,
                         "Requested path does not exist or is not a directory.",
-                    ))
+                    )
                 }
             })
     }
@@ -135,19 +142,16 @@ impl<'r> FromRequest<'r> for FileMetadata {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<FileMetadata, &'static str> {
-        let authorizor = try_outcome!(request
-            .guard::<RequestAuthorizor>()
-            .await
-            .map_failure(|(s, _)| (s, "No session authorizor")));
+        let authorizor = try_outcome!(request.guard::<RequestAuthorizor>().await);
         let file = try_outcome!(request.guard::<RequestedFile>().await);
         if !authorizor.is_allowed("file:Read", &file.logical_path) {
-            return Outcome::Failure((Status::Forbidden, "Access Denied"));
+            return guard_failure(request, Status::Forbidden, "Access denied");
         };
         match metadata_for_file(&file.real_path, &file.logical_path, &authorizor) {
             Ok(m) => Outcome::Success(m),
             Err(e) => {
                 warn!("Error fetching metadata for {:?}: {:?}", file.real_path, e);
-                Outcome::Failure((Status::InternalServerError, "Internal Error"))
+                guard_failure(request, Status::InternalServerError, "Internal Error")
             }
         }
     }
@@ -164,19 +168,16 @@ impl<'r> FromRequest<'r> for FileChildren {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<FileChildren, &'static str> {
-        let authorizor = try_outcome!(request
-            .guard::<RequestAuthorizor>()
-            .await
-            .map_failure(|(s, _)| (s, "No session authorizor")));
+        let authorizor = try_outcome!(request.guard::<RequestAuthorizor>().await);
         let file = try_outcome!(request.guard::<RequestedFile>().await);
         if !authorizor.is_allowed("file:Read", &file.logical_path) {
-            return Outcome::Failure((Status::Forbidden, "Access Denied"));
+            return guard_failure(request, Status::Forbidden, "Access denied");
         };
         match file_children(&file.real_path, &file.logical_path, &authorizor) {
             Ok(children) => Outcome::Success(FileChildren { children }),
             Err(e) => {
                 warn!("Error fetching metadata for {:?}: {:?}", file.real_path, e);
-                Outcome::Failure((Status::InternalServerError, "Internal Error"))
+                guard_failure(request, Status::InternalServerError, "Internal Error")
             }
         }
     }
@@ -1,48 +1,150 @@
-use crate::auth::policy::{Effect, Group, PolicyStatement, PolicyStore};
+use crate::audit::{AuditCategory, AuditEvent, AuditLog};
+use crate::auth::condition::RequestContext;
+use crate::auth::policy::{Effect, PolicyStatement, PolicyStore, PolicyVariables, User};
 use crate::auth::session::Session;
-use crate::auth::store::files::FilePolicyStore;
+use crate::auth::store::BoxedPolicyStore;
+use crate::error::Noted;
 use crate::meta::MetadataAuthorizor;
-use futures::executor;
 use log::{info, warn};
 use rocket::http::Status;
 use rocket::outcome::try_outcome;
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::Serialize;
 use rocket::State;
 use std::path::{Path, PathBuf};
 
 pub struct RequestAuthorizor {
     username: String,
-    policy_statements: Vec<PolicyStatement>,
+    policy_statements: Vec<SourcedStatement>,
+    restriction: Option<(String, String)>,
+    context: RequestContext,
+    audit: Option<AuditLog>,
 }
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for RequestAuthorizor {
-    type Error = ();
-
-    async fn from_request(request: &'r Request<'_>) -> Outcome<RequestAuthorizor, ()> {
-        let user = try_outcome!(request.guard::<Session>().await.map(|session| session.user));
-        let policy_store = try_outcome!(executor::block_on(
-            request.guard::<&State<FilePolicyStore>>()
-        ));
-        let policy_statements: Vec<PolicyStatement> = user.policy_statements;
-        let groups = user
-            .groups
-            .iter()
-            .map(|g| policy_store.group_named(g))
-            .filter(|o| o.is_some())
-            .flatten()
-            .collect::<Vec<Group>>();
-        let policy_statements = groups
-            .iter()
-            .flat_map(|g| &g.policy_statements)
-            .cloned()
-            .chain(policy_statements)
-            .collect::<Vec<PolicyStatement>>();
-        Outcome::Success(RequestAuthorizor {
-            username: user.login_name,
-            policy_statements,
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<RequestAuthorizor, &'static str> {
+        let session = try_outcome!(request.guard::<Session>().await);
+        let policy_store = try_outcome!(request
+            .guard::<&State<BoxedPolicyStore>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, "No policy store"))
+            .noted(request));
+        let context = RequestContext::capture(request);
+        Outcome::Success(
+            RequestAuthorizor::for_user(policy_store.inner(), session.user, context)
+                .await
+                .audited(request.rocket().state::<AuditLog>().cloned()),
+        )
+    }
+}
+
+/// Where a policy statement in an authorizor came from.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", tag = "kind")]
+pub enum PolicySource {
+    User {
+        name: String,
+    },
+    Group {
+        name: String,
+    },
+    Policy {
+        name: String,
+        attached_to: Box<PolicySource>,
+    },
+}
+
+/// A policy statement, with its variables expanded, and where it came from.
+#[derive(Debug, Clone)]
+pub struct SourcedStatement {
+    pub source: PolicySource,
+    pub statement: PolicyStatement,
+}
+
+fn sourced(
+    source: &PolicySource,
+    statements: &[PolicyStatement],
+    variables: &PolicyVariables,
+) -> Vec<SourcedStatement> {
+    statements
+        .iter()
+        .map(|s| SourcedStatement {
+            source: source.clone(),
+            statement: s.expand(variables),
         })
+        .collect()
+}
+
+async fn managed<S: PolicyStore + ?Sized>(
+    policy_store: &S,
+    attached_to: &PolicySource,
+    names: &[String],
+    variables: &PolicyVariables,
+) -> Vec<SourcedStatement> {
+    let mut statements = Vec::new();
+    for name in names {
+        let p = match policy_store.policy_named(name).await {
+            Some(p) => p,
+            None => {
+                warn!("Attached policy '{name}' does not exist.");
+                continue;
+            }
+        };
+        let source = PolicySource::Policy {
+            name: p.name.clone(),
+            attached_to: Box::new(attached_to.clone()),
+        };
+        statements.extend(sourced(&source, &p.policy_statements, variables));
     }
+    statements
+}
+
+/// Gathers the statements which apply to `user`: those of their groups and
+/// the groups' ancestors, then their attached policies and their own.
+pub async fn resolve_policy_statements<S: PolicyStore + ?Sized>(
+    policy_store: &S,
+    user: &User,
+) -> Vec<SourcedStatement> {
+    let variables = PolicyVariables::for_user(user);
+    let groups = policy_store.expand_groups(&user.groups).await;
+    let user_source = PolicySource::User {
+        name: user.login_name.to_string(),
+    };
+    let mut policy_statements = Vec::new();
+    for group in &groups {
+        let group_source = PolicySource::Group {
+            name: group.name.to_string(),
+        };
+        policy_statements.extend(sourced(&group_source, &group.policy_statements, &variables));
+        policy_statements
+            .extend(managed(policy_store, &group_source, &group.policies, &variables).await);
+    }
+    policy_statements.extend(managed(policy_store, &user_source, &user.policies, &variables).await);
+    policy_statements.extend(sourced(&user_source, &user.policy_statements, &variables));
+    policy_statements
+}
+
+/// A statement whose actions and resources matched a request.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct StatementMatch {
+    pub source: PolicySource,
+    pub statement: PolicyStatement,
+    pub conditions_met: bool,
+}
+
+/// The reasoning behind an authorization decision.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Explanation {
+    pub action: String,
+    pub resource: String,
+    pub allowed: bool,
+    pub explicit_deny: bool,
+    pub matches: Vec<StatementMatch>,
 }
 
 pub trait ToResourceId {
@@ -56,6 +158,39 @@ impl<P: AsRef<Path>> ToResourceId for P {
 }
 
 impl RequestAuthorizor {
+    pub async fn for_user<S: PolicyStore>(
+        policy_store: &S,
+        user: User,
+        context: RequestContext,
+    ) -> RequestAuthorizor {
+        let policy_statements = policy_store.policy_statements_for(&user).await;
+        RequestAuthorizor {
+            username: user.login_name.into(),
+            policy_statements,
+            restriction: None,
+            context,
+            audit: None,
+        }
+    }
+
+    /// Records denials made by this authorizor in the audit log.
+    pub fn audited(self, audit: Option<AuditLog>) -> RequestAuthorizor {
+        RequestAuthorizor { audit, ..self }
+    }
+
+    /// Limits this authorizor to a single action on a single resource. Any
+    /// other request is denied regardless of the user's policy statements.
+    pub fn restricted_to(self, action: &str, resource_id: &str) -> RequestAuthorizor {
+        RequestAuthorizor {
+            restriction: Some((String::from(action), String::from(resource_id))),
+            ..self
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
     fn result(self, r: Result<(), Status>) -> RequestAuthorizorResult {
         RequestAuthorizorResult {
             authorizor: self,
@@ -87,22 +222,63 @@ impl RequestAuthorizor {
             return false;
         }
         let resource_id = resource_id.unwrap();
-        match self
+        if self.permits(action, resource_id) {
+            return true;
+        }
+        info!(
+            "User '{}' is not authorized for '{}' on '{}'.",
+            self.username, action, resource_id
+        );
+        if let Some(audit) = &self.audit {
+            audit.record(
+                AuditEvent::new(AuditCategory::Denial, action)
+                    .user(Some(&self.username))
+                    .client_ip(self.context.client_ip)
+                    .resource(resource_id),
+            );
+        }
+        false
+    }
+
+    /// Like `is_allowed` but without logging denials, for callers probing
+    /// what a user may do.
+    pub fn permits(&self, action: &str, resource_id: &str) -> bool {
+        if let Some((allowed_action, allowed_resource)) = &self.restriction {
+            if allowed_action != action || allowed_resource != resource_id {
+                return false;
+            }
+        }
+        self.explain(action, resource_id).allowed
+    }
+
+    /// Evaluates an action on a resource, reporting every statement which
+    /// matched it. An explicit Deny always wins over any Allow. Restrictions
+    /// are not taken into account.
+    pub fn explain(&self, action: &str, resource_id: &str) -> Explanation {
+        let matches = self
             .policy_statements
             .iter()
-            .map(|s| s.effect_on(action, resource_id))
-            .filter(|o| o.is_some())
-            .flatten()
-            .reduce(|acc, e| if acc == Effect::Deny { acc } else { e })
-        {
-            Some(Effect::Allow) => true,
-            _ => {
-                info!(
-                    "User '{}' is not authorized for '{}' on '{}'.",
-                    self.username, action, resource_id
-                );
-                false
-            }
+            .filter(|s| s.statement.applies_to(action, resource_id))
+            .map(|s| StatementMatch {
+                source: s.source.clone(),
+                statement: s.statement.clone(),
+                conditions_met: s.statement.conditions_hold(&self.context),
+            })
+            .collect::<Vec<StatementMatch>>();
+        let effective = || {
+            matches
+                .iter()
+                .filter(|m| m.conditions_met)
+                .map(|m| m.statement.effect)
+        };
+        let explicit_deny = effective().any(|e| e == Effect::Deny);
+        let allowed = !explicit_deny && effective().any(|e| e == Effect::Allow);
+        Explanation {
+            action: String::from(action),
+            resource: String::from(resource_id),
+            allowed,
+            explicit_deny,
+            matches,
         }
     }
 }
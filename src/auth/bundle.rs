@@ -0,0 +1,533 @@
+use crate::audit::{self, FieldChange};
+use crate::auth::lockout::{check_lock_out, Proposed};
+use crate::auth::policy::{Change, DocumentKind, Group, ManagedPolicy, PolicyStore, User};
+use crate::auth::store::PolicyStoreError;
+use crate::auth::validation::{
+    check, validate_group, validate_policy, validate_user, FieldError, WriteError,
+};
+use rocket::form::FromFormField;
+use rocket::serde::json::{self, Value};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[cfg(test)]
+#[path = "bundle_tests.rs"]
+mod bundle_tests;
+
+/// The version of the bundle format written by `export`. Bundles of any other
+/// version are refused rather than half understood.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Every user, group and policy in a policy store, for moving them to another.
+/// Password hashes are only included when exported with secrets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Bundle {
+    pub version: BundleVersion,
+    #[serde(default)]
+    pub users: Vec<BundledUser>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub policies: Vec<ManagedPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", try_from = "u32", into = "u32")]
+pub struct BundleVersion(u32);
+
+impl TryFrom<u32> for BundleVersion {
+    type Error = String;
+
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        if version == BUNDLE_VERSION {
+            Ok(BundleVersion(version))
+        } else {
+            Err(format!(
+                "unsupported bundle version {version}, expected {BUNDLE_VERSION}"
+            ))
+        }
+    }
+}
+
+impl From<BundleVersion> for u32 {
+    fn from(v: BundleVersion) -> Self {
+        v.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct BundledUser {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+}
+
+/// How a bundle is written out. Either can be imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, FromFormField)]
+pub enum BundleFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl FromStr for BundleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(BundleFormat::Json),
+            "yaml" => Ok(BundleFormat::Yaml),
+            _ => Err(format!(
+                "unknown bundle format '{s}', expected json or yaml"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("the bundle can't be read: {0}")]
+    Parse(String),
+
+    #[error("the bundle can't be written: {0}")]
+    Write(String),
+
+    /// The bundle is invalid or would lock administrators out.
+    #[error("the bundle can't be imported: {0:?}")]
+    Refused(WriteError),
+
+    #[error(transparent)]
+    Store(#[from] PolicyStoreError),
+}
+
+impl BundleError {
+    /// The message, with every problem listed when the bundle is invalid
+    /// rather than only the first.
+    pub fn details(&self) -> String {
+        match self {
+            BundleError::Refused(WriteError::Invalid(invalid)) => {
+                let mut message = String::from("the bundle can't be imported:");
+                for error in &invalid.errors {
+                    message.push_str(&format!("\n    {}: {}", error.field, error.message));
+                }
+                message
+            }
+            BundleError::Refused(WriteError::LockOut(reason)) => {
+                format!("the bundle can't be imported: {reason}")
+            }
+            e => e.to_string(),
+        }
+    }
+}
+
+impl Bundle {
+    /// Reads a bundle written in either format. JSON is valid YAML, so one
+    /// parser does for both.
+    pub fn parse(text: &str) -> Result<Bundle, BundleError> {
+        serde_yaml::from_str(text).map_err(|e| BundleError::Parse(e.to_string()))
+    }
+
+    pub fn to_string(&self, format: BundleFormat) -> Result<String, BundleError> {
+        match format {
+            BundleFormat::Json => {
+                json::to_pretty_string(self).map_err(|e| BundleError::Write(e.to_string()))
+            }
+            BundleFormat::Yaml => {
+                serde_yaml::to_string(self).map_err(|e| BundleError::Write(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Reads every document in the store into a bundle, in name order so that
+/// exports of the same store compare equal.
+pub async fn export<S: PolicyStore>(
+    policy_store: &S,
+    secrets: bool,
+) -> Result<Bundle, PolicyStoreError> {
+    let mut users = Vec::new();
+    for user in policy_store.list_users().await? {
+        let password_hash = if secrets {
+            policy_store.password_hash(&user.login_name).await?
+        } else {
+            None
+        };
+        users.push(BundledUser {
+            user,
+            password_hash,
+        });
+    }
+    users.sort_by(|a, b| a.user.login_name.cmp(&b.user.login_name));
+    let mut groups = policy_store.list_groups().await?;
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut policies = policy_store.list_policies().await?;
+    policies.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Bundle {
+        version: BundleVersion(BUNDLE_VERSION),
+        users,
+        groups,
+        policies,
+    })
+}
+
+/// How an import treats the documents already in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, FromFormField)]
+pub enum ImportMode {
+    /// Documents in the bundle are created or replace their stored
+    /// counterparts. Everything else is left alone.
+    #[default]
+    Merge,
+    /// As `Merge`, and documents missing from the bundle are deleted.
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            _ => Err(format!(
+                "unknown import mode '{s}', expected merge or replace"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub enum ImportAction {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A document which an import changes. `changes` holds the top-level fields
+/// which differ, as in the audit log, except that password hashes are never
+/// shown: a new one appears as a change to `password`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct PlannedChange {
+    pub kind: DocumentKind,
+    pub name: String,
+    pub action: ImportAction,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+impl PlannedChange {
+    /// Whether the document itself changes, rather than only its password.
+    fn changes_document(&self) -> bool {
+        self.changes.keys().any(|field| field != PASSWORD)
+    }
+}
+
+impl fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.action {
+            ImportAction::Create => '+',
+            ImportAction::Update => '~',
+            ImportAction::Delete => '-',
+        };
+        write!(f, "{sign} {} '{}'", self.kind, self.name)?;
+        if self.action == ImportAction::Update {
+            let fields = self.changes.keys().cloned().collect::<Vec<_>>();
+            write!(f, " ({})", fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The field standing in for a user's password hash in a `PlannedChange`.
+const PASSWORD: &str = "password";
+
+/// What importing a bundle would do. Documents which the import leaves as
+/// they are aren't listed.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportPlan {
+    pub changes: Vec<PlannedChange>,
+}
+
+impl ImportPlan {
+    fn add<T: Serialize>(
+        &mut self,
+        kind: DocumentKind,
+        name: &str,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) {
+        self.add_changes(
+            kind,
+            name,
+            before.is_some(),
+            after.is_some(),
+            audit::diff(before, after),
+        );
+    }
+
+    fn add_changes(
+        &mut self,
+        kind: DocumentKind,
+        name: &str,
+        existed: bool,
+        exists: bool,
+        changes: BTreeMap<String, FieldChange>,
+    ) {
+        let action = match (existed, exists) {
+            (false, _) => ImportAction::Create,
+            (_, false) => ImportAction::Delete,
+            _ if changes.is_empty() => return,
+            _ => ImportAction::Update,
+        };
+        self.changes.push(PlannedChange {
+            kind,
+            name: String::from(name),
+            action,
+            changes,
+        });
+    }
+}
+
+/// Checks that a bundle can be imported: every document is valid in the store
+/// as it would be afterwards, no name appears twice and, unless `force` is
+/// given, the import leaves someone able to administer the store. Gives what
+/// the import would change.
+pub async fn plan<S: PolicyStore>(
+    policy_store: &S,
+    bundle: &Bundle,
+    mode: ImportMode,
+    force: bool,
+) -> Result<ImportPlan, BundleError> {
+    let proposed = match mode {
+        ImportMode::Merge => Proposed::new(policy_store),
+        ImportMode::Replace => Proposed::replacing(policy_store),
+    };
+    let proposed = bundle
+        .users
+        .iter()
+        .fold(proposed, |p, u| p.with_user(u.user.clone()));
+    let proposed = bundle
+        .groups
+        .iter()
+        .fold(proposed, |p, g| p.with_group(g.clone()));
+    let proposed = bundle
+        .policies
+        .iter()
+        .fold(proposed, |p, policy| p.with_policy(policy.clone()));
+
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, bundled) in bundle.users.iter().enumerate() {
+        let user = &bundled.user;
+        if !seen.insert(&user.login_name) {
+            errors.push(duplicate(
+                format!("users[{i}].login_name"),
+                "user",
+                &user.login_name,
+            ));
+        }
+        errors.extend(prefixed(
+            format!("users[{i}]"),
+            validate_user(&proposed, user).await,
+        ));
+    }
+    let mut seen = HashSet::new();
+    for (i, group) in bundle.groups.iter().enumerate() {
+        if !seen.insert(&group.name) {
+            errors.push(duplicate(format!("groups[{i}].name"), "group", &group.name));
+        }
+        errors.extend(prefixed(
+            format!("groups[{i}]"),
+            validate_group(&proposed, group).await,
+        ));
+    }
+    let mut seen = HashSet::new();
+    for (i, policy) in bundle.policies.iter().enumerate() {
+        if !seen.insert(&policy.name) {
+            errors.push(duplicate(
+                format!("policies[{i}].name"),
+                "policy",
+                &policy.name,
+            ));
+        }
+        errors.extend(prefixed(format!("policies[{i}]"), validate_policy(policy)));
+    }
+    check(errors).map_err(BundleError::Refused)?;
+    check_lock_out(&proposed, force)
+        .await
+        .map_err(BundleError::Refused)?;
+    Ok(compare(policy_store, bundle, mode).await?)
+}
+
+fn duplicate(field: String, kind: &str, name: &str) -> FieldError {
+    FieldError {
+        field,
+        message: format!("The bundle has more than one {kind} '{name}'"),
+    }
+}
+
+fn prefixed(prefix: String, errors: Vec<FieldError>) -> Vec<FieldError> {
+    errors
+        .into_iter()
+        .map(|e| FieldError {
+            field: format!("{prefix}.{}", e.field),
+            message: e.message,
+        })
+        .collect()
+}
+
+/// The differences between the store and the store after the import.
+async fn compare<S: PolicyStore>(
+    policy_store: &S,
+    bundle: &Bundle,
+    mode: ImportMode,
+) -> Result<ImportPlan, PolicyStoreError> {
+    let mut plan = ImportPlan::default();
+
+    let stored = policy_store.list_policies().await?;
+    for policy in &bundle.policies {
+        let before = stored.iter().find(|p| p.name == policy.name);
+        // The store assigns versions, so they don't count as a difference.
+        let after = ManagedPolicy {
+            version: before.map_or(0, |p| p.version),
+            ..policy.clone()
+        };
+        plan.add(DocumentKind::Policy, &policy.name, before, Some(&after));
+    }
+    let deleted_policies = stored
+        .iter()
+        .filter(|s| !bundle.policies.iter().any(|p| p.name == s.name));
+
+    let stored_groups = policy_store.list_groups().await?;
+    for group in &bundle.groups {
+        let before = stored_groups.iter().find(|g| g.name == group.name);
+        plan.add(DocumentKind::Group, &group.name, before, Some(group));
+    }
+    let deleted_groups = stored_groups
+        .iter()
+        .filter(|s| !bundle.groups.iter().any(|g| g.name == s.name));
+
+    let stored_users = policy_store.list_users().await?;
+    for bundled in &bundle.users {
+        let name = &bundled.user.login_name;
+        let before = stored_users.iter().find(|u| u.login_name == *name);
+        let mut changes = audit::diff(before, Some(&bundled.user));
+        // A bundle without password hashes leaves stored ones alone.
+        if let Some(hash) = &bundled.password_hash {
+            let stored_hash = match before {
+                Some(_) => policy_store.password_hash(name).await?,
+                None => None,
+            };
+            if stored_hash.as_ref() != Some(hash) {
+                changes.insert(
+                    String::from(PASSWORD),
+                    FieldChange {
+                        before: stored_hash.map(|_| Value::from("(set)")),
+                        after: Some(Value::from("(new)")),
+                    },
+                );
+            }
+        }
+        plan.add_changes(DocumentKind::User, name, before.is_some(), true, changes);
+    }
+    let deleted_users = stored_users.iter().filter(|s| {
+        !bundle
+            .users
+            .iter()
+            .any(|u| u.user.login_name == s.login_name)
+    });
+
+    if mode == ImportMode::Replace {
+        for user in deleted_users {
+            plan.add(DocumentKind::User, &user.login_name, Some(user), None);
+        }
+        for group in deleted_groups {
+            plan.add(DocumentKind::Group, &group.name, Some(group), None);
+        }
+        for policy in deleted_policies {
+            plan.add(DocumentKind::Policy, &policy.name, Some(policy), None);
+        }
+    }
+    Ok(plan)
+}
+
+/// Carries out a plan made by `plan` for the same bundle, calling `applied`
+/// with each change once it has been made. Policies are written first and
+/// deletions come last, so each document refers to ones which already exist.
+/// The import stops at the first failure, leaving what was written so far.
+pub async fn apply<S, F>(
+    policy_store: &S,
+    bundle: &Bundle,
+    plan: &ImportPlan,
+    change: &Change,
+    mut applied: F,
+) -> Result<(), PolicyStoreError>
+where
+    S: PolicyStore,
+    F: FnMut(&PlannedChange) + Send,
+{
+    for planned in &plan.changes {
+        let name = planned.name.as_str();
+        match (planned.kind, planned.action) {
+            (_, ImportAction::Delete) => continue,
+            (DocumentKind::Policy, action) => {
+                let policy = bundle.policies.iter().find(|p| p.name == name);
+                if let Some(policy) = policy {
+                    match action {
+                        ImportAction::Create => policy_store.create_policy(policy, change).await?,
+                        _ => policy_store.update_policy(policy, change).await?,
+                    }
+                }
+            }
+            (DocumentKind::Group, action) => {
+                let group = bundle.groups.iter().find(|g| g.name == name);
+                if let Some(group) = group {
+                    match action {
+                        ImportAction::Create => policy_store.create_group(group, change).await?,
+                        _ => policy_store.update_group(group, change).await?,
+                    }
+                }
+            }
+            (DocumentKind::User, action) => {
+                let bundled = bundle.users.iter().find(|u| u.user.login_name == name);
+                if let Some(bundled) = bundled {
+                    let user = &bundled.user;
+                    match action {
+                        ImportAction::Create => policy_store.create_user(user, change).await?,
+                        _ if planned.changes_document() => {
+                            policy_store.update_user(user, change).await?
+                        }
+                        _ => {}
+                    }
+                    if planned.changes.contains_key(PASSWORD) {
+                        policy_store
+                            .set_password_hash(&user.login_name, bundled.password_hash.as_deref())
+                            .await?;
+                    }
+                }
+            }
+        }
+        applied(planned);
+    }
+    for kind in [
+        DocumentKind::User,
+        DocumentKind::Group,
+        DocumentKind::Policy,
+    ] {
+        for planned in &plan.changes {
+            if planned.kind == kind && planned.action == ImportAction::Delete {
+                policy_store.delete(kind, &planned.name, change).await?;
+                applied(planned);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use super::*;
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+
+fn empty_store() -> Blocking<SqlitePolicyStore> {
+    Blocking::new(SqlitePolicyStore::open_in_memory().unwrap())
+}
+
+fn group(name: &str, description: &str) -> Group {
+    Group {
+        name: name.parse().unwrap(),
+        description: Some(String::from(description)),
+        policy_statements: json::from_str(
+            r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#,
+        )
+        .unwrap(),
+        policies: vec![],
+        parents: vec![],
+    }
+}
+
+fn user(name: &str, groups: &[&str]) -> User {
+    User {
+        login_name: name.parse().unwrap(),
+        full_name: None,
+        groups: groups.iter().map(|g| g.parse().unwrap()).collect(),
+        policy_statements: vec![],
+        policies: vec![],
+    }
+}
+
+/// A store with an administrator `root` in `admins` and `dan` in `staff`.
+async fn populated() -> Blocking<SqlitePolicyStore> {
+    let store = empty_store();
+    let change = Change::default();
+    store
+        .create_group(&group("admins", "Admins"), &change)
+        .await
+        .unwrap();
+    store
+        .create_group(&group("staff", "Staff"), &change)
+        .await
+        .unwrap();
+    store
+        .create_user(&user("root", &["admins"]), &change)
+        .await
+        .unwrap();
+    store
+        .create_user(&user("dan", &["staff"]), &change)
+        .await
+        .unwrap();
+    store
+        .set_user_password(&"root".parse().unwrap(), Some("pw"))
+        .await
+        .unwrap();
+    store
+}
+
+fn summary(plan: &ImportPlan) -> Vec<String> {
+    plan.changes.iter().map(|c| c.to_string()).collect()
+}
+
+#[rocket::async_test]
+async fn test_export_round_trips_in_either_format() {
+    let store = populated().await;
+    for format in [BundleFormat::Json, BundleFormat::Yaml] {
+        let text = export(&store, false)
+            .await
+            .unwrap()
+            .to_string(format)
+            .unwrap();
+        let bundle = Bundle::parse(&text).unwrap();
+        assert_eq!(
+            vec!["dan", "root"],
+            bundle
+                .users
+                .iter()
+                .map(|u| u.user.login_name.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert!(bundle.users.iter().all(|u| u.password_hash.is_none()));
+        assert_eq!(2, bundle.groups.len());
+
+        // Importing a store's own export changes nothing.
+        let planned = plan(&store, &bundle, ImportMode::Replace, false)
+            .await
+            .unwrap();
+        assert!(planned.changes.is_empty());
+    }
+}
+
+#[rocket::async_test]
+async fn test_secrets_are_only_exported_when_asked_for() {
+    let store = populated().await;
+    let text = export(&store, false)
+        .await
+        .unwrap()
+        .to_string(BundleFormat::Json)
+        .unwrap();
+    assert!(!text.contains("password_hash"));
+
+    let bundle = export(&store, true).await.unwrap();
+    let root = bundle
+        .users
+        .iter()
+        .find(|u| u.user.login_name == "root")
+        .unwrap();
+    assert!(root.password_hash.is_some());
+
+    let other = empty_store();
+    let planned = plan(&other, &bundle, ImportMode::Merge, false)
+        .await
+        .unwrap();
+    apply(&other, &bundle, &planned, &Change::default(), |_| {})
+        .await
+        .unwrap();
+    assert!(other
+        .authenticate_user(&"root".parse().unwrap(), "pw")
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[rocket::async_test]
+async fn test_unknown_versions_are_refused() {
+    assert!(matches!(
+        Bundle::parse(r#"{"version": 2, "users": []}"#),
+        Err(BundleError::Parse(e)) if e.contains("unsupported bundle version 2")
+    ));
+    assert!(matches!(
+        Bundle::parse("users: []"),
+        Err(BundleError::Parse(_))
+    ));
+}
+
+#[rocket::async_test]
+async fn test_replace_deletes_what_merge_leaves_alone() {
+    let store = populated().await;
+    let bundle = Bundle::parse(
+        r#"
+version: 1
+users:
+  - login_name: root
+    groups: [admins]
+    policy_statements: []
+groups:
+  - name: admins
+    description: Administrators
+    policy_statements:
+      - effect: Allow
+        actions: ["*"]
+        resources: ["*"]
+"#,
+    )
+    .unwrap();
+
+    let merged = plan(&store, &bundle, ImportMode::Merge, false)
+        .await
+        .unwrap();
+    assert_eq!(vec!["~ group 'admins' (description)"], summary(&merged));
+
+    let replaced = plan(&store, &bundle, ImportMode::Replace, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![
+            "~ group 'admins' (description)",
+            "- user 'dan'",
+            "- group 'staff'"
+        ],
+        summary(&replaced)
+    );
+    apply(&store, &bundle, &replaced, &Change::default(), |_| {})
+        .await
+        .unwrap();
+    assert_eq!(1, store.list_users().await.unwrap().len());
+    assert_eq!(1, store.list_groups().await.unwrap().len());
+    // The password hash survives an import without secrets.
+    assert!(store
+        .authenticate_user(&"root".parse().unwrap(), "pw")
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[rocket::async_test]
+async fn test_invalid_bundles_are_refused_as_a_whole() {
+    let store = populated().await;
+    let bundle = Bundle {
+        version: BundleVersion(BUNDLE_VERSION),
+        users: vec![
+            BundledUser {
+                user: user("eve", &["nobody"]),
+                password_hash: None,
+            },
+            BundledUser {
+                user: user("eve", &[]),
+                password_hash: None,
+            },
+        ],
+        groups: vec![group("auditors", "Auditors")],
+        policies: vec![],
+    };
+    match plan(&store, &bundle, ImportMode::Merge, false).await {
+        Err(BundleError::Refused(WriteError::Invalid(invalid))) => assert_eq!(
+            vec!["users[0].groups[0]", "users[1].login_name"],
+            invalid
+                .errors
+                .iter()
+                .map(|e| e.field.as_str())
+                .collect::<Vec<_>>()
+        ),
+        other => panic!("expected the bundle to be refused, got {other:?}"),
+    }
+    assert!(store
+        .group_named(&"auditors".parse().unwrap())
+        .await
+        .is_none());
+}
+
+#[rocket::async_test]
+async fn test_replacing_administrators_needs_force() {
+    let store = populated().await;
+    let bundle =
+        Bundle::parse(r#"{"version": 1, "groups": [{"name": "staff", "policy_statements": []}]}"#)
+            .unwrap();
+    assert!(matches!(
+        plan(&store, &bundle, ImportMode::Replace, false).await,
+        Err(BundleError::Refused(WriteError::LockOut(_)))
+    ));
+    assert!(plan(&store, &bundle, ImportMode::Replace, true)
+        .await
+        .is_ok());
+}
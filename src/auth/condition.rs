@@ -0,0 +1,352 @@
+use crate::config::Config;
+use rocket::request::Request;
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use time::{OffsetDateTime, UtcOffset};
+
+/// Facts about the current request which policy conditions are evaluated
+/// against.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub client_ip: Option<IpAddr>,
+    pub https: bool,
+    pub content_length: Option<u64>,
+    pub chunked: bool,
+    /// None when asking about no time in particular, in which case time
+    /// windows are taken to hold.
+    pub now: Option<OffsetDateTime>,
+}
+
+impl RequestContext {
+    /// A context which knows nothing about the request. Only conditions which
+    /// depend solely on the clock can be satisfied.
+    pub fn unknown() -> RequestContext {
+        RequestContext {
+            client_ip: None,
+            https: false,
+            content_length: None,
+            chunked: false,
+            now: Some(OffsetDateTime::now_utc()),
+        }
+    }
+
+    /// A context which knows nothing about the request, at no time in
+    /// particular. Whether someone could ever do something doesn't depend on
+    /// when they are asked.
+    pub fn any_time() -> RequestContext {
+        RequestContext {
+            now: None,
+            ..RequestContext::unknown()
+        }
+    }
+
+    pub fn capture(request: &Request<'_>) -> RequestContext {
+        let headers = request.headers();
+        let forwarded_https = headers
+            .get_one("X-Forwarded-Proto")
+            .map(|p| p.eq_ignore_ascii_case("https"));
+        let https = match forwarded_https {
+            Some(https) if via_trusted_proxy(request) => https,
+            _ => request.rocket().config().tls_enabled(),
+        };
+        RequestContext {
+            client_ip: client_ip(request),
+            https,
+            content_length: headers
+                .get_one("Content-Length")
+                .and_then(|l| l.parse().ok()),
+            chunked: headers
+                .get_one("Transfer-Encoding")
+                .map(|e| e.to_ascii_lowercase().contains("chunked"))
+                .unwrap_or(false),
+            now: Some(OffsetDateTime::now_utc()),
+        }
+    }
+}
+
+/// Whether the request was made by one of the configured `trusted_proxies`,
+/// whose forwarded headers describe the real client. Any other client could
+/// set those headers to whatever suits it.
+fn via_trusted_proxy(request: &Request<'_>) -> bool {
+    let proxies = match request.rocket().state::<Config>() {
+        Some(config) => &config.trusted_proxies,
+        None => return false,
+    };
+    request
+        .remote()
+        .map(|remote| proxies.iter().any(|p| p.contains(remote.ip())))
+        .unwrap_or(false)
+}
+
+/// The address of the client making a request: the connection's peer, or
+/// the address given in `X-Real-IP` by a trusted proxy.
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let remote = request.remote().map(|r| r.ip());
+    if via_trusted_proxy(request) {
+        request.real_ip().or(remote)
+    } else {
+        remote
+    }
+}
+
+/// Optional restrictions on when a policy statement applies. Every condition
+/// which is present must hold for the statement to take effect.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<Vec<Cidr>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_window: Option<TimeWindow>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+}
+
+impl Conditions {
+    pub fn hold(&self, context: &RequestContext) -> bool {
+        if let Some(cidrs) = &self.source_ip {
+            match context.client_ip {
+                Some(ip) if cidrs.iter().any(|c| c.contains(ip)) => (),
+                _ => return false,
+            }
+        }
+        if let (Some(window), Some(now)) = (&self.time_window, context.now) {
+            if !window.contains(now) {
+                return false;
+            }
+        }
+        if self.https_only == Some(true) && !context.https {
+            return false;
+        }
+        if let Some(max) = self.max_file_size {
+            // A body whose size isn't known up front, e.g. a chunked one,
+            // can't be shown to be within the limit.
+            match context.content_length {
+                Some(length) if !context.chunked && length <= max => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`. A bare address is
+/// treated as a single-host network.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match (self.address, ip) {
+            (IpAddr::V4(_), IpAddr::V6(v6)) => v6.to_ipv4_mapped().map(IpAddr::V4),
+            _ => Some(ip),
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(net), Some(IpAddr::V4(ip))) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), Some(IpAddr::V6(ip))) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rem_bits = prefix_len % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rem_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rem_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("Invalid IP address in CIDR: {s}"))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in CIDR: {s}"))?,
+            None => max_len,
+        };
+        Ok(Cidr {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// A recurring window of time. `start` and `end` are `HH:MM` times of day; a
+/// window whose end is before its start wraps past midnight. `days` limits
+/// the window to certain days of the week (as seen from `utc_offset`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct TimeWindow {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<TimeOfDay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<TimeOfDay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days: Option<Vec<Weekday>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utc_offset: Option<Offset>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, now: OffsetDateTime) -> bool {
+        let offset = self.utc_offset.map_or(UtcOffset::UTC, |o| o.0);
+        let now = now.to_offset(offset);
+        if let Some(days) = &self.days {
+            if !days.iter().any(|d| d.is(now.weekday())) {
+                return false;
+            }
+        }
+        let minute = TimeOfDay(now.hour() as u16 * 60 + now.minute() as u16);
+        match (self.start, self.end) {
+            (Some(start), Some(end)) if end < start => minute >= start || minute < end,
+            (start, end) => {
+                start.map(|s| minute >= s).unwrap_or(true)
+                    && end.map(|e| minute < e).unwrap_or(true)
+            }
+        }
+    }
+}
+
+/// Minutes since midnight, written as `HH:MM`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid time of day (expected HH:MM): {value}");
+        let (h, m) = value.split_once(':').ok_or_else(invalid)?;
+        let h: u16 = h.parse().map_err(|_| invalid())?;
+        let m: u16 = m.parse().map_err(|_| invalid())?;
+        if h > 24 || m > 59 || (h == 24 && m != 0) {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(h * 60 + m))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", t.0 / 60, t.0 % 60)
+    }
+}
+
+/// A fixed offset from UTC, written as `+HH:MM` or `-HH:MM`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct Offset(UtcOffset);
+
+impl TryFrom<String> for Offset {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid UTC offset (expected +HH:MM): {value}");
+        let (sign, rest) = if let Some(rest) = value.strip_prefix('+') {
+            (1, rest)
+        } else if let Some(rest) = value.strip_prefix('-') {
+            (-1, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (h, m) = rest.split_once(':').ok_or_else(invalid)?;
+        let h: i8 = h.parse().map_err(|_| invalid())?;
+        let m: i8 = m.parse().map_err(|_| invalid())?;
+        UtcOffset::from_hms(sign * h, sign * m, 0)
+            .map(Offset)
+            .map_err(|_| invalid())
+    }
+}
+
+impl From<Offset> for String {
+    fn from(o: Offset) -> Self {
+        let (h, m, _) = o.0.as_hms();
+        let sign = if o.0.is_negative() { '-' } else { '+' };
+        format!("{sign}{:02}:{:02}", h.abs(), m.abs())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub enum Weekday {
+    #[serde(alias = "Mon")]
+    Monday,
+    #[serde(alias = "Tue")]
+    Tuesday,
+    #[serde(alias = "Wed")]
+    Wednesday,
+    #[serde(alias = "Thu")]
+    Thursday,
+    #[serde(alias = "Fri")]
+    Friday,
+    #[serde(alias = "Sat")]
+    Saturday,
+    #[serde(alias = "Sun")]
+    Sunday,
+}
+
+impl Weekday {
+    fn is(&self, day: time::Weekday) -> bool {
+        let day = match day {
+            time::Weekday::Monday => Weekday::Monday,
+            time::Weekday::Tuesday => Weekday::Tuesday,
+            time::Weekday::Wednesday => Weekday::Wednesday,
+            time::Weekday::Thursday => Weekday::Thursday,
+            time::Weekday::Friday => Weekday::Friday,
+            time::Weekday::Saturday => Weekday::Saturday,
+            time::Weekday::Sunday => Weekday::Sunday,
+        };
+        *self == day
+    }
+}
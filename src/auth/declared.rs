@@ -0,0 +1,137 @@
+use crate::audit::{Audit, AuditLog};
+use crate::auth::bundle::{self, Bundle, ImportMode, ImportPlan};
+use crate::auth::policy::{Change, PolicyStore};
+use crate::auth::store::BoxedPolicyStore;
+use log::{error, info, warn};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::tokio::fs;
+use rocket::tokio::signal::unix::{signal, SignalKind};
+use rocket::{Build, Orbit, Rocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(test)]
+#[path = "declared_tests.rs"]
+mod declared_tests;
+
+/// Makes the policy store match the users, groups and policies declared in a
+/// bundle, so that they can be kept in version control. Documents which the
+/// bundle leaves out are kept unless `prune` is set. Password hashes are never
+/// declared, so passwords set through the API survive reconciliation.
+pub async fn reconcile<S: PolicyStore>(
+    policy_store: &S,
+    path: &Path,
+    prune: bool,
+    audit: &Audit,
+) -> Result<ImportPlan, String> {
+    let text = fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Error reading the declared policy {path:?}: {e}"))?;
+    let bundle = Bundle::parse(&text)
+        .map_err(|e| format!("Error in the declared policy {path:?}: {}", e.details()))?;
+    if let Some(bundled) = bundle.users.iter().find(|u| u.password_hash.is_some()) {
+        return Err(format!(
+            "The declared policy {path:?} has a password hash for user '{}'. Passwords \
+             can't be declared.",
+            bundled.user.login_name
+        ));
+    }
+    let mode = if prune {
+        ImportMode::Replace
+    } else {
+        ImportMode::Merge
+    };
+    let plan = bundle::plan(policy_store, &bundle, mode, false)
+        .await
+        .map_err(|e| format!("Error in the declared policy {path:?}: {}", e.details()))?;
+    let change = Change {
+        author: None,
+        reason: Some(format!("Reconciled with the declared policy {path:?}")),
+        base_revision: None,
+    };
+    bundle::apply(policy_store, &bundle, &plan, &change, |planned| {
+        info!("Declared policy: {planned}");
+        audit.reconcile(planned)
+    })
+    .await
+    .map_err(|e| format!("Error reconciling with the declared policy {path:?}: {e}"))?;
+    Ok(plan)
+}
+
+/// Reconciles the policy store with `declared_policy` before the app starts,
+/// refusing to start if that fails, and again whenever swaf gets SIGHUP.
+#[derive(Clone)]
+pub struct DeclaredPolicy {
+    path: PathBuf,
+    prune: bool,
+    policy_store: Arc<BoxedPolicyStore>,
+    audit_log: AuditLog,
+}
+
+impl DeclaredPolicy {
+    pub fn new(
+        path: PathBuf,
+        prune: bool,
+        policy_store: Arc<BoxedPolicyStore>,
+        audit_log: AuditLog,
+    ) -> DeclaredPolicy {
+        DeclaredPolicy {
+            path,
+            prune,
+            policy_store,
+            audit_log,
+        }
+    }
+
+    async fn reconcile(&self) -> Result<(), String> {
+        let audit = Audit::local(self.audit_log.clone(), None);
+        let plan = reconcile(&self.policy_store, &self.path, self.prune, &audit).await?;
+        info!(
+            "Policy store reconciled with {:?}: {} change(s)",
+            self.path,
+            plan.changes.len()
+        );
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for DeclaredPolicy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Declared policy",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        match self.reconcile().await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                error!("{e}");
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &Rocket<Orbit>) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Can't reload the declared policy on SIGHUP: {e}");
+                return;
+            }
+        };
+        let declared = self.clone();
+        rocket::tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Got SIGHUP. Reconciling with {:?}", declared.path);
+                // The store is left as it was if the declared policy is
+                // invalid, and the app carries on as before.
+                if let Err(e) = declared.reconcile().await {
+                    error!("{e}");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,130 @@
+use super::*;
+use crate::auth::policy::{Group, User};
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+use rocket::serde::json;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DECLARED_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A declared policy file holding `text`, with an audit for reconciling it.
+fn declared(text: &str) -> (PathBuf, Audit) {
+    let dir = env::temp_dir().join(format!(
+        "swaf-declared-{}-{}",
+        std::process::id(),
+        DECLARED_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("declared.yaml");
+    fs::write(&path, text).unwrap();
+    let audit_log = AuditLog::new(dir.join("audit.log"), 1024 * 1024, 1).unwrap();
+    (path, Audit::local(audit_log, None))
+}
+
+const ADMINS: &str = r#"
+version: 1
+groups:
+  - name: admins
+    description: Administrators
+    policy_statements:
+      - effect: Allow
+        actions: ["*"]
+        resources: ["*"]
+users:
+  - login_name: root
+    groups: [admins]
+    policy_statements: []
+"#;
+
+/// A store with `root` in `admins`, who has a password, and `dan`, who is
+/// in neither.
+async fn populated() -> Blocking<SqlitePolicyStore> {
+    let store = Blocking::new(SqlitePolicyStore::open_in_memory().unwrap());
+    let change = Change::default();
+    let admins: Group = json::from_str(
+        r#"{"name": "admins", "description": null, "policy_statements":
+            [{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]}"#,
+    )
+    .unwrap();
+    store.create_group(&admins, &change).await.unwrap();
+    for (name, groups) in [("root", vec!["admins"]), ("dan", vec![])] {
+        let user: User = json::from_value(json::json!({
+            "login_name": name,
+            "full_name": null,
+            "groups": groups,
+            "policy_statements": [],
+        }))
+        .unwrap();
+        store.create_user(&user, &change).await.unwrap();
+    }
+    store
+        .set_user_password(&"root".parse().unwrap(), Some("pw"))
+        .await
+        .unwrap();
+    store
+}
+
+#[rocket::async_test]
+async fn test_declared_documents_are_created_and_updated() {
+    let store = populated().await;
+    let (path, audit) = declared(&ADMINS.replace("admins]", "admins]\n    full_name: Root"));
+
+    let plan = reconcile(&store, &path, false, &audit).await.unwrap();
+    assert_eq!(2, plan.changes.len());
+    let admins = store.group_named(&"admins".parse().unwrap()).await.unwrap();
+    assert_eq!(Some(String::from("Administrators")), admins.description);
+    let root = store.user_named(&"root".parse().unwrap()).await.unwrap();
+    assert_eq!(Some(String::from("Root")), root.full_name);
+    // Passwords set through the API are left alone.
+    assert!(store
+        .authenticate_user(&"root".parse().unwrap(), "pw")
+        .await
+        .unwrap()
+        .is_some());
+    // Undeclared documents are kept unless pruning.
+    assert!(store.user_named(&"dan".parse().unwrap()).await.is_ok());
+
+    // Reconciling again changes nothing.
+    let plan = reconcile(&store, &path, false, &audit).await.unwrap();
+    assert!(plan.changes.is_empty());
+}
+
+#[rocket::async_test]
+async fn test_pruning_deletes_undeclared_documents() {
+    let store = populated().await;
+    let (path, audit) = declared(ADMINS);
+
+    reconcile(&store, &path, true, &audit).await.unwrap();
+    assert_eq!(
+        vec!["root"],
+        store
+            .list_users()
+            .await
+            .unwrap()
+            .iter()
+            .map(|u| u.login_name.to_string())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[rocket::async_test]
+async fn test_bad_declared_policies_change_nothing() {
+    let store = populated().await;
+    for text in [
+        // Passwords can't be declared.
+        ADMINS.replace("groups: [admins]", "groups: [admins]\n    password_hash: x"),
+        // Nobody would be left to administer the store.
+        ADMINS.replace("groups: [admins]", "groups: []"),
+        ADMINS.replace("version: 1", "version: 2"),
+    ] {
+        let (path, audit) = declared(&text);
+        assert!(
+            reconcile(&store, &path, true, &audit).await.is_err(),
+            "{text}"
+        );
+        assert_eq!(2, store.list_users().await.unwrap().len());
+    }
+}
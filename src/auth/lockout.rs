@@ -0,0 +1,284 @@
+use crate::auth::authorizor::RequestAuthorizor;
+use crate::auth::condition::RequestContext;
+use crate::auth::name::PrincipalName;
+use crate::auth::policy::{Change, DocumentKind, Group, ManagedPolicy, PolicyStore, User};
+use crate::auth::store::PolicyStoreError;
+use crate::auth::validation::WriteError;
+
+#[cfg(test)]
+#[path = "lockout_tests.rs"]
+mod lockout_tests;
+
+/// A view of a policy store with some pending changes applied on top, so the
+/// effect of a write can be evaluated before it is made. Writes through this
+/// view always fail.
+pub struct Proposed<'a, S> {
+    store: &'a S,
+    users: Vec<User>,
+    groups: Vec<Group>,
+    policies: Vec<ManagedPolicy>,
+    /// Whether the proposed documents are all there is, as though everything
+    /// else in the store were deleted.
+    replacing: bool,
+}
+
+impl<'a, S: PolicyStore> Proposed<'a, S> {
+    pub fn new(store: &'a S) -> Proposed<'a, S> {
+        Proposed {
+            store,
+            users: Vec::new(),
+            groups: Vec::new(),
+            policies: Vec::new(),
+            replacing: false,
+        }
+    }
+
+    /// A view of the store in which only the proposed documents exist.
+    pub fn replacing(store: &'a S) -> Proposed<'a, S> {
+        Proposed {
+            replacing: true,
+            ..Proposed::new(store)
+        }
+    }
+
+    /// The stored documents which show through the proposed ones.
+    async fn stored<T, F>(&self, list: F) -> Result<Vec<T>, PolicyStoreError>
+    where
+        F: std::future::Future<Output = Result<Vec<T>, PolicyStoreError>>,
+    {
+        if self.replacing {
+            Ok(Vec::new())
+        } else {
+            list.await
+        }
+    }
+
+    pub fn with_user(mut self, user: User) -> Proposed<'a, S> {
+        self.users.retain(|u| u.login_name != user.login_name);
+        self.users.push(user);
+        self
+    }
+
+    pub fn with_group(mut self, group: Group) -> Proposed<'a, S> {
+        self.groups.retain(|g| g.name != group.name);
+        self.groups.push(group);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: ManagedPolicy) -> Proposed<'a, S> {
+        self.policies.retain(|p| p.name != policy.name);
+        self.policies.push(policy);
+        self
+    }
+}
+
+/// Replaces the items in `stored` which have a proposed counterpart and
+/// appends the proposed items which are new.
+fn overlay<T: Clone, F: Fn(&T) -> &str>(stored: Vec<T>, proposed: &[T], name: F) -> Vec<T> {
+    let mut items = stored
+        .into_iter()
+        .filter(|s| !proposed.iter().any(|p| name(p) == name(s)))
+        .collect::<Vec<T>>();
+    items.extend(proposed.iter().cloned());
+    items
+}
+
+fn read_only() -> PolicyStoreError {
+    PolicyStoreError::Conflict(String::from("Proposed changes can't be written"))
+}
+
+#[rocket::async_trait]
+impl<S: PolicyStore> PolicyStore for Proposed<'_, S> {
+    async fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
+        Ok(overlay(
+            self.stored(self.store.list_users()).await?,
+            &self.users,
+            |u| &u.login_name,
+        ))
+    }
+
+    async fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
+        match self.users.iter().find(|u| u.login_name == name) {
+            Some(user) => Ok(user.clone()),
+            None if self.replacing => Err(PolicyStoreError::NotFound(format!("user '{name}'"))),
+            None => self.store.user_named(name).await,
+        }
+    }
+
+    async fn create_user(&self, _user: &User, _change: &Change) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn update_user(&self, _user: &User, _change: &Change) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn set_user_password(
+        &self,
+        _login_name: &PrincipalName,
+        _password: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn authenticate_user(
+        &self,
+        _login_name: &PrincipalName,
+        _password: &str,
+    ) -> Result<Option<User>, PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError> {
+        self.store.password_hash(login_name).await
+    }
+
+    async fn set_password_hash(
+        &self,
+        _login_name: &PrincipalName,
+        _hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
+        Ok(overlay(
+            self.stored(self.store.list_groups()).await?,
+            &self.groups,
+            |g| &g.name,
+        ))
+    }
+
+    async fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+        match self.groups.iter().find(|g| g.name == name) {
+            Some(group) => Some(group.clone()),
+            None if self.replacing => None,
+            None => self.store.group_named(name).await,
+        }
+    }
+
+    async fn create_group(&self, _group: &Group, _change: &Change) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn update_group(&self, _group: &Group, _change: &Change) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+        Ok(overlay(
+            self.stored(self.store.list_policies()).await?,
+            &self.policies,
+            |p| &p.name,
+        ))
+    }
+
+    async fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+        match self.policies.iter().find(|p| p.name == name) {
+            Some(policy) => Some(policy.clone()),
+            None if self.replacing => None,
+            None => self.store.policy_named(name).await,
+        }
+    }
+
+    async fn create_policy(
+        &self,
+        _policy: &ManagedPolicy,
+        _change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn update_policy(
+        &self,
+        _policy: &ManagedPolicy,
+        _change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+
+    async fn delete(
+        &self,
+        _kind: DocumentKind,
+        _name: &str,
+        _change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        Err(read_only())
+    }
+}
+
+/// The resources a user must be able to update to count as an administrator:
+/// every user and every group in the store.
+async fn administered_resources<S: PolicyStore>(
+    policy_store: &S,
+) -> Option<Vec<(&'static str, String)>> {
+    let users = policy_store.list_users().await.ok()?;
+    let groups = policy_store.list_groups().await.ok()?;
+    Some(
+        users
+            .into_iter()
+            .map(|u| ("UpdateUser", format!("user:{}", u.login_name)))
+            .chain(
+                groups
+                    .into_iter()
+                    .map(|g| ("UpdateGroup", format!("group:{}", g.name))),
+            )
+            .collect(),
+    )
+}
+
+/// Whether the authorizor may update every user and group in the store.
+pub async fn administers<S: PolicyStore>(policy_store: &S, authorizor: &RequestAuthorizor) -> bool {
+    match administered_resources(policy_store).await {
+        Some(resources) => resources
+            .iter()
+            .all(|(action, resource)| authorizor.permits(action, resource)),
+        None => false,
+    }
+}
+
+/// Whether any user could update every user and group in the store. Users are
+/// evaluated without knowledge of any request, so statements with conditions
+/// on the request don't count: there's no telling whether the user will be
+/// able to satisfy them when it matters. Time windows do count, as the user
+/// need only wait for one, and otherwise the answer would depend on when the
+/// question was asked.
+pub async fn has_administrator<S: PolicyStore>(policy_store: &S) -> bool {
+    let resources = match administered_resources(policy_store).await {
+        Some(resources) => resources,
+        None => return false,
+    };
+    let users = policy_store.list_users().await.unwrap_or_default();
+    for user in users {
+        let authorizor =
+            RequestAuthorizor::for_user(policy_store, user, RequestContext::any_time()).await;
+        if resources
+            .iter()
+            .all(|(action, resource)| authorizor.permits(action, resource))
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Refuses a change which would leave nobody able to administer users and
+/// groups, unless it is forced. A store which already has no administrator
+/// can't be made any worse, so changes to it are allowed in order that it can
+/// be repaired.
+pub async fn check_lock_out<S: PolicyStore>(
+    proposed: &Proposed<'_, S>,
+    force: bool,
+) -> Result<(), WriteError> {
+    if force || has_administrator(proposed).await || !has_administrator(proposed.store).await {
+        Ok(())
+    } else {
+        Err(WriteError::LockOut(
+            "This change would leave no user able to update every user and group. \
+             Retry with force=true to make it anyway.",
+        ))
+    }
+}
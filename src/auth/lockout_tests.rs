@@ -0,0 +1,111 @@
+use super::*;
+use crate::auth::policy::{Effect, PolicyStatement};
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+use rocket::serde::json;
+
+fn admin_statements() -> Vec<PolicyStatement> {
+    json::from_str(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#).unwrap()
+}
+
+fn user(name: &str, groups: &[&str]) -> User {
+    User {
+        login_name: name.parse().unwrap(),
+        full_name: Some(String::from(name)),
+        groups: groups.iter().map(|g| g.parse().unwrap()).collect(),
+        policy_statements: vec![],
+        policies: vec![],
+    }
+}
+
+fn admins() -> Group {
+    Group {
+        name: "admins".parse().unwrap(),
+        description: None,
+        policy_statements: admin_statements(),
+        policies: vec![],
+        parents: vec![],
+    }
+}
+
+/// An in-memory store holding `users` and the `admins` group.
+async fn store_with(users: &[User]) -> Blocking<SqlitePolicyStore> {
+    let store = Blocking::new(SqlitePolicyStore::open_in_memory().unwrap());
+    let change = Change::default();
+    store.create_group(&admins(), &change).await.unwrap();
+    for user in users {
+        store.create_user(user, &change).await.unwrap();
+    }
+    store
+}
+
+/// A store in which `root` administers everything and `dan` nothing.
+async fn store() -> Blocking<SqlitePolicyStore> {
+    store_with(&[user("root", &["admins"]), user("dan", &[])]).await
+}
+
+#[rocket::async_test]
+async fn test_store_has_administrator() {
+    assert!(has_administrator(&store().await).await);
+}
+
+#[rocket::async_test]
+async fn test_removing_last_admin_locks_out() {
+    let store = store().await;
+    let proposed = Proposed::new(&store).with_user(user("root", &[]));
+    assert!(!has_administrator(&proposed).await);
+    assert!(matches!(
+        check_lock_out(&proposed, false).await,
+        Err(WriteError::LockOut(_))
+    ));
+    assert!(check_lock_out(&proposed, true).await.is_ok());
+}
+
+#[rocket::async_test]
+async fn test_removing_one_of_several_admins_is_allowed() {
+    let store = store().await;
+    let proposed = Proposed::new(&store)
+        .with_user(user("dan", &["admins"]))
+        .with_user(user("root", &[]));
+    assert!(check_lock_out(&proposed, false).await.is_ok());
+}
+
+#[rocket::async_test]
+async fn test_denying_admins_locks_out() {
+    let store = store().await;
+    let mut admins = store.group_named(&"admins".parse().unwrap()).await.unwrap();
+    admins.policy_statements.push(PolicyStatement {
+        effect: Effect::Deny,
+        ..json::from_str(
+            r#"{"effect": "Allow", "actions": ["UpdateGroup"], "resources": ["group:admins"]}"#,
+        )
+        .unwrap()
+    });
+    let proposed = Proposed::new(&store).with_group(admins);
+    assert!(check_lock_out(&proposed, false).await.is_err());
+}
+
+#[rocket::async_test]
+async fn test_store_without_administrator_can_be_changed() {
+    let locked = store_with(&[user("root", &[]), user("dan", &[])]).await;
+    let proposed = Proposed::new(&locked).with_user(user("dan", &["admins"]));
+    assert!(check_lock_out(&proposed, false).await.is_ok());
+    let proposed = Proposed::new(&locked).with_user(user("dan", &[]));
+    assert!(check_lock_out(&proposed, false).await.is_ok());
+}
+
+#[rocket::async_test]
+async fn test_admin_in_a_time_window_counts_at_any_time() {
+    // Open only tomorrow, so the window is shut now.
+    let tomorrow = time::OffsetDateTime::now_utc().weekday().next();
+    let mut admins = admins();
+    admins.policy_statements = json::from_str(&format!(
+        r#"[{{"effect": "Allow", "actions": ["*"], "resources": ["*"],
+             "conditions": {{"time_window": {{"days": ["{tomorrow}"]}}}}}}]"#
+    ))
+    .unwrap();
+    let store = store().await;
+    let proposed = Proposed::new(&store).with_group(admins);
+    assert!(has_administrator(&proposed).await);
+    assert!(check_lock_out(&proposed, false).await.is_ok());
+}
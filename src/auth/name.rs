@@ -0,0 +1,160 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[cfg(test)]
+#[path = "name_tests.rs"]
+mod name_tests;
+
+/// The longest name a user or group may have.
+pub const MAX_LENGTH: usize = 64;
+
+/// Names which mean something other than a user or group wherever names
+/// appear, e.g. in audit events for requests made without a session. They
+/// are reserved whatever their case.
+pub const RESERVED_NAMES: &[&str] = &["anonymous"];
+
+/// The login name of a user or the name of a group. Names are also file names
+/// in the file policy store, so only letters, digits, `.`, `_`, `-` and `@`
+/// are allowed and a name must start with a letter or digit, which rules out
+/// `.`, `..` and anything with a path separator. Names are case sensitive but
+/// reserved names are refused in any case.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct PrincipalName(String);
+
+/// Why a name isn't a valid `PrincipalName`.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid name '{name}': {reason}")]
+pub struct InvalidName {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+impl InvalidName {
+    pub fn new<N: Into<String>>(name: N, reason: &'static str) -> InvalidName {
+        InvalidName {
+            name: name.into(),
+            reason,
+        }
+    }
+}
+
+impl PrincipalName {
+    pub fn parse(name: &str) -> Result<PrincipalName, InvalidName> {
+        let reason = if name.is_empty() {
+            Some("must not be empty")
+        } else if name.len() > MAX_LENGTH {
+            Some("must be at most 64 characters long")
+        } else if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'))
+        {
+            Some("may only contain letters, digits, '.', '_', '-' and '@'")
+        } else if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            Some("must start with a letter or digit")
+        } else if RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+        {
+            Some("is reserved")
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => Err(InvalidName::new(name, reason)),
+            None => Ok(PrincipalName(String::from(name))),
+        }
+    }
+
+    /// The reserved name given to principals which aren't a user, e.g. the
+    /// one a policy simulation for a list of groups runs as.
+    pub fn anonymous() -> PrincipalName {
+        PrincipalName(String::from(RESERVED_NAMES[0]))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for PrincipalName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for PrincipalName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for PrincipalName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PrincipalName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for PrincipalName {
+    type Err = InvalidName;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        PrincipalName::parse(name)
+    }
+}
+
+impl TryFrom<String> for PrincipalName {
+    type Error = InvalidName;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        PrincipalName::parse(&name)
+    }
+}
+
+impl From<PrincipalName> for String {
+    fn from(name: PrincipalName) -> Self {
+        name.0
+    }
+}
+
+impl PartialEq<str> for PrincipalName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&PrincipalName> for PrincipalName {
+    fn eq(&self, other: &&PrincipalName) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<&str> for PrincipalName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<PrincipalName> for str {
+    fn eq(&self, other: &PrincipalName) -> bool {
+        self == other.0
+    }
+}
+
+impl PartialEq<PrincipalName> for &str {
+    fn eq(&self, other: &PrincipalName) -> bool {
+        *self == other.0
+    }
+}
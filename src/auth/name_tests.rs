@@ -0,0 +1,52 @@
+use super::*;
+use rocket::serde::json;
+
+fn reason(name: &str) -> &'static str {
+    PrincipalName::parse(name).unwrap_err().reason
+}
+
+#[test]
+fn test_ordinary_names_are_valid() {
+    for name in [
+        "dan",
+        "fullFileAccess",
+        "dan.smith",
+        "build_bot-2",
+        "dan@example.com",
+        "7up",
+    ] {
+        assert_eq!(name, PrincipalName::parse(name).unwrap().as_str());
+    }
+}
+
+#[test]
+fn test_names_which_could_escape_a_directory_are_invalid() {
+    assert_eq!("must start with a letter or digit", reason(".."));
+    assert_eq!("must start with a letter or digit", reason(".hidden"));
+    for name in ["../users/root", "a/b", "a\\b", "dan\0", "dan smith", "dän"] {
+        assert_eq!(
+            "may only contain letters, digits, '.', '_', '-' and '@'",
+            reason(name)
+        );
+    }
+}
+
+#[test]
+fn test_empty_long_and_reserved_names_are_invalid() {
+    assert_eq!("must not be empty", reason(""));
+    assert!(PrincipalName::parse(&"a".repeat(MAX_LENGTH)).is_ok());
+    assert_eq!(
+        "must be at most 64 characters long",
+        reason(&"a".repeat(MAX_LENGTH + 1))
+    );
+    assert_eq!("is reserved", reason("anonymous"));
+    assert_eq!("is reserved", reason("Anonymous"));
+}
+
+#[test]
+fn test_names_are_checked_when_deserialized() {
+    let name: PrincipalName = json::from_str(r#""dan""#).unwrap();
+    assert_eq!("\"dan\"", json::to_string(&name).unwrap());
+    let error = json::from_str::<PrincipalName>(r#""../users/root""#).unwrap_err();
+    assert!(error.to_string().contains("invalid name '../users/root'"));
+}
@@ -1,5 +1,13 @@
+use crate::auth::authorizor::{self, SourcedStatement};
+use crate::auth::condition::{Conditions, RequestContext};
+use crate::auth::name::PrincipalName;
+use crate::auth::store::PolicyStoreError;
 use crate::util;
+use rocket::serde::json::Value;
 use rocket::serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use time::OffsetDateTime;
 
 #[cfg(test)]
 #[path = "policy_tests.rs"]
@@ -8,20 +16,103 @@ mod policy_tests;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct User {
-    pub login_name: String,
+    pub login_name: PrincipalName,
     pub full_name: Option<String>,
-    pub groups: Vec<String>,
+    pub groups: Vec<PrincipalName>,
     pub policy_statements: Vec<PolicyStatement>,
+    #[serde(default)]
+    pub policies: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct Group {
+    pub name: PrincipalName,
+    pub description: Option<String>,
+    pub policy_statements: Vec<PolicyStatement>,
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Groups whose policies members of this group also receive.
+    #[serde(default)]
+    pub parents: Vec<PrincipalName>,
+}
+
+/// A named list of policy statements which users and groups attach by name
+/// rather than repeating the statements. `version` is incremented by the
+/// store on every update.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ManagedPolicy {
     pub name: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub version: u64,
     pub policy_statements: Vec<PolicyStatement>,
 }
 
+/// Who is making a change to the policy store and why, for its history.
+#[derive(Debug, Clone, Default)]
+pub struct Change {
+    pub author: Option<String>,
+    pub reason: Option<String>,
+    /// The revision of the document the change was based on. When given, the
+    /// write fails if the document has been changed since.
+    pub base_revision: Option<u64>,
+}
+
+/// The kinds of document kept in the policy store.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub enum DocumentKind {
+    User,
+    Group,
+    Policy,
+}
+
+impl fmt::Display for DocumentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DocumentKind::User => "user",
+            DocumentKind::Group => "group",
+            DocumentKind::Policy => "policy",
+        })
+    }
+}
+
+/// A document as it was after one of the writes made to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Revision {
+    pub revision: u64,
+    pub author: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub reason: Option<String>,
+    pub document: Value,
+}
+
+/// Every action the API checks permissions for.
+pub const KNOWN_ACTIONS: &[&str] = &[
+    "file:Read",
+    "file:Write",
+    "ListUsers",
+    "CreateUser",
+    "UpdateUser",
+    "SetUserPassword",
+    "ListGroups",
+    "CreateGroup",
+    "UpdateGroup",
+    "ManageGroupMembers",
+    "SimulatePolicy",
+    "ListPolicies",
+    "CreatePolicy",
+    "UpdatePolicy",
+    "AttachPolicy",
+    "ReadAuditLog",
+    "ExportPolicyStore",
+    "ImportPolicyStore",
+];
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 #[serde(crate = "rocket::serde")]
 pub enum Effect {
@@ -29,29 +120,250 @@ pub enum Effect {
     Deny,
 }
 
+/// How a statement's resource patterns are matched. `V1` uses plain globs in
+/// which `*` also matches `/`. `V2` uses path-aware globs in which `*` stops
+/// at `/` and `**` matches any depth.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", try_from = "u8", into = "u8")]
+pub enum PolicyVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl PolicyVersion {
+    fn is_v1(&self) -> bool {
+        *self == PolicyVersion::V1
+    }
+}
+
+impl TryFrom<u8> for PolicyVersion {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PolicyVersion::V1),
+            2 => Ok(PolicyVersion::V2),
+            v => Err(format!("Unsupported policy statement version: {v}")),
+        }
+    }
+}
+
+impl From<PolicyVersion> for u8 {
+    fn from(v: PolicyVersion) -> Self {
+        match v {
+            PolicyVersion::V1 => 1,
+            PolicyVersion::V2 => 2,
+        }
+    }
+}
+
+/// A statement matches an action when it is listed in `actions` or, if
+/// `not_actions` is given instead, when it is *not* listed there. Resources
+/// work the same way.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(crate = "rocket::serde")]
+#[serde(crate = "rocket::serde", try_from = "UncheckedPolicyStatement")]
 pub struct PolicyStatement {
+    #[serde(default, skip_serializing_if = "PolicyVersion::is_v1")]
+    pub version: PolicyVersion,
     pub effect: Effect,
     pub actions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_actions: Option<Vec<String>>,
     pub resources: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_resources: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Conditions>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UncheckedPolicyStatement {
+    #[serde(default)]
+    version: PolicyVersion,
+    effect: Effect,
+    #[serde(default)]
+    actions: Vec<String>,
+    #[serde(default)]
+    not_actions: Option<Vec<String>>,
+    #[serde(default)]
+    resources: Vec<String>,
+    #[serde(default)]
+    not_resources: Option<Vec<String>>,
+    #[serde(default)]
+    conditions: Option<Conditions>,
+}
+
+impl TryFrom<UncheckedPolicyStatement> for PolicyStatement {
+    type Error = String;
+
+    fn try_from(s: UncheckedPolicyStatement) -> Result<Self, Self::Error> {
+        if !s.actions.is_empty() && s.not_actions.is_some() {
+            return Err(String::from(
+                "A policy statement cannot have both actions and not_actions",
+            ));
+        }
+        if !s.resources.is_empty() && s.not_resources.is_some() {
+            return Err(String::from(
+                "A policy statement cannot have both resources and not_resources",
+            ));
+        }
+        Ok(PolicyStatement {
+            version: s.version,
+            effect: s.effect,
+            actions: s.actions,
+            not_actions: s.not_actions,
+            resources: s.resources,
+            not_resources: s.not_resources,
+            conditions: s.conditions,
+        })
+    }
+}
+
+/// The names of the variables which may appear as `${...}` in patterns.
+pub const VARIABLE_NAMES: &[&str] = &["user", "group", "date"];
+
+/// Values substituted for `${...}` variables in policy statement patterns.
+pub struct PolicyVariables {
+    pub user: String,
+    pub groups: Vec<String>,
+    pub date: String,
+}
+
+impl PolicyVariables {
+    pub fn for_user(user: &User) -> PolicyVariables {
+        PolicyVariables {
+            user: user.login_name.to_string(),
+            groups: user.groups.iter().map(|g| g.to_string()).collect(),
+            date: OffsetDateTime::now_utc().date().to_string(),
+        }
+    }
+
+    fn values(&self, name: &str) -> Option<Vec<&str>> {
+        match name {
+            "user" => Some(vec![self.user.as_str()]),
+            "group" => Some(self.groups.iter().map(String::as_str).collect()),
+            "date" => Some(vec![self.date.as_str()]),
+            _ => None,
+        }
+    }
+
+    /// Expands every variable in `pattern`, producing one pattern per
+    /// combination of values (`${group}` has a value per group). A pattern
+    /// referencing an unknown variable expands to nothing so it never matches.
+    pub fn expand(&self, pattern: &str) -> Vec<String> {
+        let mut expanded = vec![String::new()];
+        let mut rest = pattern;
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            let values = match self.values(&rest[start + 2..end]) {
+                Some(values) => values,
+                None => return Vec::new(),
+            };
+            let literal = &rest[..start];
+            expanded = expanded
+                .iter()
+                .flat_map(|prefix| {
+                    values
+                        .iter()
+                        .map(move |v| format!("{prefix}{literal}{}", util::glob_escape(v)))
+                })
+                .collect();
+            rest = &rest[end + 1..];
+        }
+        expanded.iter_mut().for_each(|p| p.push_str(rest));
+        expanded
+    }
 }
 
 impl PolicyStatement {
+    /// Returns a copy of this statement with all pattern variables expanded.
+    /// If a `not_` pattern expands to nothing the statement would match far
+    /// more than intended, so it is made to match nothing instead.
+    pub fn expand(&self, variables: &PolicyVariables) -> PolicyStatement {
+        let mut inert = false;
+        let expand_all = |patterns: &Vec<String>| {
+            patterns
+                .iter()
+                .flat_map(|p| variables.expand(p))
+                .collect::<Vec<String>>()
+        };
+        let mut expand_negated = |patterns: &Option<Vec<String>>| {
+            patterns.as_ref().map(|patterns| {
+                let expanded = patterns
+                    .iter()
+                    .map(|p| variables.expand(p))
+                    .collect::<Vec<Vec<String>>>();
+                inert |= expanded.iter().any(|e| e.is_empty());
+                expanded.concat()
+            })
+        };
+        let not_actions = expand_negated(&self.not_actions);
+        let not_resources = expand_negated(&self.not_resources);
+        if inert {
+            return PolicyStatement {
+                version: self.version,
+                effect: self.effect,
+                actions: Vec::new(),
+                not_actions: None,
+                resources: Vec::new(),
+                not_resources: None,
+                conditions: None,
+            };
+        }
+        PolicyStatement {
+            version: self.version,
+            effect: self.effect,
+            actions: expand_all(&self.actions),
+            not_actions,
+            resources: expand_all(&self.resources),
+            not_resources,
+            conditions: self.conditions.clone(),
+        }
+    }
+
     fn matches_action(&self, action: &str) -> bool {
-        self.actions
-            .iter()
-            .any(|pattern| util::glob_matches(pattern, action))
+        match &self.not_actions {
+            Some(not_actions) => !matches_any(not_actions, action),
+            None => matches_any(&self.actions, action),
+        }
     }
 
     fn matches_resource(&self, resource: &str) -> bool {
-        self.resources
-            .iter()
-            .any(|pattern| util::glob_matches(pattern, resource))
+        let matches = match self.version {
+            PolicyVersion::V1 => util::glob_matches,
+            PolicyVersion::V2 => util::path_glob_matches,
+        };
+        match &self.not_resources {
+            Some(not_resources) => !not_resources.iter().any(|p| matches(p, resource)),
+            None => self.resources.iter().any(|p| matches(p, resource)),
+        }
     }
 
-    pub fn effect_on(&self, action: &str, resource: &str) -> Option<Effect> {
-        if self.matches_action(action) && self.matches_resource(resource) {
+    /// Whether the statement's actions and resources match, ignoring its
+    /// conditions.
+    pub fn applies_to(&self, action: &str, resource: &str) -> bool {
+        self.matches_action(action) && self.matches_resource(resource)
+    }
+
+    pub fn conditions_hold(&self, context: &RequestContext) -> bool {
+        self.conditions
+            .as_ref()
+            .map(|c| c.hold(context))
+            .unwrap_or(true)
+    }
+
+    pub fn effect_on(
+        &self,
+        action: &str,
+        resource: &str,
+        context: &RequestContext,
+    ) -> Option<Effect> {
+        if self.applies_to(action, resource) && self.conditions_hold(context) {
             Some(self.effect)
         } else {
             None
@@ -59,17 +371,251 @@ impl PolicyStatement {
     }
 }
 
-pub trait PolicyStore {
-    fn list_users(&self) -> Result<Vec<User>, ()>;
-    fn user_named(&self, name: &str) -> Result<User, ()>;
-    fn create_user(&self, user: &User) -> Result<(), ()>;
-    fn update_user(&self, user: &User) -> Result<(), ()>;
+fn matches_any(patterns: &[String], s: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| util::glob_matches(pattern, s))
+}
+
+/// A breadth-first walk from some groups up through all of their ancestors,
+/// nearest first. Each group is visited once even if the stored hierarchy
+/// contains a cycle, and groups which don't exist are skipped. The caller
+/// looks up each group named by `next`, so that the same walk serves both
+/// blocking and async stores.
+struct Ancestry {
+    seen: HashSet<PrincipalName>,
+    pending: VecDeque<PrincipalName>,
+    groups: Vec<Group>,
+}
+
+impl Ancestry {
+    fn new(names: &[PrincipalName]) -> Ancestry {
+        Ancestry {
+            seen: HashSet::new(),
+            pending: names.iter().cloned().collect(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// The next group to look up, if the walk isn't finished.
+    fn next(&mut self) -> Option<PrincipalName> {
+        while let Some(name) = self.pending.pop_front() {
+            if self.seen.insert(name.clone()) {
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    fn found(&mut self, group: Option<Group>) {
+        if let Some(group) = group {
+            self.pending.extend(group.parents.iter().cloned());
+            self.groups.push(group);
+        }
+    }
+}
+
+/// Expands group names into the groups along with all of their ancestors,
+/// nearest first, looking each group up with `group_named`.
+pub fn expand_groups_with<F>(names: &[PrincipalName], group_named: F) -> Vec<Group>
+where
+    F: Fn(&PrincipalName) -> Option<Group>,
+{
+    let mut ancestry = Ancestry::new(names);
+    while let Some(name) = ancestry.next() {
+        ancestry.found(group_named(&name));
+    }
+    ancestry.groups
+}
+
+/// Whether storing `group`, whose parents' ancestry is `ancestors`, would
+/// make it one of its own ancestors.
+fn is_own_ancestor(group: &Group, ancestors: &[Group]) -> bool {
+    group.parents.contains(&group.name) || ancestors.iter().any(|g| g.name == group.name)
+}
+
+/// Whether storing `group` would make it one of its own ancestors.
+pub fn creates_cycle_with<F>(group: &Group, group_named: F) -> bool
+where
+    F: Fn(&PrincipalName) -> Option<Group>,
+{
+    is_own_ancestor(group, &expand_groups_with(&group.parents, group_named))
+}
+
+/// How many times a change to a user's groups is made before giving up, when
+/// the user keeps being changed by someone else in the meantime.
+const MEMBERSHIP_ATTEMPTS: usize = 3;
+
+/// Applies `edit` to a user's groups, unless it returns false to say there's
+/// nothing to do. The write is based on the revision that was read, so that a
+/// change made to the user in the meantime isn't undone; the edit is tried
+/// again on the user as they are now.
+async fn revise_groups<S, F>(
+    store: &S,
+    login_name: &PrincipalName,
+    change: &Change,
+    edit: F,
+) -> Result<(), PolicyStoreError>
+where
+    S: PolicyStore + ?Sized,
+    F: Fn(&mut Vec<PrincipalName>) -> bool + Send + Sync,
+{
+    let mut attempts = 1;
+    loop {
+        let revision = store.revision(DocumentKind::User, login_name).await?;
+        let mut user = store.user_named(login_name).await?;
+        if !edit(&mut user.groups) {
+            return Ok(());
+        }
+        let based = Change {
+            base_revision: Some(revision),
+            ..change.clone()
+        };
+        match store.update_user(&user, &based).await {
+            Err(PolicyStoreError::Conflict(_)) if attempts < MEMBERSHIP_ATTEMPTS => attempts += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Users, groups and policies. Implementations which do blocking I/O must keep
+/// it off the async runtime's worker threads, e.g. with `spawn_blocking`.
+#[rocket::async_trait]
+pub trait PolicyStore: Send + Sync {
+    async fn list_users(&self) -> Result<Vec<User>, PolicyStoreError>;
+    async fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError>;
+    async fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError>;
+    async fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError>;
+
+    async fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError>;
+    async fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError>;
+
+    /// The stored password hash of a user, for moving them to another store.
+    async fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError>;
+    async fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError>;
+
+    async fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError>;
+    async fn group_named(&self, name: &PrincipalName) -> Option<Group>;
+    async fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError>;
+    async fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError>;
 
-    fn set_user_password(&self, login_name: &str, password: Option<&str>) -> Result<(), ()>;
-    fn authenticate_user(&self, login_name: &str, password: &str) -> Result<User, ()>;
+    /// Users who list the group directly in their `groups`.
+    async fn group_members(&self, name: &PrincipalName) -> Result<Vec<User>, PolicyStoreError> {
+        Ok(self
+            .list_users()
+            .await?
+            .into_iter()
+            .filter(|u| u.groups.contains(name))
+            .collect())
+    }
+
+    async fn add_group_member(
+        &self,
+        name: &PrincipalName,
+        login_name: &PrincipalName,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        revise_groups(self, login_name, change, |groups| {
+            if groups.contains(name) {
+                return false;
+            }
+            groups.push(name.clone());
+            true
+        })
+        .await
+    }
 
-    fn list_groups(&self) -> Result<Vec<Group>, ()>;
-    fn group_named(&self, name: &str) -> Option<Group>;
-    fn create_group(&self, group: &Group) -> Result<(), ()>;
-    fn update_group(&self, group: &Group) -> Result<(), ()>;
+    async fn remove_group_member(
+        &self,
+        name: &PrincipalName,
+        login_name: &PrincipalName,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        revise_groups(self, login_name, change, |groups| {
+            if !groups.contains(name) {
+                return false;
+            }
+            groups.retain(|g| g != name);
+            true
+        })
+        .await
+    }
+
+    /// Resolves the named groups along with all of their ancestors, nearest
+    /// first. See `expand_groups_with`.
+    async fn expand_groups(&self, names: &[PrincipalName]) -> Vec<Group> {
+        let mut ancestry = Ancestry::new(names);
+        while let Some(name) = ancestry.next() {
+            ancestry.found(self.group_named(&name).await);
+        }
+        ancestry.groups
+    }
+
+    /// Whether storing `group` would make it one of its own ancestors.
+    async fn creates_cycle(&self, group: &Group) -> bool {
+        is_own_ancestor(group, &self.expand_groups(&group.parents).await)
+    }
+
+    /// The statements which apply to `user`, with their variables expanded.
+    async fn policy_statements_for(&self, user: &User) -> Vec<SourcedStatement> {
+        authorizor::resolve_policy_statements(self, user).await
+    }
+
+    async fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError>;
+    async fn policy_named(&self, name: &str) -> Option<ManagedPolicy>;
+    async fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+    async fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+
+    /// Removes a document. Its history is kept, so a document created later
+    /// with the same name carries on from its latest revision. Nothing checks
+    /// whether other documents still refer to it.
+    async fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+
+    /// Every recorded revision of a document, oldest first. Stores which
+    /// don't keep history have none.
+    async fn history(
+        &self,
+        _kind: DocumentKind,
+        _name: &str,
+    ) -> Result<Vec<Revision>, PolicyStoreError> {
+        Ok(Vec::new())
+    }
+
+    /// The latest revision of a document, or 0 if it has none.
+    async fn revision(&self, kind: DocumentKind, name: &str) -> Result<u64, PolicyStoreError> {
+        Ok(self
+            .history(kind, name)
+            .await?
+            .last()
+            .map(|r| r.revision)
+            .unwrap_or(0))
+    }
 }
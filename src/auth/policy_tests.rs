@@ -1,56 +1,384 @@
-#[cfg(test)]
-mod policy_tests {
-    use super::super::*;
-
-    // struct TestContext {
-    //     empty_user: User,
-    // }
-
-    // impl PolicyStore for TestContext {
-    //     fn list_users(&self) -> Result<Vec<User>, ()> {
-    //         todo!()
-    //     }
-    //     fn create_user(&self, user: &User) -> Result<(), ()> {
-    //         todo!()
-    //     }
-    //     fn update_user(&self, user: &User) -> Result<(), ()> {
-    //         todo!()
-    //     }
-    //     fn set_user_password(&self, login_name: &str, password: Option<&str>) -> Result<(), ()> {
-    //         todo!()
-    //     }
-    //     fn authenticate_user(&self, login_name: &str, password: &str) -> Result<User, ()> {
-    //         todo!()
-    //     }
-    //     fn user_named(&self, name: &str) -> Result<User, ()> {
-    //         todo!()
-    //     }
-    //     fn list_groups(&self) -> Result<Vec<Group>, ()> {
-    //         todo!()
-    //     }
-    //     fn group_named(&self, name: &str) -> Option<Group> {
-    //         todo!()
-    //     }
-    // }
-
-    // fn setup() -> TestContext {
-    //     TestContext {
-    //         empty_user: User {
-    //             login_name: String::from("username"),
-    //             full_name: None,
-    //             groups: Vec::new(),
-    //             policy_statements: Vec::new(),
-    //         },
-    //     }
-    // }
-
-    // #[test]
-    // fn test_user_may_perform_empty_denies() {
-    //     let ctx = setup();
-    //     assert_eq!(
-    //         false,
-    //         ctx.empty_user
-    //             .may_perform(Box::new(ctx), "some:action", "some:resource")
-    //     );
-    // }
+use super::*;
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+use rocket::serde::json;
+
+fn variables() -> PolicyVariables {
+    PolicyVariables {
+        user: String::from("alice"),
+        groups: vec![String::from("staff"), String::from("ops")],
+        date: String::from("2023-01-31"),
+    }
+}
+
+fn ctx() -> RequestContext {
+    RequestContext::unknown()
+}
+
+fn statement(effect: Effect, actions: &[&str], resources: &[&str]) -> PolicyStatement {
+    PolicyStatement {
+        version: PolicyVersion::V1,
+        effect,
+        actions: actions.iter().map(|s| String::from(*s)).collect(),
+        not_actions: None,
+        resources: resources.iter().map(|s| String::from(*s)).collect(),
+        not_resources: None,
+        conditions: None,
+    }
+}
+
+#[test]
+fn test_expand_without_variables() {
+    assert_eq!(vec!["home/*"], variables().expand("home/*"));
+}
+
+#[test]
+fn test_expand_user_and_date() {
+    assert_eq!(
+        vec!["home/alice/2023-01-31/*"],
+        variables().expand("home/${user}/${date}/*")
+    );
+}
+
+#[test]
+fn test_expand_group_per_membership() {
+    assert_eq!(
+        vec!["shared/staff/*", "shared/ops/*"],
+        variables().expand("shared/${group}/*")
+    );
+}
+
+#[test]
+fn test_expand_unknown_variable_never_matches() {
+    assert!(variables().expand("home/${nobody}/*").is_empty());
+}
+
+#[test]
+fn test_expand_escapes_glob_characters() {
+    let vars = PolicyVariables {
+        user: String::from("a*"),
+        ..variables()
+    };
+    let s = statement(Effect::Allow, &["file:Read"], &["home/${user}/*"]).expand(&vars);
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Read", "home/a*/x", &ctx())
+    );
+    assert_eq!(None, s.effect_on("file:Read", "home/abc/x", &ctx()));
+}
+
+#[test]
+fn test_expanded_statement_matches_own_home_only() {
+    let s = statement(Effect::Allow, &["file:*"], &["home/${user}/*"]).expand(&variables());
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Write", "home/alice/notes.txt", &ctx())
+    );
+    assert_eq!(
+        None,
+        s.effect_on("file:Write", "home/bob/notes.txt", &ctx())
+    );
+}
+
+fn conditional(conditions: &str) -> PolicyStatement {
+    json::from_str(&format!(
+        r#"{{"effect": "Allow", "actions": ["*"], "resources": ["*"], "conditions": {conditions}}}"#
+    ))
+    .expect("statement should deserialize")
+}
+
+#[test]
+fn test_conditions_unknown_key_is_rejected() {
+    let s = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"], "conditions": {"source_iq": []}}"#,
+    );
+    assert!(s.is_err());
+    // There's no second factor to require.
+    let s = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"], "conditions": {"mfa_required": true}}"#,
+    );
+    assert!(s.is_err());
+}
+
+#[test]
+fn test_conditions_source_ip() {
+    let s = conditional(r#"{"source_ip": ["10.1.0.0/16", "192.168.1.7"]}"#);
+    let from = |ip: &str| RequestContext {
+        client_ip: Some(ip.parse().unwrap()),
+        ..ctx()
+    };
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("a", "r", &from("10.1.200.3"))
+    );
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("a", "r", &from("192.168.1.7"))
+    );
+    assert_eq!(None, s.effect_on("a", "r", &from("10.2.0.1")));
+    assert_eq!(None, s.effect_on("a", "r", &ctx()));
+}
+
+#[test]
+fn test_conditions_time_window() {
+    let s = conditional(
+        r#"{"time_window": {"start": "09:00", "end": "17:00", "days": ["Mon", "Friday"], "utc_offset": "-05:00"}}"#,
+    );
+    let at = |unix: i64| RequestContext {
+        now: Some(OffsetDateTime::from_unix_timestamp(unix).unwrap()),
+        ..ctx()
+    };
+    // Monday 2023-01-02 10:00 and 08:00 at -05:00, then Tuesday 10:00.
+    assert_eq!(Some(Effect::Allow), s.effect_on("a", "r", &at(1672671600)));
+    assert_eq!(None, s.effect_on("a", "r", &at(1672664400)));
+    assert_eq!(None, s.effect_on("a", "r", &at(1672758000)));
+}
+
+#[test]
+fn test_conditions_time_window_wraps_midnight() {
+    let s = conditional(r#"{"time_window": {"start": "22:00", "end": "06:00"}}"#);
+    let at = |unix: i64| RequestContext {
+        now: Some(OffsetDateTime::from_unix_timestamp(unix).unwrap()),
+        ..ctx()
+    };
+    // 2023-01-02 at 23:00, 05:00 and 12:00 UTC.
+    assert_eq!(Some(Effect::Allow), s.effect_on("a", "r", &at(1672700400)));
+    assert_eq!(Some(Effect::Allow), s.effect_on("a", "r", &at(1672635600)));
+    assert_eq!(None, s.effect_on("a", "r", &at(1672660800)));
+}
+
+#[test]
+fn test_conditions_invalid_utc_offset_is_rejected() {
+    for offset in ["é5:00", "05:00", "+5", "+xx:00", ""] {
+        let conditions = format!(
+            r#"{{"time_window": {{"start": "09:00", "end": "17:00", "utc_offset": "{offset}"}}}}"#
+        );
+        assert!(
+            json::from_str::<Conditions>(&conditions).is_err(),
+            "{offset}"
+        );
+    }
+}
+
+#[test]
+fn test_conditions_https_and_file_size() {
+    let s = conditional(r#"{"https_only": true, "max_file_size": 1024}"#);
+    let req = |https: bool, content_length: Option<u64>| RequestContext {
+        https,
+        content_length,
+        ..ctx()
+    };
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("a", "r", &req(true, Some(1024)))
+    );
+    assert_eq!(None, s.effect_on("a", "r", &req(false, Some(10))));
+    assert_eq!(None, s.effect_on("a", "r", &req(true, Some(1025))));
+    // A body of unknown size can't be shown to be within the limit.
+    assert_eq!(None, s.effect_on("a", "r", &req(true, None)));
+    let chunked = RequestContext {
+        chunked: true,
+        ..req(true, Some(10))
+    };
+    assert_eq!(None, s.effect_on("a", "r", &chunked));
+}
+
+fn negated(effect: Effect, not_actions: &[&str], not_resources: &[&str]) -> PolicyStatement {
+    let strings = |v: &[&str]| v.iter().map(|s| String::from(*s)).collect::<Vec<String>>();
+    PolicyStatement {
+        not_actions: Some(strings(not_actions)),
+        not_resources: Some(strings(not_resources)),
+        ..statement(effect, &[], &[])
+    }
+}
+
+#[test]
+fn test_not_actions_matches_unlisted_actions() {
+    let s = PolicyStatement {
+        not_actions: Some(vec![String::from("file:Delete")]),
+        ..statement(Effect::Allow, &[], &["archive/*"])
+    };
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Read", "archive/a", &ctx())
+    );
+    assert_eq!(None, s.effect_on("file:Delete", "archive/a", &ctx()));
+    assert_eq!(None, s.effect_on("file:Read", "current/a", &ctx()));
+}
+
+#[test]
+fn test_not_resources_matches_unlisted_resources() {
+    let s = PolicyStatement {
+        not_resources: Some(vec![String::from("archive/*")]),
+        ..statement(Effect::Deny, &["file:Delete"], &[])
+    };
+    assert_eq!(
+        Some(Effect::Deny),
+        s.effect_on("file:Delete", "current/a", &ctx())
+    );
+    assert_eq!(None, s.effect_on("file:Delete", "archive/a", &ctx()));
+    assert_eq!(None, s.effect_on("file:Read", "current/a", &ctx()));
+}
+
+#[test]
+fn test_not_actions_and_not_resources_together() {
+    let s = negated(Effect::Allow, &["file:Delete"], &["archive/*"]);
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Write", "current/a", &ctx())
+    );
+    assert_eq!(None, s.effect_on("file:Delete", "current/a", &ctx()));
+    assert_eq!(None, s.effect_on("file:Write", "archive/a", &ctx()));
+}
+
+#[test]
+fn test_positive_and_negative_forms_are_exclusive() {
+    let both_actions = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "actions": ["*"], "not_actions": ["file:Delete"], "resources": ["*"]}"#,
+    );
+    assert!(both_actions.is_err());
+    let both_resources = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"], "not_resources": ["a/*"]}"#,
+    );
+    assert!(both_resources.is_err());
+}
+
+#[test]
+fn test_negative_form_deserializes_without_positive_lists() {
+    let s = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "not_actions": ["file:Delete"], "resources": ["*"]}"#,
+    )
+    .expect("statement should deserialize");
+    assert_eq!(Some(Effect::Allow), s.effect_on("file:Read", "a", &ctx()));
+    assert_eq!(None, s.effect_on("file:Delete", "a", &ctx()));
+}
+
+#[test]
+fn test_unexpandable_negated_pattern_matches_nothing() {
+    let vars = PolicyVariables {
+        groups: Vec::new(),
+        ..variables()
+    };
+    let s = negated(Effect::Allow, &[], &["shared/${group}/*"]).expand(&vars);
+    assert_eq!(None, s.effect_on("file:Read", "anything", &ctx()));
+}
+
+#[test]
+fn test_v1_star_crosses_directories() {
+    let s = statement(Effect::Allow, &["file:Read"], &["projects/*"]);
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Read", "projects/a/b", &ctx())
+    );
+}
+
+#[test]
+fn test_v2_star_matches_direct_children_only() {
+    let s = PolicyStatement {
+        version: PolicyVersion::V2,
+        ..statement(Effect::Allow, &["file:Read"], &["projects/*"])
+    };
+    assert_eq!(
+        Some(Effect::Allow),
+        s.effect_on("file:Read", "projects/a", &ctx())
+    );
+    assert_eq!(None, s.effect_on("file:Read", "projects/a/b", &ctx()));
+}
+
+#[test]
+fn test_version_defaults_to_v1_and_rejects_unknown() {
+    let s = json::from_str::<PolicyStatement>(
+        r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"]}"#,
+    )
+    .expect("statement should deserialize");
+    assert_eq!(PolicyVersion::V1, s.version);
+    let s = json::from_str::<PolicyStatement>(
+        r#"{"version": 3, "effect": "Allow", "actions": ["*"], "resources": ["*"]}"#,
+    );
+    assert!(s.is_err());
+}
+
+fn group(name: &str, parents: &[&str]) -> Group {
+    Group {
+        name: name.parse().unwrap(),
+        description: None,
+        policy_statements: Vec::new(),
+        policies: Vec::new(),
+        parents: parents.iter().map(|p| p.parse().unwrap()).collect(),
+    }
+}
+
+/// An in-memory store holding `groups`, which are written as given, even
+/// if their parents don't exist yet.
+async fn group_store(groups: &[Group]) -> Blocking<SqlitePolicyStore> {
+    let store = Blocking::new(SqlitePolicyStore::open_in_memory().unwrap());
+    for group in groups {
+        store.create_group(group, &Change::default()).await.unwrap();
+    }
+    store
+}
+
+fn names(groups: Vec<Group>) -> Vec<String> {
+    groups.into_iter().map(|g| g.name.into()).collect()
+}
+
+#[rocket::async_test]
+async fn test_expand_groups_includes_ancestors_once() {
+    let store = group_store(&[
+        group("team", &["dept"]),
+        group("other-team", &["dept"]),
+        group("dept", &["company"]),
+        group("company", &[]),
+    ])
+    .await;
+    assert_eq!(
+        vec!["team", "other-team", "dept", "company"],
+        names(
+            store
+                .expand_groups(&["team".parse().unwrap(), "other-team".parse().unwrap()])
+                .await
+        )
+    );
+}
+
+#[rocket::async_test]
+async fn test_expand_groups_survives_stored_cycle() {
+    let store = group_store(&[group("a", &["b"]), group("b", &["a"])]).await;
+    assert_eq!(
+        vec!["a", "b"],
+        names(store.expand_groups(&["a".parse().unwrap()]).await)
+    );
+}
+
+#[rocket::async_test]
+async fn test_creates_cycle() {
+    let store = group_store(&[group("team", &["dept"]), group("dept", &[])]).await;
+    assert!(store.creates_cycle(&group("dept", &["team"])).await);
+    assert!(store.creates_cycle(&group("dept", &["dept"])).await);
+    assert!(!store.creates_cycle(&group("dept", &["company"])).await);
+    assert!(!store.creates_cycle(&group("new", &["team"])).await);
+}
+
+#[rocket::async_test]
+async fn test_concurrent_membership_changes_are_all_kept() {
+    let store = group_store(&[group("staff", &[]), group("ops", &[])]).await;
+    let dan = User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: Vec::new(),
+        policy_statements: Vec::new(),
+        policies: Vec::new(),
+    };
+    let change = Change::default();
+    store.create_user(&dan, &change).await.unwrap();
+    let (staff, ops) = ("staff".parse().unwrap(), "ops".parse().unwrap());
+    let (staff, ops) = rocket::tokio::join!(
+        store.add_group_member(&staff, &dan.login_name, &change),
+        store.add_group_member(&ops, &dan.login_name, &change),
+    );
+    staff.unwrap();
+    ops.unwrap();
+    let mut groups = store.user_named(&dan.login_name).await.unwrap().groups;
+    groups.sort();
+    assert_eq!(vec!["ops", "staff"], groups);
 }
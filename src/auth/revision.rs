@@ -0,0 +1,101 @@
+use crate::body;
+use crate::error::{guard_failure, ApiError};
+use rocket::data::{self, Data, FromData};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
+
+/// A policy store document along with the revision it was read at. Clients
+/// send the revision back with an update so a stale write can be detected.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Revisioned<T> {
+    #[serde(flatten)]
+    pub document: T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+}
+
+impl<T> Revisioned<T> {
+    pub fn new(document: T, revision: u64) -> Revisioned<T> {
+        Revisioned {
+            document,
+            revision: Some(revision),
+        }
+    }
+}
+
+/// Reads a document and the revision it is based on from a request body. The
+/// document is read on its own, rather than flattened, so that an error in it
+/// names the field at fault.
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for Revisioned<T> {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let result = body::read(request, data).await.and_then(|mut value| {
+            let revision = match &mut value {
+                Value::Object(fields) => fields.remove("revision"),
+                _ => None,
+            };
+            let revision = match revision {
+                None | Some(Value::Null) => None,
+                Some(revision) => Some(revision.as_u64().ok_or_else(|| {
+                    body::invalid("revision", "The revision must be a whole number")
+                })?),
+            };
+            Ok(Revisioned {
+                document: body::parse(value)?,
+                revision,
+            })
+        });
+        body::outcome(request, result)
+    }
+}
+
+fn etag(revision: u64) -> Header<'static> {
+    Header::new("ETag", format!("\"{revision}\""))
+}
+
+/// A single document, with its revision in both the body and the `ETag`
+/// header.
+#[derive(Responder)]
+pub struct Tagged<T: Serialize> {
+    inner: Json<Revisioned<T>>,
+    etag: Header<'static>,
+}
+
+impl<T: Serialize> Tagged<T> {
+    pub fn new(document: T, revision: u64) -> Tagged<T> {
+        Tagged {
+            inner: Json(Revisioned::new(document, revision)),
+            etag: etag(revision),
+        }
+    }
+}
+
+/// The revision given in an `If-Match` header, if any. `*` matches any
+/// revision so is treated like no header at all.
+pub struct IfMatch(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<IfMatch, &'static str> {
+        let value = match request.headers().get_one("If-Match") {
+            None | Some("*") => return Outcome::Success(IfMatch(None)),
+            Some(value) => value,
+        };
+        let revision = value
+            .trim()
+            .trim_start_matches("W/")
+            .trim_matches('"')
+            .parse::<u64>();
+        match revision {
+            Ok(revision) => Outcome::Success(IfMatch(Some(revision))),
+            Err(_) => guard_failure(request, Status::BadRequest, "Invalid If-Match header"),
+        }
+    }
+}
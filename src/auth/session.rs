@@ -4,15 +4,17 @@ use rocket::request::{FromRequest, Outcome, Request};
 use rocket::serde::{json, Deserialize, Serialize};
 use rocket::State;
 
-use crate::auth::policy::{PolicyStore, User};
+use crate::auth::name::PrincipalName;
+use crate::auth::policy::{Change, PolicyStore, User};
+use crate::error::{guard_failure, Noted};
 use crate::util::now_as_secs;
 
-use super::store::files::FilePolicyStore;
+use super::store::BoxedPolicyStore;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct SessionCookie {
-    pub username: String,
+    pub username: PrincipalName,
     pub expires: u64,
 }
 
@@ -23,23 +25,54 @@ pub struct Session {
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Session {
-    type Error = ();
+    type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let now = try_outcome!(now_as_secs().into_outcome(Status::InternalServerError));
-        let policy_store = try_outcome!(request.guard::<&State<FilePolicyStore>>().await);
-        let policy_store = policy_store.inner();
-        // TODO: Load user from store
-        request
+        let now = try_outcome!(now_as_secs()
+            .map_err(|_| "Clock error")
+            .into_outcome(Status::InternalServerError)
+            .noted(request));
+        let policy_store = try_outcome!(request
+            .guard::<&State<BoxedPolicyStore>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, "No policy store"))
+            .noted(request));
+        let cookie = request
             .cookies()
             .get_private("session")
             .map(|cookie| String::from(cookie.value()))
             .and_then(|s| json::from_str::<SessionCookie>(s.as_str()).ok())
-            .filter(|session| session.expires > now)
-            .map(|s| policy_store.user_named(&s.username))
-            .filter(|r| r.is_ok())
-            .map(|r| r.unwrap())
-            .map(|user| Session { user })
-            .into_outcome((Status::Unauthorized, ()))
+            .filter(|session| session.expires > now);
+        let session = match cookie {
+            Some(cookie) => policy_store
+                .user_named(&cookie.username)
+                .await
+                .ok()
+                .map(|user| Session { user }),
+            None => None,
+        };
+        match session {
+            Some(session) => Outcome::Success(session),
+            None => guard_failure(request, Status::Unauthorized, "You are not logged in"),
+        }
+    }
+}
+
+/// A change to the policy store made by the session user, for the reason given
+/// in the `X-Change-Reason` header.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Change {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = try_outcome!(request.guard::<Session>().await);
+        Outcome::Success(Change {
+            author: Some(session.user.login_name.into()),
+            reason: request
+                .headers()
+                .get_one("X-Change-Reason")
+                .map(String::from),
+            base_revision: None,
+        })
     }
 }
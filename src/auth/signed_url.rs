@@ -0,0 +1,261 @@
+use crate::audit::AuditLog;
+use crate::auth::authorizor::RequestAuthorizor;
+use crate::auth::condition::RequestContext;
+use crate::auth::name::PrincipalName;
+use crate::auth::policy::PolicyStore;
+use crate::auth::store::BoxedPolicyStore;
+use crate::error::{guard_failure, Noted};
+use crate::files::RequestedFile;
+use crate::util::now_as_secs;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rocket::figment::Figment;
+use rocket::http::{Method, RawStr, Status};
+use rocket::outcome::{try_outcome, IntoOutcome};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::State;
+use sha2::Sha256;
+use std::path::PathBuf;
+
+#[cfg(test)]
+#[path = "signed_url_tests.rs"]
+mod signed_url_tests;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DEFAULT_TTL_SECS: u64 = 300;
+pub const MAX_TTL_SECS: u64 = 3600;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub enum SignedMethod {
+    #[serde(rename = "GET")]
+    Get,
+    #[serde(rename = "PUT")]
+    Put,
+}
+
+impl SignedMethod {
+    pub fn action(&self) -> &'static str {
+        match self {
+            SignedMethod::Get => "file:Read",
+            SignedMethod::Put => "file:Write",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SignedMethod::Get => "GET",
+            SignedMethod::Put => "PUT",
+        }
+    }
+
+    fn from_method(method: Method) -> Option<SignedMethod> {
+        match method {
+            Method::Get => Some(SignedMethod::Get),
+            Method::Put => Some(SignedMethod::Put),
+            _ => None,
+        }
+    }
+}
+
+/// Everything covered by a signed URL's signature.
+pub struct SignedUrlClaims<'a> {
+    pub method: SignedMethod,
+    pub logical_path: &'a str,
+    pub expires: u64,
+    pub login_name: &'a str,
+}
+
+impl SignedUrlClaims<'_> {
+    pub fn to_url(&self, signature: &str) -> String {
+        let path = self
+            .logical_path
+            .split('/')
+            .map(|s| RawStr::new(s).percent_encode().to_string())
+            .collect::<Vec<String>>()
+            .join("/");
+        format!(
+            "/api/signed/{}?user={}&expires={}&signature={}",
+            path,
+            RawStr::new(self.login_name).percent_encode(),
+            self.expires,
+            signature
+        )
+    }
+}
+
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(secret: &[u8]) -> UrlSigner {
+        // Derive a dedicated key so signatures can't be confused with
+        // anything else keyed from the same secret.
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+        mac.update(b"swaf signed url");
+        UrlSigner {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    pub fn from_figment(figment: &Figment) -> UrlSigner {
+        if let Ok(secret) = figment.extract_inner::<String>("secret_key") {
+            return UrlSigner::new(secret.as_bytes());
+        }
+        if let Ok(secret) = figment.extract_inner::<Vec<u8>>("secret_key") {
+            return UrlSigner::new(&secret);
+        }
+        warn!("No secret_key configured. Signed URLs will not survive a restart.");
+        UrlSigner::new(&rand::random::<[u8; 32]>())
+    }
+
+    fn mac(&self, claims: &SignedUrlClaims) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        let expires = claims.expires.to_string();
+        for field in [
+            claims.method.as_str(),
+            claims.logical_path,
+            expires.as_str(),
+            claims.login_name,
+        ] {
+            // Length-prefix each field so no two claim sets share an encoding.
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        mac
+    }
+
+    pub fn sign(&self, claims: &SignedUrlClaims) -> String {
+        self.mac(claims)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    pub fn verify(&self, claims: &SignedUrlClaims, signature: &str) -> bool {
+        match decode_hex(signature) {
+            Some(sig) => self.mac(claims).verify_slice(&sig).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .filter(|c| c.len() == 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+        })
+        .collect()
+}
+
+/// A file addressed by a valid, unexpired signed URL. The request is
+/// authorized as the issuing user, restricted to the signed method's action
+/// on the signed path.
+pub struct SignedFileRequest {
+    pub real_path: PathBuf,
+    pub logical_path: PathBuf,
+    pub login_name: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SignedFileRequest {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<SignedFileRequest, &'static str> {
+        let method = try_outcome!(SignedMethod::from_method(request.method())
+            .into_outcome((Status::MethodNotAllowed, "Method cannot be signed"))
+            .noted(request));
+        let login_name = try_outcome!(query_param::<&str>(request, "user"));
+        let expires = try_outcome!(query_param::<u64>(request, "expires"));
+        let signature = try_outcome!(query_param::<&str>(request, "signature"));
+        let now = try_outcome!(now_as_secs()
+            .map_err(|_| "Clock error")
+            .into_outcome(Status::InternalServerError)
+            .noted(request));
+        if expires <= now {
+            return guard_failure(request, Status::Forbidden, "Signed URL has expired");
+        }
+
+        let file = try_outcome!(request.guard::<RequestedFile>().await);
+        let logical_path = try_outcome!(file
+            .logical_path
+            .to_str()
+            .into_outcome((Status::BadRequest, "Invalid path"))
+            .noted(request));
+        let signer = try_outcome!(request
+            .guard::<&State<UrlSigner>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, "No URL signer"))
+            .noted(request));
+        let claims = SignedUrlClaims {
+            method,
+            logical_path,
+            expires,
+            login_name,
+        };
+        if !signer.verify(&claims, signature) {
+            info!("Rejected signed URL with invalid signature for '{logical_path}'.");
+            return guard_failure(request, Status::Forbidden, "Invalid signature");
+        }
+
+        let policy_store = try_outcome!(request
+            .guard::<&State<BoxedPolicyStore>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, "No policy store"))
+            .noted(request));
+        let issuer = match PrincipalName::parse(login_name) {
+            Ok(login_name) => policy_store.user_named(&login_name).await.ok(),
+            Err(_) => None,
+        };
+        let user = try_outcome!(issuer
+            .into_outcome((Status::Forbidden, "Issuing user not found"))
+            .noted(request));
+        let context = RequestContext::capture(request);
+        let authorizor = RequestAuthorizor::for_user(policy_store.inner(), user, context)
+            .await
+            .restricted_to(method.action(), logical_path)
+            .audited(request.rocket().state::<AuditLog>().cloned());
+        try_outcome!(authorizor
+            .require(method.action(), &logical_path)
+            .ok()
+            .map_err(|_| "Access denied")
+            .into_outcome(Status::Forbidden)
+            .noted(request));
+
+        if method == SignedMethod::Get && !file.real_path.is_file() {
+            return guard_failure(
+                request,
+                Status::NotFound,
+                "Requested path does not exist or is not a regular file.",
+            );
+        }
+        Outcome::Success(SignedFileRequest {
+            real_path: file.real_path,
+            logical_path: file.logical_path,
+            login_name: String::from(login_name),
+        })
+    }
+}
+
+fn query_param<'r, T: rocket::form::FromFormField<'r>>(
+    request: &'r Request<'_>,
+    name: &str,
+) -> Outcome<T, &'static str> {
+    match request.query_value::<T>(name) {
+        Some(Ok(v)) => Outcome::Success(v),
+        _ => guard_failure(
+            request,
+            Status::BadRequest,
+            "Missing or invalid signed URL parameters",
+        ),
+    }
+}
@@ -0,0 +1,110 @@
+use super::*;
+use crate::auth::policy::{Change, User};
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+use rocket::serde::json;
+
+fn claims(method: SignedMethod, logical_path: &str) -> SignedUrlClaims<'_> {
+    SignedUrlClaims {
+        method,
+        logical_path,
+        expires: 1_700_000_000,
+        login_name: "dan",
+    }
+}
+
+#[test]
+fn test_signature_round_trips() {
+    let signer = UrlSigner::new(b"secret");
+    let signed = claims(SignedMethod::Get, "home/dan/a.txt");
+    let signature = signer.sign(&signed);
+    assert_eq!(64, signature.len());
+    assert!(signer.verify(&signed, &signature));
+    assert!(UrlSigner::new(b"secret").verify(&signed, &signature));
+}
+
+#[test]
+fn test_tampered_claims_are_rejected() {
+    let signer = UrlSigner::new(b"secret");
+    let signed = claims(SignedMethod::Get, "home/dan/a.txt");
+    let signature = signer.sign(&signed);
+
+    for tampered in [
+        claims(SignedMethod::Put, "home/dan/a.txt"),
+        claims(SignedMethod::Get, "home/dan/b.txt"),
+        SignedUrlClaims {
+            expires: signed.expires + 1,
+            ..claims(SignedMethod::Get, "home/dan/a.txt")
+        },
+        SignedUrlClaims {
+            login_name: "eve",
+            ..claims(SignedMethod::Get, "home/dan/a.txt")
+        },
+    ] {
+        assert!(!signer.verify(&tampered, &signature));
+    }
+    // Fields can't be shifted into one another.
+    let shifted = SignedUrlClaims {
+        logical_path: "home/dan/a.txt1700000000",
+        expires: 0,
+        ..signed
+    };
+    assert!(!signer.verify(&shifted, &signature));
+}
+
+#[test]
+fn test_tampered_signatures_are_rejected() {
+    let signer = UrlSigner::new(b"secret");
+    let signed = claims(SignedMethod::Get, "a.txt");
+    let signature = signer.sign(&signed);
+
+    let mut flipped = signature.clone().into_bytes();
+    flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+    let flipped = String::from_utf8(flipped).unwrap();
+    for bad in [
+        flipped.as_str(),
+        &signature[..62],
+        &signature[..63],
+        "zz",
+        "",
+    ] {
+        assert!(!signer.verify(&signed, bad), "{bad}");
+    }
+    assert!(!UrlSigner::new(b"other").verify(&signed, &signature));
+}
+
+#[test]
+fn test_url_encodes_the_path_and_user() {
+    let signed = SignedUrlClaims {
+        login_name: "dan",
+        ..claims(SignedMethod::Get, "my files/a&b.txt")
+    };
+    assert_eq!(
+        "/api/signed/my%20files/a%26b.txt?user=dan&expires=1700000000&signature=ab",
+        signed.to_url("ab")
+    );
+}
+
+#[rocket::async_test]
+async fn test_restricted_authorizor_only_allows_the_signed_action() {
+    let store = Blocking::new(SqlitePolicyStore::open_in_memory().unwrap());
+    let dan = User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: vec![],
+        policy_statements: json::from_str(
+            r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#,
+        )
+        .unwrap(),
+        policies: vec![],
+    };
+    store.create_user(&dan, &Change::default()).await.unwrap();
+
+    let authorizor = RequestAuthorizor::for_user(&store, dan, RequestContext::unknown())
+        .await
+        .restricted_to("file:Read", "a.txt");
+    assert!(authorizor.is_allowed("file:Read", &"a.txt"));
+    assert!(!authorizor.is_allowed("file:Write", &"a.txt"));
+    assert!(!authorizor.is_allowed("file:Read", &"b.txt"));
+    assert!(!authorizor.is_allowed("UpdateUser", &"user:dan"));
+}
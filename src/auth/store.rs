@@ -1 +1,564 @@
+use crate::auth::authorizor::SourcedStatement;
+use crate::auth::name::{InvalidName, PrincipalName};
+use crate::auth::policy::{
+    Change, DocumentKind, Group, ManagedPolicy, PolicyStore, Revision, User,
+};
+use crate::config::{Config, PolicyStoreBackend};
+use cache::CachingPolicyStore;
+use files::FilePolicyStore;
+use log::{info, warn};
+use rocket::http::Status;
+use rocket::tokio::task;
+use sqlite::SqlitePolicyStore;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+pub mod cache;
 pub mod files;
+pub mod sqlite;
+
+/// The policy store the app is built around. Routes and guards use it through
+/// the `PolicyStore` trait so any backend can be managed in its place.
+pub type BoxedPolicyStore = Box<dyn PolicyStore>;
+
+/// Why a policy store operation failed. Documents are named in the messages
+/// as e.g. `user 'dan'`.
+#[derive(Debug, Error)]
+pub enum PolicyStoreError {
+    #[error("{0} does not exist")]
+    NotFound(String),
+
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+
+    #[error("{0}")]
+    InvalidName(#[from] InvalidName),
+
+    /// The write doesn't fit the store as it is now, e.g. it was based on a
+    /// stale revision or would make a group its own ancestor.
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("timed out waiting for the lock on {0}")]
+    Locked(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Corrupt(String),
+}
+
+impl PolicyStoreError {
+    pub fn status(&self) -> Status {
+        match self {
+            PolicyStoreError::NotFound(_) => Status::NotFound,
+            PolicyStoreError::AlreadyExists(_) | PolicyStoreError::Conflict(_) => Status::Conflict,
+            PolicyStoreError::InvalidName(_) => Status::UnprocessableEntity,
+            PolicyStoreError::Locked(_) => Status::Locked,
+            PolicyStoreError::Io(_) | PolicyStoreError::Corrupt(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Names the kind of error for API clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PolicyStoreError::NotFound(_) => "NotFound",
+            PolicyStoreError::AlreadyExists(_) => "AlreadyExists",
+            PolicyStoreError::InvalidName(_) => "InvalidName",
+            PolicyStoreError::Conflict(_) => "Conflict",
+            PolicyStoreError::Locked(_) => "Locked",
+            PolicyStoreError::Io(_) => "Io",
+            PolicyStoreError::Corrupt(_) => "Corrupt",
+        }
+    }
+}
+
+/// Logs an error which the caller can't do anything about along with what
+/// was being done, passing on the rest.
+fn logged(doing: String, e: PolicyStoreError) -> PolicyStoreError {
+    if let PolicyStoreError::Io(_) | PolicyStoreError::Corrupt(_) = e {
+        warn!("{doing}: {e}");
+    }
+    e
+}
+
+/// How long a store waits for a lock on a document or database before giving
+/// up on the request.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes attempts to take a lock until one succeeds or `LOCK_TIMEOUT` has
+/// passed. `attempt` gives `None` when the lock is held by someone else.
+/// Gives `None` if the lock couldn't be taken in time.
+fn wait_for_lock<T, E, F>(mut attempt: F) -> Result<Option<T>, E>
+where
+    F: FnMut() -> Result<Option<T>, E>,
+{
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    loop {
+        if let Some(locked) = attempt()? {
+            return Ok(Some(locked));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Opens the policy store selected by the configuration.
+pub fn open(config: &Config) -> Result<BoxedPolicyStore, String> {
+    match config.policy_store {
+        PolicyStoreBackend::Files => Ok(Box::new(CachingPolicyStore::watching(
+            Blocking::new(checked(FilePolicyStore::new(&config.policy_store_root)?)?),
+            &config.policy_store_root,
+        )?)),
+        PolicyStoreBackend::Sqlite => {
+            let database = config.policy_store_database.as_ref().ok_or_else(|| {
+                String::from("policy_store_database is required for the sqlite policy store")
+            })?;
+            let store = SqlitePolicyStore::open(database)?;
+            if store.is_empty()? && config.policy_store_root.is_dir() {
+                info!(
+                    "Importing the file policy store at {:?} into {database:?}",
+                    config.policy_store_root
+                );
+                store.import(&checked(FilePolicyStore::new(&config.policy_store_root)?)?)?;
+            }
+            // Not cached, as nothing would tell the cache about changes made
+            // to the database by another process, e.g. `swaf import`.
+            Ok(Box::new(Blocking::new(store)))
+        }
+    }
+}
+
+/// Reports the documents in a file policy store which can't be read, as
+/// they would otherwise only be missing from lists.
+fn checked(store: FilePolicyStore) -> Result<FilePolicyStore, String> {
+    let problems = store
+        .check_integrity()
+        .map_err(|e| format!("Error checking the file policy store: {e}"))?;
+    for problem in &problems {
+        warn!("Policy store integrity: {problem}");
+    }
+    if !problems.is_empty() {
+        warn!(
+            "{} document(s) in the policy store can't be read and are ignored",
+            problems.len()
+        );
+    }
+    Ok(store)
+}
+
+/// A policy store backend which does blocking I/O. Wrap one in `Blocking` to
+/// use it as a `PolicyStore`.
+pub trait BlockingPolicyStore {
+    fn list_users(&self) -> Result<Vec<User>, PolicyStoreError>;
+    fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError>;
+    fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError>;
+    fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError>;
+
+    fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError>;
+    fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError>;
+    fn password_hash(&self, login_name: &PrincipalName)
+        -> Result<Option<String>, PolicyStoreError>;
+    fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError>;
+
+    fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError>;
+    fn group_named(&self, name: &PrincipalName) -> Option<Group>;
+    fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError>;
+    fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError>;
+
+    /// Users who list the group directly in their `groups`.
+    fn group_members(&self, name: &PrincipalName) -> Result<Vec<User>, PolicyStoreError> {
+        Ok(self
+            .list_users()?
+            .into_iter()
+            .filter(|u| u.groups.iter().any(|g| g == name))
+            .collect())
+    }
+
+    fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError>;
+    fn policy_named(&self, name: &str) -> Option<ManagedPolicy>;
+    fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+    fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+
+    fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError>;
+
+    fn history(&self, _kind: DocumentKind, _name: &str) -> Result<Vec<Revision>, PolicyStoreError> {
+        Ok(Vec::new())
+    }
+
+    fn revision(&self, kind: DocumentKind, name: &str) -> Result<u64, PolicyStoreError> {
+        Ok(self
+            .history(kind, name)?
+            .last()
+            .map(|r| r.revision)
+            .unwrap_or(0))
+    }
+}
+
+/// Runs each call to a blocking backend on the runtime's blocking thread pool
+/// so it doesn't hold up the worker threads serving other requests.
+pub struct Blocking<S> {
+    inner: Arc<S>,
+}
+
+impl<S: BlockingPolicyStore + Send + Sync + 'static> Blocking<S> {
+    pub fn new(store: S) -> Blocking<S> {
+        Blocking {
+            inner: Arc::new(store),
+        }
+    }
+
+    async fn run<T, F>(&self, op: F) -> Result<T, PolicyStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> T + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        task::spawn_blocking(move || op(&inner))
+            .await
+            .map_err(|e| PolicyStoreError::Io(io::Error::other(e)))
+    }
+}
+
+#[rocket::async_trait]
+impl<S: BlockingPolicyStore + Send + Sync + 'static> PolicyStore for Blocking<S> {
+    async fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
+        self.run(|s| s.list_users()).await?
+    }
+
+    async fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
+        let name = name.clone();
+        self.run(move |s| s.user_named(&name)).await?
+    }
+
+    async fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        let (user, change) = (user.clone(), change.clone());
+        self.run(move |s| s.create_user(&user, &change)).await?
+    }
+
+    async fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        let (user, change) = (user.clone(), change.clone());
+        self.run(move |s| s.update_user(&user, &change)).await?
+    }
+
+    async fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        let (login_name, password) = (login_name.clone(), password.map(String::from));
+        self.run(move |s| s.set_user_password(&login_name, password.as_deref()))
+            .await?
+    }
+
+    async fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError> {
+        let (login_name, password) = (login_name.clone(), String::from(password));
+        self.run(move |s| s.authenticate_user(&login_name, &password))
+            .await?
+    }
+
+    async fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError> {
+        let login_name = login_name.clone();
+        self.run(move |s| s.password_hash(&login_name)).await?
+    }
+
+    async fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        let (login_name, hash) = (login_name.clone(), hash.map(String::from));
+        self.run(move |s| s.set_password_hash(&login_name, hash.as_deref()))
+            .await?
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
+        self.run(|s| s.list_groups()).await?
+    }
+
+    async fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+        let name = name.clone();
+        self.run(move |s| s.group_named(&name)).await.ok()?
+    }
+
+    async fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        let (group, change) = (group.clone(), change.clone());
+        self.run(move |s| s.create_group(&group, &change)).await?
+    }
+
+    async fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        let (group, change) = (group.clone(), change.clone());
+        self.run(move |s| s.update_group(&group, &change)).await?
+    }
+
+    async fn group_members(&self, name: &PrincipalName) -> Result<Vec<User>, PolicyStoreError> {
+        let name = name.clone();
+        self.run(move |s| s.group_members(&name)).await?
+    }
+
+    async fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+        self.run(|s| s.list_policies()).await?
+    }
+
+    async fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+        let name = String::from(name);
+        self.run(move |s| s.policy_named(&name)).await.ok()?
+    }
+
+    async fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let (policy, change) = (policy.clone(), change.clone());
+        self.run(move |s| s.create_policy(&policy, &change)).await?
+    }
+
+    async fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let (policy, change) = (policy.clone(), change.clone());
+        self.run(move |s| s.update_policy(&policy, &change)).await?
+    }
+
+    async fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let (name, change) = (String::from(name), change.clone());
+        self.run(move |s| s.delete(kind, &name, &change)).await?
+    }
+
+    async fn history(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+    ) -> Result<Vec<Revision>, PolicyStoreError> {
+        let name = String::from(name);
+        self.run(move |s| s.history(kind, &name)).await?
+    }
+
+    async fn revision(&self, kind: DocumentKind, name: &str) -> Result<u64, PolicyStoreError> {
+        let name = String::from(name);
+        self.run(move |s| s.revision(kind, &name)).await?
+    }
+}
+
+/// Implements `PolicyStore` for a smart pointer to one by forwarding every
+/// method, so that a store can be boxed for Rocket or shared with a task.
+macro_rules! forward_policy_store {
+    ($pointer:ident) => {
+        #[rocket::async_trait]
+        impl<T: PolicyStore + ?Sized> PolicyStore for $pointer<T> {
+            async fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
+                (**self).list_users().await
+            }
+
+            async fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
+                (**self).user_named(name).await
+            }
+
+            async fn create_user(
+                &self,
+                user: &User,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).create_user(user, change).await
+            }
+
+            async fn update_user(
+                &self,
+                user: &User,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).update_user(user, change).await
+            }
+
+            async fn set_user_password(
+                &self,
+                login_name: &PrincipalName,
+                password: Option<&str>,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).set_user_password(login_name, password).await
+            }
+
+            async fn authenticate_user(
+                &self,
+                login_name: &PrincipalName,
+                password: &str,
+            ) -> Result<Option<User>, PolicyStoreError> {
+                (**self).authenticate_user(login_name, password).await
+            }
+
+            async fn password_hash(
+                &self,
+                login_name: &PrincipalName,
+            ) -> Result<Option<String>, PolicyStoreError> {
+                (**self).password_hash(login_name).await
+            }
+
+            async fn set_password_hash(
+                &self,
+                login_name: &PrincipalName,
+                hash: Option<&str>,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).set_password_hash(login_name, hash).await
+            }
+
+            async fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
+                (**self).list_groups().await
+            }
+
+            async fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+                (**self).group_named(name).await
+            }
+
+            async fn create_group(
+                &self,
+                group: &Group,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).create_group(group, change).await
+            }
+
+            async fn update_group(
+                &self,
+                group: &Group,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).update_group(group, change).await
+            }
+
+            async fn group_members(
+                &self,
+                name: &PrincipalName,
+            ) -> Result<Vec<User>, PolicyStoreError> {
+                (**self).group_members(name).await
+            }
+
+            async fn add_group_member(
+                &self,
+                name: &PrincipalName,
+                login_name: &PrincipalName,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).add_group_member(name, login_name, change).await
+            }
+
+            async fn remove_group_member(
+                &self,
+                name: &PrincipalName,
+                login_name: &PrincipalName,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).remove_group_member(name, login_name, change).await
+            }
+
+            async fn expand_groups(&self, names: &[PrincipalName]) -> Vec<Group> {
+                (**self).expand_groups(names).await
+            }
+
+            async fn creates_cycle(&self, group: &Group) -> bool {
+                (**self).creates_cycle(group).await
+            }
+
+            async fn policy_statements_for(&self, user: &User) -> Vec<SourcedStatement> {
+                (**self).policy_statements_for(user).await
+            }
+
+            async fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+                (**self).list_policies().await
+            }
+
+            async fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+                (**self).policy_named(name).await
+            }
+
+            async fn create_policy(
+                &self,
+                policy: &ManagedPolicy,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).create_policy(policy, change).await
+            }
+
+            async fn update_policy(
+                &self,
+                policy: &ManagedPolicy,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).update_policy(policy, change).await
+            }
+
+            async fn delete(
+                &self,
+                kind: DocumentKind,
+                name: &str,
+                change: &Change,
+            ) -> Result<(), PolicyStoreError> {
+                (**self).delete(kind, name, change).await
+            }
+
+            async fn history(
+                &self,
+                kind: DocumentKind,
+                name: &str,
+            ) -> Result<Vec<Revision>, PolicyStoreError> {
+                (**self).history(kind, name).await
+            }
+
+            async fn revision(
+                &self,
+                kind: DocumentKind,
+                name: &str,
+            ) -> Result<u64, PolicyStoreError> {
+                (**self).revision(kind, name).await
+            }
+        }
+    };
+}
+
+forward_policy_store!(Box);
+forward_policy_store!(Arc);
@@ -0,0 +1,298 @@
+use crate::auth::authorizor::{self, SourcedStatement};
+use crate::auth::name::PrincipalName;
+use crate::auth::policy::{
+    Change, DocumentKind, Group, ManagedPolicy, PolicyStore, Revision, User,
+};
+use crate::auth::store::PolicyStoreError;
+use inotify::{Inotify, WatchMask};
+use log::warn;
+use rocket::serde::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use time::{Date, OffsetDateTime};
+
+#[cfg(test)]
+#[path = "cache_tests.rs"]
+mod cache_tests;
+
+/// Keeps parsed users and groups, and the statements resolved for each user,
+/// in memory in front of another store. Everything is dropped on any write
+/// made through the cache, and on any change to the watched policy directory.
+pub struct CachingPolicyStore<S> {
+    inner: S,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Bumped on every invalidation so a read which raced with a write
+    /// doesn't put what it read into the cache afterwards.
+    generation: u64,
+    users: HashMap<PrincipalName, User>,
+    groups: HashMap<PrincipalName, Group>,
+    statements: HashMap<PrincipalName, ResolvedStatements>,
+}
+
+/// The statements for a user are only good for the user document they were
+/// resolved from, and for the day `${date}` was expanded to.
+struct ResolvedStatements {
+    user: String,
+    date: Date,
+    statements: Vec<SourcedStatement>,
+}
+
+impl Cache {
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        self.users.clear();
+        self.groups.clear();
+        self.statements.clear();
+    }
+}
+
+fn lock(cache: &Mutex<Cache>) -> MutexGuard<'_, Cache> {
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<S: PolicyStore> CachingPolicyStore<S> {
+    pub fn new(inner: S) -> CachingPolicyStore<S> {
+        CachingPolicyStore {
+            inner,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Caches `inner`, which keeps its documents under `root`, dropping the
+    /// cache whenever a user, group or policy file there changes so that
+    /// hand-edited JSON still takes effect.
+    pub fn watching(inner: S, root: &Path) -> Result<CachingPolicyStore<S>, String> {
+        let store = CachingPolicyStore::new(inner);
+        watch(root, Arc::downgrade(&store.cache))?;
+        Ok(store)
+    }
+
+    pub fn invalidate(&self) {
+        lock(&self.cache).invalidate();
+    }
+
+    /// Stores a value read from the inner store, unless the cache has been
+    /// invalidated since the read began at `generation`.
+    fn insert(&self, generation: u64, insert: impl FnOnce(&mut Cache)) {
+        let mut cache = lock(&self.cache);
+        if cache.generation == generation {
+            insert(&mut cache);
+        }
+    }
+
+    fn invalidated<T>(&self, result: T) -> T {
+        self.invalidate();
+        result
+    }
+}
+
+/// Watches the user, group and policy directories under `root` from a thread
+/// which invalidates `cache` on every change, until the cache is dropped.
+fn watch(root: &Path, cache: Weak<Mutex<Cache>>) -> Result<(), String> {
+    let mut inotify = Inotify::init().map_err(|e| format!("Can't watch {root:?}: {e}"))?;
+    for dir in ["users", "groups", "policies"] {
+        let dir = root.join(dir);
+        inotify
+            .watches()
+            .add(
+                &dir,
+                WatchMask::CLOSE_WRITE
+                    | WatchMask::CREATE
+                    | WatchMask::DELETE
+                    | WatchMask::MOVED_FROM
+                    | WatchMask::MOVED_TO,
+            )
+            .map_err(|e| format!("Can't watch {dir:?}: {e}"))?;
+    }
+    thread::Builder::new()
+        .name(String::from("policy-watch"))
+        .spawn(move || {
+            let mut buffer = [0; 4096];
+            loop {
+                if let Err(e) = inotify.read_events_blocking(&mut buffer) {
+                    warn!("Stopped watching the policy store for changes: {e}");
+                    return;
+                }
+                match cache.upgrade() {
+                    Some(cache) => lock(&cache).invalidate(),
+                    None => return,
+                }
+            }
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Can't watch {root:?}: {e}"))
+}
+
+#[rocket::async_trait]
+impl<S: PolicyStore> PolicyStore for CachingPolicyStore<S> {
+    async fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
+        self.inner.list_users().await
+    }
+
+    async fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
+        let generation = {
+            let cache = lock(&self.cache);
+            if let Some(user) = cache.users.get(name) {
+                return Ok(user.clone());
+            }
+            cache.generation
+        };
+        let user = self.inner.user_named(name).await?;
+        self.insert(generation, |c| {
+            c.users.insert(name.clone(), user.clone());
+        });
+        Ok(user)
+    }
+
+    async fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.create_user(user, change).await)
+    }
+
+    async fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.update_user(user, change).await)
+    }
+
+    async fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        self.inner.set_user_password(login_name, password).await
+    }
+
+    async fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError> {
+        self.inner.authenticate_user(login_name, password).await
+    }
+
+    async fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError> {
+        self.inner.password_hash(login_name).await
+    }
+
+    async fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        self.inner.set_password_hash(login_name, hash).await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
+        self.inner.list_groups().await
+    }
+
+    async fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+        let generation = {
+            let cache = lock(&self.cache);
+            if let Some(group) = cache.groups.get(name) {
+                return Some(group.clone());
+            }
+            cache.generation
+        };
+        let group = self.inner.group_named(name).await?;
+        self.insert(generation, |c| {
+            c.groups.insert(name.clone(), group.clone());
+        });
+        Some(group)
+    }
+
+    async fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.create_group(group, change).await)
+    }
+
+    async fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.update_group(group, change).await)
+    }
+
+    async fn group_members(&self, name: &PrincipalName) -> Result<Vec<User>, PolicyStoreError> {
+        self.inner.group_members(name).await
+    }
+
+    async fn policy_statements_for(&self, user: &User) -> Vec<SourcedStatement> {
+        let document = match json::to_string(user) {
+            Ok(document) => document,
+            Err(_) => return authorizor::resolve_policy_statements(self, user).await,
+        };
+        let date = OffsetDateTime::now_utc().date();
+        let generation = {
+            let cache = lock(&self.cache);
+            if let Some(resolved) = cache.statements.get(&user.login_name) {
+                if resolved.user == document && resolved.date == date {
+                    return resolved.statements.clone();
+                }
+            }
+            cache.generation
+        };
+        let statements = authorizor::resolve_policy_statements(self, user).await;
+        self.insert(generation, |c| {
+            c.statements.insert(
+                user.login_name.clone(),
+                ResolvedStatements {
+                    user: document,
+                    date,
+                    statements: statements.clone(),
+                },
+            );
+        });
+        statements
+    }
+
+    async fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+        self.inner.list_policies().await
+    }
+
+    async fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+        self.inner.policy_named(name).await
+    }
+
+    async fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.create_policy(policy, change).await)
+    }
+
+    async fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.update_policy(policy, change).await)
+    }
+
+    async fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        self.invalidated(self.inner.delete(kind, name, change).await)
+    }
+
+    async fn history(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+    ) -> Result<Vec<Revision>, PolicyStoreError> {
+        self.inner.history(kind, name).await
+    }
+
+    async fn revision(&self, kind: DocumentKind, name: &str) -> Result<u64, PolicyStoreError> {
+        self.inner.revision(kind, name).await
+    }
+}
@@ -0,0 +1,136 @@
+use super::*;
+use crate::auth::store::files::FilePolicyStore;
+use crate::auth::store::sqlite::SqlitePolicyStore;
+use crate::auth::store::Blocking;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "swaf-cache-{}-{}",
+        std::process::id(),
+        STORE_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn group(description: &str, statements: &str) -> Group {
+    Group {
+        name: "staff".parse().unwrap(),
+        description: Some(String::from(description)),
+        policy_statements: json::from_str(statements).unwrap(),
+        policies: vec![],
+        parents: vec![],
+    }
+}
+
+fn dan() -> User {
+    User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: vec!["staff".parse().unwrap()],
+        policy_statements: vec![],
+        policies: vec![],
+    }
+}
+
+const ALLOW_ALL: &str = r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#;
+
+#[rocket::async_test]
+async fn test_writes_invalidate_the_cache() {
+    let store =
+        CachingPolicyStore::new(Blocking::new(SqlitePolicyStore::open_in_memory().unwrap()));
+    let change = Change::default();
+    store
+        .create_group(&group("one", "[]"), &change)
+        .await
+        .unwrap();
+    store.create_user(&dan(), &change).await.unwrap();
+
+    let user = store.user_named(&"dan".parse().unwrap()).await.unwrap();
+    assert!(store.policy_statements_for(&user).await.is_empty());
+    assert_eq!(
+        Some(String::from("one")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .await
+            .unwrap()
+            .description
+    );
+
+    store
+        .update_group(&group("two", ALLOW_ALL), &change)
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(String::from("two")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .await
+            .unwrap()
+            .description
+    );
+    assert_eq!(1, store.policy_statements_for(&user).await.len());
+}
+
+#[rocket::async_test]
+async fn test_statements_follow_the_user_document() {
+    let store =
+        CachingPolicyStore::new(Blocking::new(SqlitePolicyStore::open_in_memory().unwrap()));
+    let change = Change::default();
+    store
+        .create_group(&group("one", ALLOW_ALL), &change)
+        .await
+        .unwrap();
+
+    let mut user = dan();
+    assert_eq!(1, store.policy_statements_for(&user).await.len());
+    user.groups.clear();
+    assert!(store.policy_statements_for(&user).await.is_empty());
+}
+
+#[rocket::async_test]
+async fn test_hand_edits_invalidate_the_cache() {
+    let dir = temp_dir();
+    let store =
+        CachingPolicyStore::watching(Blocking::new(FilePolicyStore::new(&dir).unwrap()), &dir)
+            .unwrap();
+    store
+        .create_group(&group("one", "[]"), &Change::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(String::from("one")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .await
+            .unwrap()
+            .description
+    );
+
+    fs::write(
+        dir.join("groups").join("staff.json"),
+        json::to_string(&group("edited", "[]")).unwrap(),
+    )
+    .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let description = store
+            .group_named(&"staff".parse().unwrap())
+            .await
+            .unwrap()
+            .description;
+        if description.as_deref() == Some("edited") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "edit was never noticed");
+        rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
@@ -1,4 +1,9 @@
-use crate::auth::policy::{Group, PolicyStatement, PolicyStore, User};
+use crate::auth::name::{InvalidName, PrincipalName};
+use crate::auth::policy::{
+    creates_cycle_with, Change, DocumentKind, Group, ManagedPolicy, PolicyStatement, Revision, User,
+};
+use crate::auth::store::{logged, wait_for_lock, BlockingPolicyStore, PolicyStoreError};
+use crate::util::now_as_secs;
 use fs2::FileExt;
 use log::{info, warn};
 use pwhash::sha512_crypt;
@@ -8,23 +13,33 @@ use std::env;
 use std::fmt::Debug;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::io;
 use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
+#[cfg(test)]
+#[path = "files_tests.rs"]
+mod files_tests;
+
 pub struct FilePolicyStore {
     user_dir: PathBuf,
     group_dir: PathBuf,
+    policy_dir: PathBuf,
+    history_dir: PathBuf,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct StoredUser {
     // Shared with policy::User
-    login_name: String,
+    login_name: PrincipalName,
     full_name: Option<String>,
-    groups: Vec<String>,
+    groups: Vec<PrincipalName>,
     policy_statements: Vec<PolicyStatement>,
+    #[serde(default)]
+    policies: Vec<String>,
 
     // Private
     password_hash: Option<String>,
@@ -37,6 +52,7 @@ impl From<StoredUser> for User {
             full_name: v.full_name,
             groups: v.groups,
             policy_statements: v.policy_statements,
+            policies: v.policies,
         }
     }
 }
@@ -56,20 +72,136 @@ impl FilePolicyStore {
         let store = FilePolicyStore {
             user_dir: base_dir.join("users"),
             group_dir: base_dir.join("groups"),
+            policy_dir: base_dir.join("policies"),
+            history_dir: base_dir.join("history"),
         };
 
         check_dir("user", &store.user_dir)?;
         check_dir("group", &store.group_dir)?;
+        check_dir("policy", &store.policy_dir)?;
+        check_dir("history", &store.history_dir)?;
+        for kind in [
+            DocumentKind::User,
+            DocumentKind::Group,
+            DocumentKind::Policy,
+        ] {
+            check_dir("history", store.kind_history_dir(kind))?;
+        }
         Ok(store)
     }
 
-    fn load_user(&self, login_name: &str) -> Result<StoredUser, ()> {
-        load(&self.user_dir, login_name)
-            .map_err(|e| warn!("Error loading user '{}': {}", login_name, e))
+    fn kind_history_dir(&self, kind: DocumentKind) -> PathBuf {
+        self.history_dir.join(match kind {
+            DocumentKind::User => "users",
+            DocumentKind::Group => "groups",
+            DocumentKind::Policy => "policies",
+        })
+    }
+
+    /// Writes a document with `write` and appends the new revision to its
+    /// history. The history stays locked throughout so concurrent writers are
+    /// serialized, and the write is refused if `change.base_revision` isn't
+    /// the latest revision. `write` gives back the document it replaced, if
+    /// any, and the one it wrote, so that a document derived from the stored
+    /// one can be read under the lock. When the document was written before
+    /// history was kept, the replaced one is recorded first so the change can
+    /// be rolled back.
+    fn revise<T, W>(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+        write: W,
+    ) -> Result<(), PolicyStoreError>
+    where
+        T: Serialize,
+        W: FnOnce() -> Result<(Option<T>, T), PolicyStoreError>,
+    {
+        let dir = self.kind_history_dir(kind);
+        with_history(&dir, name, |file| {
+            let latest = check_base_revision(file, change)?;
+            let (previous, document) = write()?;
+            let recorded = append_revisions(file, latest, previous.as_ref(), &document, change);
+            // The document has been written, so this isn't a failed write.
+            if let Err(e) = recorded {
+                warn!("Error recording history of {kind} '{name}': {e}");
+            }
+            Ok(())
+        })
+        .map_err(|e| logged(format!("Error writing {kind} '{name}'"), e))
+    }
+
+    /// Runs a write to a group with every other group write locked out, so
+    /// that two writes can't each pass the cycle check in `store_group` and
+    /// together make a cycle.
+    fn with_groups_locked<T, O>(&self, op: O) -> Result<T, PolicyStoreError>
+    where
+        O: FnOnce() -> Result<T, PolicyStoreError>,
+    {
+        let path = self.history_dir.join("groups.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        lock(&file, &path, true)?;
+        let ret = op();
+        file.unlock()
+            .unwrap_or_else(|_| panic!("Failed to unlock {path:?}"));
+        ret
     }
 
-    fn load_group(&self, name: &str) -> Result<Group, ()> {
-        load(&self.group_dir, name).map_err(|e| warn!("Error loading group '{}': {}", name, e))
+    fn kind_dir(&self, kind: DocumentKind) -> &Path {
+        match kind {
+            DocumentKind::User => &self.user_dir,
+            DocumentKind::Group => &self.group_dir,
+            DocumentKind::Policy => &self.policy_dir,
+        }
+    }
+
+    /// Reads every document and history in the store, giving a description
+    /// of each one which can't be read, and removes temporary files left by
+    /// writes which never finished. Listing skips documents which can't be
+    /// read, so this is how damage to the store gets noticed.
+    pub fn check_integrity(&self) -> Result<Vec<String>, PolicyStoreError> {
+        let mut problems = Vec::new();
+        for kind in [
+            DocumentKind::User,
+            DocumentKind::Group,
+            DocumentKind::Policy,
+        ] {
+            for name in file_names(self.kind_dir(kind), ".json")? {
+                let loaded = match kind {
+                    DocumentKind::User => self.load_user(&name).map(drop),
+                    DocumentKind::Group => self.load_group(&name).map(drop),
+                    DocumentKind::Policy => self.load_policy(&name).map(drop),
+                };
+                if let Err(e) = loaded {
+                    problems.push(e.to_string());
+                }
+            }
+            let history_dir = self.kind_history_dir(kind);
+            for name in file_names(&history_dir, ".jsonl")? {
+                let read = document_path(&history_dir, &name, "jsonl")
+                    .and_then(|path| Ok(File::open(path)?))
+                    .and_then(|file| read_revisions(&file));
+                if let Err(e) = read {
+                    problems.push(format!("History of {kind} '{name}': {e}"));
+                }
+            }
+            for dir in [self.kind_dir(kind), &history_dir] {
+                remove_unfinished_writes(dir)?;
+            }
+        }
+        Ok(problems)
+    }
+
+    fn load_user(&self, login_name: &str) -> Result<StoredUser, PolicyStoreError> {
+        load(&self.user_dir, DocumentKind::User, login_name)
+    }
+
+    fn load_group(&self, name: &str) -> Result<Group, PolicyStoreError> {
+        load(&self.group_dir, DocumentKind::Group, name)
     }
 
     fn store_user(
@@ -77,9 +209,10 @@ impl FilePolicyStore {
         create_new: bool,
         user: &User,
         password_hash: Option<String>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), PolicyStoreError> {
         store(
             &self.user_dir,
+            DocumentKind::User,
             &user.login_name,
             create_new,
             &StoredUser {
@@ -87,71 +220,224 @@ impl FilePolicyStore {
                 full_name: user.full_name.clone(),
                 groups: user.groups.clone(),
                 policy_statements: user.policy_statements.clone(),
+                policies: user.policies.clone(),
                 password_hash,
             },
         )
-        .map_err(|e| warn!("Error creating user: {:?}", e))
     }
 
-    fn store_group(&self, create_new: bool, group: &Group) -> Result<(), ()> {
-        store(&self.group_dir, &group.name, create_new, group)
-            .map_err(|e| warn!("Error creating group: {:?}", e))
+    fn store_group(&self, create_new: bool, group: &Group) -> Result<(), PolicyStoreError> {
+        if creates_cycle_with(group, |n| self.group_named(n)) {
+            return Err(PolicyStoreError::Conflict(format!(
+                "The parents of group '{}' would make it its own ancestor",
+                group.name
+            )));
+        }
+        store(
+            &self.group_dir,
+            DocumentKind::Group,
+            &group.name,
+            create_new,
+            group,
+        )
+    }
+
+    fn load_policy(&self, name: &str) -> Result<ManagedPolicy, PolicyStoreError> {
+        load(&self.policy_dir, DocumentKind::Policy, name)
+    }
+
+    fn store_policy(
+        &self,
+        create_new: bool,
+        policy: &ManagedPolicy,
+    ) -> Result<(), PolicyStoreError> {
+        store(
+            &self.policy_dir,
+            DocumentKind::Policy,
+            &policy.name,
+            create_new,
+            policy,
+        )
     }
 }
 
-impl PolicyStore for FilePolicyStore {
-    fn list_users(&self) -> Result<Vec<User>, ()> {
+impl BlockingPolicyStore for FilePolicyStore {
+    fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
         list(&self.user_dir, |n| self.load_user(n).map(User::from))
     }
 
-    fn list_groups(&self) -> Result<Vec<Group>, ()> {
+    fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
         list(&self.group_dir, |n| self.load_group(n))
     }
 
-    fn create_user(&self, user: &User) -> Result<(), ()> {
-        self.store_user(true, user, None)
+    fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::User, &user.login_name, change, || {
+            self.store_user(true, user, None)?;
+            Ok((None, user.clone()))
+        })
     }
 
-    fn update_user(&self, user: &User) -> Result<(), ()> {
-        let old_user = self.load_user(user.login_name.as_str())?;
-        self.store_user(false, user, old_user.password_hash)
+    fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        // Refuse a missing user before their history is touched.
+        self.load_user(&user.login_name)?;
+        self.revise(DocumentKind::User, &user.login_name, change, || {
+            // Read again under the lock so that a password set meanwhile is
+            // kept.
+            let old_user = self.load_user(&user.login_name)?;
+            let password_hash = old_user.password_hash.clone();
+            self.store_user(false, user, password_hash)?;
+            Ok((Some(User::from(old_user)), user.clone()))
+        })
     }
 
-    fn set_user_password(&self, login_name: &str, password: Option<&str>) -> Result<(), ()> {
-        let old_user = self.load_user(login_name)?;
-        let user = User::from(old_user);
-        let password = match password {
+    fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        let hash = match password {
             None => None,
-            Some(pw) => {
-                Some(sha512_crypt::hash(pw).map_err(|e| warn!("Error hashing password: {}", e))?)
-            }
+            Some(pw) => Some(sha512_crypt::hash(pw).map_err(io::Error::other)?),
         };
-        self.store_user(false, &user, password)
+        self.set_password_hash(login_name, hash.as_deref())
+    }
+
+    fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError> {
+        self.load_user(login_name).map(|u| u.password_hash)
     }
 
-    fn authenticate_user(&self, login_name: &str, password: &str) -> Result<User, ()> {
+    fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        // Password hashes are kept out of the history, but taking its lock
+        // keeps other writers of the user out.
+        let dir = self.kind_history_dir(DocumentKind::User);
+        with_history(&dir, login_name, |_| {
+            let user = User::from(self.load_user(login_name)?);
+            self.store_user(false, &user, hash.map(String::from))
+        })
+    }
+
+    fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError> {
         let user = self.load_user(login_name)?;
-        let hash = user.password_hash.as_ref().ok_or(())?;
-        if !sha512_crypt::verify(password, hash.as_str()) {
-            return Err(());
-        };
-        Ok(user.into())
+        match &user.password_hash {
+            Some(hash) if sha512_crypt::verify(password, hash.as_str()) => Ok(Some(user.into())),
+            _ => Ok(None),
+        }
     }
 
-    fn user_named(&self, name: &str) -> Result<User, ()> {
+    fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
         self.load_user(name).map(User::from)
     }
 
-    fn group_named(&self, name: &str) -> Option<Group> {
-        self.load_group(name).ok()
+    fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+        self.load_group(name)
+            .map_err(|e| logged(format!("Error loading group '{name}'"), e))
+            .ok()
     }
 
-    fn create_group(&self, group: &Group) -> Result<(), ()> {
-        self.store_group(true, group)
+    fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.with_groups_locked(|| {
+            self.revise(DocumentKind::Group, &group.name, change, || {
+                self.store_group(true, group)?;
+                Ok((None, group.clone()))
+            })
+        })
     }
 
-    fn update_group(&self, group: &Group) -> Result<(), ()> {
-        self.store_group(false, group)
+    fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.with_groups_locked(|| {
+            // Refuse a missing group before its history is touched.
+            self.load_group(&group.name)?;
+            self.revise(DocumentKind::Group, &group.name, change, || {
+                let old_group = self.load_group(&group.name)?;
+                self.store_group(false, group)?;
+                Ok((Some(old_group), group.clone()))
+            })
+        })
+    }
+
+    fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+        list(&self.policy_dir, |n| self.load_policy(n))
+    }
+
+    fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+        self.load_policy(name)
+            .map_err(|e| logged(format!("Error loading policy '{name}'"), e))
+            .ok()
+    }
+
+    fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::Policy, &policy.name, change, || {
+            let policy = ManagedPolicy {
+                version: 1,
+                ..policy.clone()
+            };
+            self.store_policy(true, &policy)?;
+            Ok((None, policy))
+        })
+    }
+
+    fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        // Refuse a missing policy before its history is touched.
+        self.load_policy(&policy.name)?;
+        self.revise(DocumentKind::Policy, &policy.name, change, || {
+            // Read again under the lock so that concurrent updates each get
+            // a version of their own.
+            let old_policy = self.load_policy(&policy.name)?;
+            let policy = ManagedPolicy {
+                version: old_policy.version + 1,
+                ..policy.clone()
+            };
+            self.store_policy(false, &policy)?;
+            Ok((Some(old_policy), policy))
+        })
+    }
+
+    fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let dir = self.kind_dir(kind);
+        let path = document_path(dir, name, "json")?;
+        with_history(&self.kind_history_dir(kind), name, |file| {
+            check_base_revision(file, change)?;
+            fs::remove_file(&path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => PolicyStoreError::NotFound(format!("{kind} '{name}'")),
+                _ => PolicyStoreError::Io(e),
+            })?;
+            File::open(dir)?.sync_all()?;
+            Ok(())
+        })
+        .map_err(|e| logged(format!("Error deleting {kind} '{name}'"), e))
+    }
+
+    fn history(&self, kind: DocumentKind, name: &str) -> Result<Vec<Revision>, PolicyStoreError> {
+        let dir = self.kind_history_dir(kind);
+        if !document_path(&dir, name, "jsonl")?.exists() {
+            return Ok(Vec::new());
+        }
+        with_history(&dir, name, read_revisions)
+            .map_err(|e| logged(format!("Error reading history of {kind} '{name}'"), e))
     }
 }
 
@@ -173,99 +459,265 @@ fn check_dir<P: AsRef<Path>>(desc: &str, path: P) -> Result<(), String> {
     Ok(())
 }
 
-enum OpenMode {
-    Read,
-    Update,
-    Create,
+/// The file in `dir` holding the document `name`. Names which aren't a plain
+/// file name, e.g. `../users/root`, are refused before anything is opened.
+fn document_path(dir: &Path, name: &str, extension: &str) -> Result<PathBuf, PolicyStoreError> {
+    let plain = !name.starts_with('.')
+        && !name.contains(['/', '\\', '\0'])
+        && Path::new(name).components().count() == 1;
+    if !plain {
+        return Err(InvalidName::new(name, "must be a plain file name").into());
+    }
+    Ok(dir.join(format!("{name}.{extension}")))
 }
 
-fn with_file<O, T>(dir: &PathBuf, name: &'_ str, mode: OpenMode, op: O) -> Result<T, String>
+/// Documents are only ever replaced whole, by `write_atomically`, so they can
+/// be read without taking a lock.
+fn load<T>(dir: &Path, kind: DocumentKind, name: &'_ str) -> Result<T, PolicyStoreError>
 where
-    O: FnOnce(&File) -> Result<T, String>,
+    T: DeserializeOwned,
 {
-    let file_name = format!("{name}.json");
-    let path = dir.join(file_name);
-    // let path = path
-    //     .canonicalize()
-    //     .map_err(|_| format!("Non-canonical data path: {:?}", path))?;
-    if !path.starts_with(dir) {
-        return Err(format!("Invalid object path: {path:?}"));
-    }
-    let mut options = OpenOptions::new();
-    let exclusive = match mode {
-        OpenMode::Read => {
-            options.read(true);
-            false
-        }
-        OpenMode::Update => {
-            options.write(true).create(false).truncate(true);
-            true
-        }
-        OpenMode::Create => {
-            options.write(true).create_new(true);
-            true
+    let path = document_path(dir, name, "json")?;
+    let buf = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => PolicyStoreError::NotFound(format!("{kind} '{name}'")),
+        _ => PolicyStoreError::Io(e),
+    })?;
+    json::from_str(buf.as_str())
+        .map_err(|e| PolicyStoreError::Corrupt(format!("Error decoding {kind} '{name}': {e}")))
+}
+
+/// Writes a document. Writers of a document are serialized by its history
+/// lock, so whether it exists can't change between the check and the write.
+fn store<T>(
+    dir: &Path,
+    kind: DocumentKind,
+    name: &'_ str,
+    create_new: bool,
+    o: &T,
+) -> Result<(), PolicyStoreError>
+where
+    T: Serialize,
+{
+    let path = document_path(dir, name, "json")?;
+    match (create_new, path.exists()) {
+        (true, true) => return Err(PolicyStoreError::AlreadyExists(format!("{kind} '{name}'"))),
+        (false, false) => return Err(PolicyStoreError::NotFound(format!("{kind} '{name}'"))),
+        _ => {}
+    }
+    let s = json::to_pretty_string(o).map_err(io::Error::from)?;
+    write_atomically(&path, s.as_bytes())?;
+    Ok(())
+}
+
+/// Replaces the file at `path` so that a crash or a full disk leaves either
+/// the old contents or the new ones, never a mix. The contents go to a
+/// temporary file in the same directory, which is flushed to disk before
+/// being renamed over `path`, and the directory is flushed after the rename
+/// so that the rename is on disk too. The new file keeps the permissions of
+/// the one it replaces, and is only readable by its owner if there wasn't
+/// one, as user documents hold password hashes.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| io::Error::other(format!("{path:?} has no directory")))?;
+    let temp = temp_path(path);
+    let written = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)?;
+        match fs::metadata(path) {
+            Ok(existing) => file.set_permissions(existing.permissions())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
         }
-    };
-    let file = options
-        .open(&path)
-        .map_err(|e| format!("Error opening {path:?}: {e:?}"))?;
-    if exclusive {
-        file.try_lock_exclusive()
-            .map_err(|e| format!("Error locking {path:?} exclusively: {e:?}"))?;
-    } else {
-        file.lock_shared()
-            .map_err(|e| format!("Error locking {path:?}: {e:?}"))?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
     }
+    written?;
+    File::open(dir)?.sync_all()
+}
+
+/// Where `write_atomically` writes the new contents of `path`. Temporary
+/// files are hidden, so `list` never mistakes one for a document.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.{}{TEMP_SUFFIX}", std::process::id()))
+}
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Locks a file, waiting for other holders of the lock to finish for no longer
+/// than `LOCK_TIMEOUT`.
+fn lock(file: &File, path: &Path, exclusive: bool) -> Result<(), PolicyStoreError> {
+    let locked = wait_for_lock(|| {
+        let attempt = if exclusive {
+            FileExt::try_lock_exclusive(file)
+        } else {
+            FileExt::try_lock_shared(file)
+        };
+        match attempt {
+            Ok(()) => Ok(Some(())),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(None),
+            Err(e) => Err(e),
+        }
+    })?;
+    locked.ok_or_else(|| PolicyStoreError::Locked(format!("{path:?}")))
+}
+
+/// Opens a document's history, one revision per line, for reading and
+/// appending.
+fn with_history<O, T>(dir: &Path, name: &str, op: O) -> Result<T, PolicyStoreError>
+where
+    O: FnOnce(&File) -> Result<T, PolicyStoreError>,
+{
+    let path = document_path(dir, name, "jsonl")?;
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&path)?;
+    lock(&file, &path, true)?;
     let ret = op(&file);
     file.unlock()
         .unwrap_or_else(|_| panic!("Failed to unlock {path:?}"));
     ret
 }
 
-fn load<T>(dir: &PathBuf, name: &'_ str) -> Result<T, String>
-where
-    T: DeserializeOwned,
-{
-    let mut buf = String::new();
-    with_file(dir, name, OpenMode::Read, |mut f| {
-        f.read_to_string(&mut buf)
-            .map_err(|e| format!("Error reading {name} in {dir:?}: {e:?}"))?;
-        json::from_str(buf.as_str()).map_err(|e| format!("Error decoding {name} in {dir:?}: {e:?}"))
-    })
+/// The latest revision in a history, refusing a change which was based on
+/// any other.
+fn check_base_revision(file: &File, change: &Change) -> Result<u64, PolicyStoreError> {
+    let latest = read_revisions(file)?
+        .last()
+        .map(|r| r.revision)
+        .unwrap_or(0);
+    if let Some(base) = change.base_revision {
+        if base != latest {
+            return Err(PolicyStoreError::Conflict(format!(
+                "Stale revision {base}, the latest revision is {latest}"
+            )));
+        }
+    }
+    Ok(latest)
 }
 
-fn store<T>(dir: &PathBuf, name: &'_ str, create_new: bool, o: &T) -> Result<(), String>
-where
-    T: Serialize,
-{
-    let mode = if create_new {
-        OpenMode::Create
-    } else {
-        OpenMode::Update
-    };
-    with_file(dir, name, mode, |mut f| {
-        let s = json::to_pretty_string(o)
-            .map_err(|e| format!("Error serializing {name} in {dir:?}: {e:?}"))?;
-        f.write(s.as_bytes())
-            .map_err(|e| format!("Error writing {name} in {dir:?}: {e:?}"))
-            .map(|_| ())
+fn read_revisions(file: &File) -> Result<Vec<Revision>, PolicyStoreError> {
+    BufReader::new(file)
+        .lines()
+        .map(|line| {
+            json::from_str(&line?)
+                .map_err(|e| PolicyStoreError::Corrupt(format!("Error decoding history: {e}")))
+        })
+        .collect()
+}
+
+fn revision<T: Serialize>(
+    number: u64,
+    document: &T,
+    change: &Change,
+) -> Result<Revision, PolicyStoreError> {
+    Ok(Revision {
+        revision: number,
+        author: change.author.clone(),
+        timestamp: now_as_secs().map_err(|_| io::Error::other("Clock error"))?,
+        reason: change.reason.clone(),
+        document: json::to_value(document).map_err(io::Error::from)?,
     })
 }
 
-fn list<P, O, F>(path: P, f: F) -> Result<Vec<O>, ()>
+/// Records `document` as the revision after `latest`, preceded by `previous`
+/// when the document had no history.
+fn append_revisions<T: Serialize>(
+    file: &File,
+    latest: u64,
+    previous: Option<&T>,
+    document: &T,
+    change: &Change,
+) -> Result<(), PolicyStoreError> {
+    let mut next = latest + 1;
+    if let (1, Some(previous)) = (next, previous) {
+        let untracked = Change {
+            reason: Some(String::from("Written before history was kept")),
+            ..Change::default()
+        };
+        write_revision(file, &revision(1, previous, &untracked)?)?;
+        next = 2;
+    }
+    write_revision(file, &revision(next, document, change)?)
+}
+
+fn write_revision(mut file: &File, revision: &Revision) -> Result<(), PolicyStoreError> {
+    let mut line = json::to_string(revision).map_err(io::Error::from)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn list<P, O, F>(path: P, f: F) -> Result<Vec<O>, PolicyStoreError>
 where
     P: AsRef<Path> + Debug,
-    F: Fn(&str) -> Result<O, ()>,
+    F: Fn(&str) -> Result<O, PolicyStoreError>,
 {
-    Ok(fs::read_dir(&path)
-        .map_err(|e| warn!("Error reading object directory {:?}: {}", &path, e))?
+    Ok(file_names(path.as_ref(), ".json")
+        .map_err(|e| logged(format!("Error reading object directory {path:?}"), e))?
+        .into_iter()
+        .filter_map(|n| {
+            f(&n)
+                .map_err(|e| logged(format!("Error listing {path:?}"), e))
+                .ok()
+        })
+        .collect())
+}
+
+/// The names of the documents in `dir`, i.e. its files named with `suffix`
+/// without the suffix.
+fn file_names(dir: &Path, suffix: &str) -> Result<Vec<String>, PolicyStoreError> {
+    Ok(fs::read_dir(dir)?
         .filter_map(|r| r.ok())
         .filter(|e| e.file_type().map_or(false, |t| t.is_file()))
         .map(|e| e.file_name().into_string())
         .filter_map(|r| r.ok())
-        .filter(|n| n.ends_with(".json"))
-        .map(|n| String::from(n.trim_end_matches(".json")))
-        .map(|n| f(&n))
-        .filter_map(|r| r.ok())
+        .filter(|n| !n.starts_with('.') && n.ends_with(suffix))
+        .map(|n| String::from(n.trim_end_matches(suffix)))
         .collect())
 }
+
+/// Removes the temporary files of writes interrupted by a crash. The
+/// documents they were replacing are untouched.
+/// Removes temporary files whose writer has gone. The store may be opened
+/// while another process, e.g. the server, is writing to it, and the temporary
+/// file of a write which is still going on has to be left for it to rename.
+fn remove_unfinished_writes(dir: &Path) -> Result<(), PolicyStoreError> {
+    for entry in fs::read_dir(dir)?.filter_map(|r| r.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let writer = match name
+            .strip_prefix('.')
+            .and_then(|n| n.strip_suffix(TEMP_SUFFIX))
+        {
+            Some(rest) => rest
+                .rsplit('.')
+                .next()
+                .and_then(|pid| pid.parse::<u32>().ok()),
+            None => continue,
+        };
+        if writer.is_some_and(is_running) {
+            continue;
+        }
+        warn!("Removing {:?}, left by an unfinished write", entry.path());
+        fs::remove_file(entry.path())?;
+    }
+    Ok(())
+}
+
+fn is_running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
@@ -0,0 +1,360 @@
+use super::*;
+use crate::auth::authorizor::PolicySource;
+use crate::auth::policy::PolicyStore;
+use crate::auth::store::Blocking;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_store() -> (PathBuf, FilePolicyStore) {
+    let dir = env::temp_dir().join(format!(
+        "swaf-store-{}-{}",
+        std::process::id(),
+        STORE_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let store = FilePolicyStore::new(&dir).unwrap();
+    (dir, store)
+}
+
+fn group(description: &str) -> Group {
+    Group {
+        name: "staff".parse().unwrap(),
+        description: Some(String::from(description)),
+        policy_statements: vec![],
+        policies: vec![],
+        parents: vec![],
+    }
+}
+
+fn change(author: &str, reason: Option<&str>) -> Change {
+    Change {
+        author: Some(String::from(author)),
+        reason: reason.map(String::from),
+        base_revision: None,
+    }
+}
+
+#[test]
+fn test_writes_record_revisions() {
+    let (_dir, store) = temp_store();
+    store
+        .create_group(&group("one"), &change("dan", None))
+        .unwrap();
+    store
+        .update_group(&group("two"), &change("bob", Some("Renamed")))
+        .unwrap();
+
+    let history = store.history(DocumentKind::Group, "staff").unwrap();
+    assert_eq!(
+        vec![1, 2],
+        history.iter().map(|r| r.revision).collect::<Vec<u64>>()
+    );
+    assert_eq!(Some(String::from("bob")), history[1].author);
+    assert_eq!(Some(String::from("Renamed")), history[1].reason);
+    assert_eq!("one", history[0].document["description"]);
+    assert_eq!("two", history[1].document["description"]);
+}
+
+fn policy(description: &str) -> ManagedPolicy {
+    ManagedPolicy {
+        name: String::from("FileRead"),
+        description: Some(String::from(description)),
+        version: 7,
+        policy_statements: json::from_str(
+            r#"[{"effect": "Allow", "actions": ["file:Read"], "resources": ["*"]}]"#,
+        )
+        .unwrap(),
+    }
+}
+
+#[test]
+fn test_policy_versions_are_assigned_by_the_store() {
+    let (_dir, store) = temp_store();
+    store
+        .create_policy(&policy("one"), &change("dan", None))
+        .unwrap();
+    assert_eq!(1, store.policy_named("FileRead").unwrap().version);
+
+    store
+        .update_policy(&policy("two"), &change("dan", None))
+        .unwrap();
+    store
+        .update_policy(&policy("three"), &change("dan", None))
+        .unwrap();
+    let updated = store.policy_named("FileRead").unwrap();
+    assert_eq!(3, updated.version);
+    assert_eq!(Some(String::from("three")), updated.description);
+
+    let history = store.history(DocumentKind::Policy, "FileRead").unwrap();
+    assert_eq!(
+        vec![1, 2, 3],
+        history
+            .iter()
+            .map(|r| r.document["version"].as_u64().unwrap())
+            .collect::<Vec<u64>>()
+    );
+
+    let missing = ManagedPolicy {
+        name: String::from("Missing"),
+        ..policy("one")
+    };
+    assert!(matches!(
+        store.update_policy(&missing, &change("dan", None)),
+        Err(PolicyStoreError::NotFound(_))
+    ));
+}
+
+#[rocket::async_test]
+async fn test_attached_policies_resolve_for_users_and_groups() {
+    let (_dir, store) = temp_store();
+    let store = Blocking::new(store);
+    let change = change("dan", None);
+    store.create_policy(&policy("one"), &change).await.unwrap();
+    let staff = Group {
+        policies: vec![String::from("FileRead")],
+        ..group("staff")
+    };
+    store.create_group(&staff, &change).await.unwrap();
+    let dan = User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: vec!["staff".parse().unwrap()],
+        policy_statements: vec![],
+        // A policy which doesn't exist is skipped.
+        policies: vec![String::from("FileRead"), String::from("Missing")],
+    };
+
+    let sources = store
+        .policy_statements_for(&dan)
+        .await
+        .into_iter()
+        .map(|s| s.source)
+        .collect::<Vec<PolicySource>>();
+    let attached_to = |source| PolicySource::Policy {
+        name: String::from("FileRead"),
+        attached_to: Box::new(source),
+    };
+    assert_eq!(
+        vec![
+            attached_to(PolicySource::Group {
+                name: String::from("staff")
+            }),
+            attached_to(PolicySource::User {
+                name: String::from("dan")
+            }),
+        ],
+        sources
+    );
+}
+
+#[test]
+fn test_concurrent_parent_updates_cant_make_a_cycle() {
+    let (_dir, store) = temp_store();
+    let parented = |name: &str, parent: &str| Group {
+        name: name.parse().unwrap(),
+        parents: vec![parent.parse().unwrap()],
+        ..group("")
+    };
+    for name in ["a", "b"] {
+        let group = Group {
+            name: name.parse().unwrap(),
+            ..group("")
+        };
+        store.create_group(&group, &change("dan", None)).unwrap();
+    }
+
+    let results = std::thread::scope(|scope| {
+        let updates = [("a", "b"), ("b", "a")].map(|(name, parent)| {
+            let (store, group) = (&store, parented(name, parent));
+            scope.spawn(move || store.update_group(&group, &change("dan", None)))
+        });
+        updates.map(|update| update.join().unwrap())
+    });
+    assert_eq!(1, results.iter().filter(|r| r.is_ok()).count());
+    assert!(results
+        .iter()
+        .any(|r| matches!(r, Err(PolicyStoreError::Conflict(_)))));
+}
+
+#[test]
+fn test_updating_a_user_keeps_their_password() {
+    let (_dir, store) = temp_store();
+    let dan = User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: vec![],
+        policy_statements: vec![],
+        policies: vec![],
+    };
+    store.create_user(&dan, &change("dan", None)).unwrap();
+    store
+        .set_user_password(&dan.login_name, Some("pw"))
+        .unwrap();
+    let renamed = User {
+        full_name: Some(String::from("Dan")),
+        ..dan.clone()
+    };
+    store.update_user(&renamed, &change("dan", None)).unwrap();
+
+    assert!(store
+        .authenticate_user(&dan.login_name, "pw")
+        .unwrap()
+        .is_some());
+    let history = store.history(DocumentKind::User, "dan").unwrap();
+    assert!(history
+        .iter()
+        .all(|r| r.document.get("password_hash").is_none()));
+}
+
+#[test]
+fn test_untracked_document_is_recorded_before_first_update() {
+    let (dir, store) = temp_store();
+    fs::write(
+        dir.join("groups/staff.json"),
+        json::to_string(&group("legacy")).unwrap(),
+    )
+    .unwrap();
+    store
+        .update_group(&group("new"), &change("dan", None))
+        .unwrap();
+
+    let history = store.history(DocumentKind::Group, "staff").unwrap();
+    assert_eq!(2, history.len());
+    assert_eq!(None, history[0].author);
+    assert_eq!("legacy", history[0].document["description"]);
+    assert_eq!("new", history[1].document["description"]);
+}
+
+#[test]
+fn test_missing_history_is_empty() {
+    let (_dir, store) = temp_store();
+    assert!(store
+        .history(DocumentKind::User, "nobody")
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_history_refuses_names_outside_the_store() {
+    let (_dir, store) = temp_store();
+    for name in ["../users/root", ".hidden"] {
+        assert!(
+            matches!(
+                store.history(DocumentKind::User, name),
+                Err(PolicyStoreError::InvalidName(_))
+            ),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn test_stale_base_revision_is_refused() {
+    let (_dir, store) = temp_store();
+    store
+        .create_group(&group("one"), &change("dan", None))
+        .unwrap();
+    let based_on = |revision| Change {
+        base_revision: Some(revision),
+        ..change("dan", None)
+    };
+    store.update_group(&group("two"), &based_on(1)).unwrap();
+    assert!(matches!(
+        store.update_group(&group("three"), &based_on(1)),
+        Err(PolicyStoreError::Conflict(_))
+    ));
+
+    assert_eq!(2, store.revision(DocumentKind::Group, "staff").unwrap());
+    assert_eq!(
+        Some(String::from("two")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .unwrap()
+            .description
+    );
+}
+
+#[test]
+fn test_errors_say_what_went_wrong() {
+    let (_dir, store) = temp_store();
+    store
+        .create_group(&group("one"), &change("dan", None))
+        .unwrap();
+    assert!(matches!(
+        store.create_group(&group("two"), &change("dan", None)),
+        Err(PolicyStoreError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        store.user_named(&"nobody".parse().unwrap()),
+        Err(PolicyStoreError::NotFound(_))
+    ));
+    assert!(matches!(
+        store.set_user_password(&"nobody".parse().unwrap(), Some("pw")),
+        Err(PolicyStoreError::NotFound(_))
+    ));
+}
+
+#[test]
+fn test_writes_replace_the_whole_document() {
+    let (dir, store) = temp_store();
+    store
+        .create_group(&group("a much longer description"), &change("dan", None))
+        .unwrap();
+    store
+        .update_group(&group("short"), &change("dan", None))
+        .unwrap();
+
+    let written = fs::read_to_string(dir.join("groups").join("staff.json")).unwrap();
+    let written: Group = json::from_str(&written).unwrap();
+    assert_eq!(Some(String::from("short")), written.description);
+    let leftovers = fs::read_dir(dir.join("groups"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["staff.json"], leftovers);
+}
+
+#[test]
+fn test_integrity_check_reports_unreadable_documents() {
+    let (dir, store) = temp_store();
+    store
+        .create_group(&group("one"), &change("dan", None))
+        .unwrap();
+    fs::write(dir.join("users").join("dan.json"), "{\"login_name\": \"da").unwrap();
+    // No process can have this ID.
+    let unfinished = dir.join("groups").join(".staff.json.4294967295.tmp");
+    fs::write(&unfinished, "{").unwrap();
+    // Another process may still be writing this one.
+    let in_flight = dir
+        .join("groups")
+        .join(format!(".staff.json.{}.tmp", std::process::id()));
+    fs::write(&in_flight, "{").unwrap();
+
+    assert!(store.list_users().unwrap().is_empty());
+    let problems = store.check_integrity().unwrap();
+    assert_eq!(1, problems.len());
+    assert!(problems[0].starts_with("Error decoding user 'dan'"));
+    assert!(!unfinished.exists());
+    assert!(in_flight.exists());
+    assert_eq!(1, store.list_groups().unwrap().len());
+}
+
+#[test]
+fn test_writes_keep_the_document_permissions() {
+    let (dir, store) = temp_store();
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    store
+        .create_group(&group("one"), &change("dan", None))
+        .unwrap();
+    let path = dir.join("groups").join("staff.json");
+    assert_eq!(0o600, mode(&path));
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    store
+        .update_group(&group("two"), &change("dan", None))
+        .unwrap();
+    assert_eq!(0o640, mode(&path));
+}
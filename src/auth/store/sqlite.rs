@@ -0,0 +1,934 @@
+use crate::auth::name::{InvalidName, PrincipalName};
+use crate::auth::policy::{
+    creates_cycle_with, Change, DocumentKind, Group, ManagedPolicy, PolicyStatement, Revision, User,
+};
+use crate::auth::store::files::FilePolicyStore;
+use crate::auth::store::{
+    logged, wait_for_lock, BlockingPolicyStore, PolicyStoreError, LOCK_TIMEOUT,
+};
+use crate::util::now_as_secs;
+use log::warn;
+use pwhash::sha512_crypt;
+use rocket::serde::json::{self, Value};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{
+    ffi, params, Connection, ErrorCode, OptionalExtension, ToSql, Transaction, TransactionBehavior,
+};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use thiserror::Error;
+
+#[cfg(test)]
+#[path = "sqlite_tests.rs"]
+mod sqlite_tests;
+
+/// Schema changes, applied in order. The database's `user_version` records
+/// how many of them have been applied.
+///
+/// Each list belonging to a document (a user's groups, a group's parents, the
+/// policies attached to a user or group and the statements of any of them) is
+/// kept in its own table, keyed by the kind and name of its owner and ordered
+/// by `position`.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE users (
+        login_name TEXT PRIMARY KEY NOT NULL,
+        full_name TEXT,
+        password_hash TEXT
+    );
+    CREATE TABLE groups (
+        name TEXT PRIMARY KEY NOT NULL,
+        description TEXT
+    );
+    CREATE TABLE policies (
+        name TEXT PRIMARY KEY NOT NULL,
+        description TEXT,
+        version INTEGER NOT NULL
+    );
+    CREATE TABLE memberships (
+        owner_kind TEXT NOT NULL,
+        owner_name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        group_name TEXT NOT NULL,
+        PRIMARY KEY (owner_kind, owner_name, position)
+    );
+    CREATE INDEX memberships_by_group ON memberships (group_name);
+    CREATE TABLE parents (
+        owner_kind TEXT NOT NULL,
+        owner_name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        parent_name TEXT NOT NULL,
+        PRIMARY KEY (owner_kind, owner_name, position)
+    );
+    CREATE TABLE attachments (
+        owner_kind TEXT NOT NULL,
+        owner_name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        policy_name TEXT NOT NULL,
+        PRIMARY KEY (owner_kind, owner_name, position)
+    );
+    CREATE TABLE statements (
+        owner_kind TEXT NOT NULL,
+        owner_name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        statement TEXT NOT NULL,
+        PRIMARY KEY (owner_kind, owner_name, position)
+    );
+    CREATE TABLE revisions (
+        kind TEXT NOT NULL,
+        name TEXT NOT NULL,
+        revision INTEGER NOT NULL,
+        author TEXT,
+        timestamp INTEGER NOT NULL,
+        reason TEXT,
+        document TEXT NOT NULL,
+        PRIMARY KEY (kind, name, revision)
+    );
+"#];
+
+#[derive(Debug, Error)]
+enum SqliteError {
+    #[error("database error: {0}")]
+    Sql(#[from] rusqlite::Error),
+
+    #[error("error encoding or decoding a document: {0}")]
+    Json(#[from] json::serde_json::Error),
+
+    #[error("{0} does not exist")]
+    NotFound(String),
+
+    #[error("stale revision {0}, the latest revision is {1}")]
+    Stale(u64, u64),
+
+    #[error("the database schema version {0} is newer than this version of swaf supports")]
+    NewerSchema(usize),
+
+    #[error("timed out waiting for the database connection")]
+    LockTimeout,
+
+    #[error("{0}")]
+    InvalidName(#[from] InvalidName),
+
+    #[error("the parents of group '{0}' would make it its own ancestor")]
+    Cycle(String),
+}
+
+impl ToSql for PrincipalName {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
+/// Names are checked as they're read so that a row edited to hold an invalid
+/// name fails to load rather than reaching the rest of the app.
+impl FromSql for PrincipalName {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        PrincipalName::parse(value.as_str()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl From<SqliteError> for PolicyStoreError {
+    fn from(e: SqliteError) -> Self {
+        match e {
+            SqliteError::Sql(rusqlite::Error::SqliteFailure(f, _))
+                if matches!(f.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+            {
+                PolicyStoreError::Locked(String::from("the policy database"))
+            }
+            SqliteError::LockTimeout => {
+                PolicyStoreError::Locked(String::from("the policy database connection"))
+            }
+            SqliteError::NotFound(document) => PolicyStoreError::NotFound(document),
+            SqliteError::Stale(base, latest) => PolicyStoreError::Conflict(format!(
+                "Stale revision {base}, the latest revision is {latest}"
+            )),
+            SqliteError::Cycle(name) => PolicyStoreError::Conflict(format!(
+                "The parents of group '{name}' would make it its own ancestor"
+            )),
+            SqliteError::Json(_) | SqliteError::NewerSchema(_) | SqliteError::InvalidName(_) => {
+                PolicyStoreError::Corrupt(e.to_string())
+            }
+            SqliteError::Sql(_) => PolicyStoreError::Io(io::Error::other(e)),
+        }
+    }
+}
+
+/// Whether the error is from inserting a row whose key is already taken, as
+/// opposed to breaking any other constraint, e.g. a foreign key.
+fn is_duplicate(e: &SqliteError) -> bool {
+    matches!(
+        e,
+        SqliteError::Sql(rusqlite::Error::SqliteFailure(f, _))
+            if matches!(
+                f.extended_code,
+                ffi::SQLITE_CONSTRAINT_PRIMARYKEY | ffi::SQLITE_CONSTRAINT_UNIQUE
+            )
+    )
+}
+
+/// A policy store kept in a single SQLite database. Every write, along with
+/// the revision it records, is made in one transaction.
+pub struct SqlitePolicyStore {
+    connection: Mutex<Connection>,
+}
+
+/// The lists belonging to a document, as the table and column they're kept in.
+const GROUPS: (&str, &str) = ("memberships", "group_name");
+const PARENTS: (&str, &str) = ("parents", "parent_name");
+const POLICIES: (&str, &str) = ("attachments", "policy_name");
+const STATEMENTS: (&str, &str) = ("statements", "statement");
+
+fn kind_name(kind: DocumentKind) -> &'static str {
+    match kind {
+        DocumentKind::User => "user",
+        DocumentKind::Group => "group",
+        DocumentKind::Policy => "policy",
+    }
+}
+
+impl SqlitePolicyStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqlitePolicyStore, String> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Error creating directory for {path:?}: {e:?}"))?;
+        }
+        let connection = Connection::open(path)
+            .map_err(|e| format!("Error opening policy database {path:?}: {e}"))?;
+        SqlitePolicyStore::with_connection(connection)
+            .map_err(|e| format!("Error preparing policy database {path:?}: {e}"))
+    }
+
+    /// A store which lasts as long as it does, for tests.
+    pub fn open_in_memory() -> Result<SqlitePolicyStore, String> {
+        let connection = Connection::open_in_memory()
+            .map_err(|e| format!("Error opening in-memory policy database: {e}"))?;
+        SqlitePolicyStore::with_connection(connection)
+            .map_err(|e| format!("Error preparing in-memory policy database: {e}"))
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<SqlitePolicyStore, SqliteError> {
+        // Other processes (e.g. a backup) may hold the database briefly.
+        connection.busy_timeout(LOCK_TIMEOUT)?;
+        migrate(&mut connection)?;
+        Ok(SqlitePolicyStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>, SqliteError> {
+        let connection = wait_for_lock(|| match self.connection.try_lock() {
+            Ok(connection) => Ok::<_, SqliteError>(Some(connection)),
+            Err(TryLockError::Poisoned(e)) => Ok(Some(e.into_inner())),
+            Err(TryLockError::WouldBlock) => Ok(None),
+        })?;
+        connection.ok_or(SqliteError::LockTimeout)
+    }
+
+    fn read<T, F>(&self, op: F) -> Result<T, SqliteError>
+    where
+        F: FnOnce(&Connection) -> Result<T, SqliteError>,
+    {
+        let connection = self.lock()?;
+        op(&connection)
+    }
+
+    fn write<T, F>(&self, op: F) -> Result<T, SqliteError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, SqliteError>,
+    {
+        let mut connection = self.lock()?;
+        // Immediate so the revision check and the write can't be interleaved
+        // with another process's write.
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let ret = op(&tx)?;
+        tx.commit()?;
+        Ok(ret)
+    }
+
+    /// Makes a write with `write` and records the resulting document as a new
+    /// revision, all in one transaction. The write is refused if
+    /// `change.base_revision` isn't the latest revision. When the document was
+    /// written before history was kept, the previous document is recorded
+    /// first so the change can be rolled back.
+    fn revise<W>(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+        write: W,
+    ) -> Result<(), PolicyStoreError>
+    where
+        W: FnOnce(&Transaction) -> Result<(), SqliteError>,
+    {
+        self.write(|tx| {
+            let latest = latest_revision(tx, kind, name)?;
+            if let Some(base) = change.base_revision {
+                if base != latest {
+                    return Err(SqliteError::Stale(base, latest));
+                }
+            }
+            let previous = load_document(tx, kind, name)?;
+            write(tx)?;
+            let mut next = latest + 1;
+            if let (1, Some(previous)) = (next, previous) {
+                let untracked = Change {
+                    reason: Some(String::from("Written before history was kept")),
+                    ..Change::default()
+                };
+                insert_revision(tx, kind, name, &revision(1, previous, &untracked))?;
+                next = 2;
+            }
+            let document = load_document(tx, kind, name)?
+                .ok_or_else(|| SqliteError::NotFound(format!("{kind} '{name}'")))?;
+            insert_revision(tx, kind, name, &revision(next, document, change))
+        })
+        .map_err(|e| match e {
+            e if is_duplicate(&e) => PolicyStoreError::AlreadyExists(format!("{kind} '{name}'")),
+            e => logged(format!("Error writing {kind} '{name}'"), e.into()),
+        })
+    }
+
+    /// Whether the store has no users, groups or policies.
+    pub fn is_empty(&self) -> Result<bool, String> {
+        self.read(|c| {
+            Ok(c.query_row(
+                "SELECT NOT EXISTS (SELECT 1 FROM users)
+                    AND NOT EXISTS (SELECT 1 FROM groups)
+                    AND NOT EXISTS (SELECT 1 FROM policies)",
+                [],
+                |r| r.get(0),
+            )?)
+        })
+        .map_err(|e| format!("Error reading policy database: {e}"))
+    }
+
+    /// Copies every user (with their password hash), group and policy, along
+    /// with their history, from a file store. It's done in one transaction so
+    /// an interrupted import leaves the database as it was.
+    pub fn import(&self, from: &FilePolicyStore) -> Result<(), String> {
+        let failed = |e| format!("Error reading file policy store: {e}");
+        let users = from.list_users().map_err(failed)?;
+        let groups = from.list_groups().map_err(failed)?;
+        let policies = from.list_policies().map_err(failed)?;
+        let mut hashes = Vec::new();
+        for user in &users {
+            hashes.push(from.password_hash(&user.login_name).map_err(failed)?);
+        }
+        let mut histories = Vec::new();
+        for (kind, name) in users
+            .iter()
+            .map(|u| (DocumentKind::User, u.login_name.as_str()))
+            .chain(
+                groups
+                    .iter()
+                    .map(|g| (DocumentKind::Group, g.name.as_str())),
+            )
+            .chain(
+                policies
+                    .iter()
+                    .map(|p| (DocumentKind::Policy, p.name.as_str())),
+            )
+        {
+            histories.push((kind, name, from.history(kind, name).map_err(failed)?));
+        }
+
+        self.write(|tx| {
+            for (user, hash) in users.iter().zip(&hashes) {
+                insert_user(tx, user, hash.as_deref())?;
+            }
+            for group in &groups {
+                insert_group(tx, group)?;
+            }
+            for policy in &policies {
+                insert_policy(tx, policy)?;
+            }
+            for (kind, name, history) in &histories {
+                for revision in history {
+                    insert_revision(tx, *kind, name, revision)?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| format!("Error importing policy store: {e}"))
+    }
+}
+
+impl BlockingPolicyStore for SqlitePolicyStore {
+    fn list_users(&self) -> Result<Vec<User>, PolicyStoreError> {
+        self.read(|c| {
+            let names = names(c, "SELECT login_name FROM users ORDER BY login_name", [])?;
+            Ok(load_each(c, DocumentKind::User, &names, |c, n| {
+                load_user(c, n).map(|u| u.map(|(user, _)| user))
+            }))
+        })
+        .map_err(|e| logged(String::from("Error listing users"), e.into()))
+    }
+
+    fn user_named(&self, name: &PrincipalName) -> Result<User, PolicyStoreError> {
+        self.read(|c| load_user(c, name))
+            .map_err(|e| logged(format!("Error loading user '{name}'"), e.into()))?
+            .map(|(user, _)| user)
+            .ok_or_else(|| PolicyStoreError::NotFound(format!("user '{name}'")))
+    }
+
+    fn create_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::User, &user.login_name, change, |tx| {
+            insert_user(tx, user, None)
+        })
+    }
+
+    fn update_user(&self, user: &User, change: &Change) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::User, &user.login_name, change, |tx| {
+            let updated = tx.execute(
+                "UPDATE users SET full_name = ?2 WHERE login_name = ?1",
+                params![user.login_name, user.full_name],
+            )?;
+            if updated == 0 {
+                return Err(SqliteError::NotFound(format!("user '{}'", user.login_name)));
+            }
+            write_user_lists(tx, user)
+        })
+    }
+
+    fn set_user_password(
+        &self,
+        login_name: &PrincipalName,
+        password: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        let hash = match password {
+            None => None,
+            Some(pw) => Some(sha512_crypt::hash(pw).map_err(io::Error::other)?),
+        };
+        self.set_password_hash(login_name, hash.as_deref())
+    }
+
+    fn password_hash(
+        &self,
+        login_name: &PrincipalName,
+    ) -> Result<Option<String>, PolicyStoreError> {
+        let (_, hash) = self
+            .read(|c| load_user(c, login_name))
+            .map_err(|e| logged(format!("Error loading user '{login_name}'"), e.into()))?
+            .ok_or_else(|| PolicyStoreError::NotFound(format!("user '{login_name}'")))?;
+        Ok(hash)
+    }
+
+    fn set_password_hash(
+        &self,
+        login_name: &PrincipalName,
+        hash: Option<&str>,
+    ) -> Result<(), PolicyStoreError> {
+        let updated = self
+            .write(|tx| {
+                Ok(tx.execute(
+                    "UPDATE users SET password_hash = ?2 WHERE login_name = ?1",
+                    params![login_name, hash],
+                )?)
+            })
+            .map_err(|e| {
+                logged(
+                    format!("Error setting password of '{login_name}'"),
+                    e.into(),
+                )
+            })?;
+        if updated == 0 {
+            return Err(PolicyStoreError::NotFound(format!("user '{login_name}'")));
+        }
+        Ok(())
+    }
+
+    fn authenticate_user(
+        &self,
+        login_name: &PrincipalName,
+        password: &str,
+    ) -> Result<Option<User>, PolicyStoreError> {
+        let (user, hash) = self
+            .read(|c| load_user(c, login_name))
+            .map_err(|e| logged(format!("Error loading user '{login_name}'"), e.into()))?
+            .ok_or_else(|| PolicyStoreError::NotFound(format!("user '{login_name}'")))?;
+        match hash {
+            Some(hash) if sha512_crypt::verify(password, hash.as_str()) => Ok(Some(user)),
+            _ => Ok(None),
+        }
+    }
+
+    fn list_groups(&self) -> Result<Vec<Group>, PolicyStoreError> {
+        self.read(|c| {
+            let names = names(c, "SELECT name FROM groups ORDER BY name", [])?;
+            Ok(load_each(c, DocumentKind::Group, &names, load_group))
+        })
+        .map_err(|e| logged(String::from("Error listing groups"), e.into()))
+    }
+
+    fn group_named(&self, name: &PrincipalName) -> Option<Group> {
+        self.read(|c| load_group(c, name))
+            .map_err(|e| logged(format!("Error loading group '{name}'"), e.into()))
+            .ok()
+            .flatten()
+    }
+
+    fn create_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::Group, &group.name, change, |tx| {
+            refuse_cycle(tx, group)?;
+            insert_group(tx, group)
+        })
+    }
+
+    fn update_group(&self, group: &Group, change: &Change) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::Group, &group.name, change, |tx| {
+            refuse_cycle(tx, group)?;
+            let updated = tx.execute(
+                "UPDATE groups SET description = ?2 WHERE name = ?1",
+                params![group.name, group.description],
+            )?;
+            if updated == 0 {
+                return Err(SqliteError::NotFound(format!("group '{}'", group.name)));
+            }
+            write_group_lists(tx, group)
+        })
+    }
+
+    fn group_members(&self, name: &PrincipalName) -> Result<Vec<User>, PolicyStoreError> {
+        self.read(|c| {
+            let names = names(
+                c,
+                "SELECT DISTINCT owner_name FROM memberships
+                    WHERE owner_kind = 'user' AND group_name = ?1
+                    ORDER BY owner_name",
+                [name],
+            )?;
+            Ok(load_each(c, DocumentKind::User, &names, |c, n| {
+                load_user(c, n).map(|u| u.map(|(user, _)| user))
+            }))
+        })
+        .map_err(|e| logged(format!("Error listing members of group '{name}'"), e.into()))
+    }
+
+    fn list_policies(&self) -> Result<Vec<ManagedPolicy>, PolicyStoreError> {
+        self.read(|c| {
+            let names = names(c, "SELECT name FROM policies ORDER BY name", [])?;
+            Ok(load_each(c, DocumentKind::Policy, &names, load_policy))
+        })
+        .map_err(|e| logged(String::from("Error listing policies"), e.into()))
+    }
+
+    fn policy_named(&self, name: &str) -> Option<ManagedPolicy> {
+        self.read(|c| load_policy(c, name))
+            .map_err(|e| logged(format!("Error loading policy '{name}'"), e.into()))
+            .ok()
+            .flatten()
+    }
+
+    fn create_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let policy = ManagedPolicy {
+            version: 1,
+            ..policy.clone()
+        };
+        self.revise(DocumentKind::Policy, &policy.name, change, |tx| {
+            insert_policy(tx, &policy)
+        })
+    }
+
+    fn update_policy(
+        &self,
+        policy: &ManagedPolicy,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        self.revise(DocumentKind::Policy, &policy.name, change, |tx| {
+            let updated = tx.execute(
+                "UPDATE policies SET description = ?2, version = version + 1 WHERE name = ?1",
+                params![policy.name, policy.description],
+            )?;
+            if updated == 0 {
+                return Err(SqliteError::NotFound(format!("policy '{}'", policy.name)));
+            }
+            write_statements(
+                tx,
+                DocumentKind::Policy,
+                &policy.name,
+                &policy.policy_statements,
+            )
+        })
+    }
+
+    fn delete(
+        &self,
+        kind: DocumentKind,
+        name: &str,
+        change: &Change,
+    ) -> Result<(), PolicyStoreError> {
+        let (table, key) = match kind {
+            DocumentKind::User => ("users", "login_name"),
+            DocumentKind::Group => ("groups", "name"),
+            DocumentKind::Policy => ("policies", "name"),
+        };
+        self.write(|tx| {
+            let latest = latest_revision(tx, kind, name)?;
+            if let Some(base) = change.base_revision {
+                if base != latest {
+                    return Err(SqliteError::Stale(base, latest));
+                }
+            }
+            let deleted = tx.execute(&format!("DELETE FROM {table} WHERE {key} = ?1"), [name])?;
+            if deleted == 0 {
+                return Err(SqliteError::NotFound(format!("{kind} '{name}'")));
+            }
+            for (table, _) in [GROUPS, PARENTS, POLICIES, STATEMENTS] {
+                tx.execute(
+                    &format!("DELETE FROM {table} WHERE owner_kind = ?1 AND owner_name = ?2"),
+                    params![kind_name(kind), name],
+                )?;
+            }
+            Ok(())
+        })
+        .map_err(|e| logged(format!("Error deleting {kind} '{name}'"), e.into()))
+    }
+
+    fn history(&self, kind: DocumentKind, name: &str) -> Result<Vec<Revision>, PolicyStoreError> {
+        self.read(|c| {
+            let mut statement = c.prepare_cached(
+                "SELECT revision, author, timestamp, reason, document FROM revisions
+                    WHERE kind = ?1 AND name = ?2 ORDER BY revision",
+            )?;
+            let rows = statement.query_map(params![kind_name(kind), name], |r| {
+                Ok((
+                    r.get::<_, u64>(0)?,
+                    r.get::<_, Option<String>>(1)?,
+                    r.get::<_, u64>(2)?,
+                    r.get::<_, Option<String>>(3)?,
+                    r.get::<_, String>(4)?,
+                ))
+            })?;
+            rows.map(|row| {
+                let (revision, author, timestamp, reason, document) = row?;
+                Ok(Revision {
+                    revision,
+                    author,
+                    timestamp,
+                    reason,
+                    document: json::from_str(&document)?,
+                })
+            })
+            .collect()
+        })
+        .map_err(|e| {
+            logged(
+                format!("Error reading history of {kind} '{name}'"),
+                e.into(),
+            )
+        })
+    }
+
+    fn revision(&self, kind: DocumentKind, name: &str) -> Result<u64, PolicyStoreError> {
+        self.read(|c| latest_revision(c, kind, name)).map_err(|e| {
+            logged(
+                format!("Error reading revision of {kind} '{name}'"),
+                e.into(),
+            )
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), SqliteError> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(SqliteError::NewerSchema(version));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn names<T: FromSql, P: rusqlite::Params>(
+    connection: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Vec<T>, SqliteError> {
+    let mut statement = connection.prepare_cached(sql)?;
+    let rows = statement.query_map(params, |r| r.get(0))?;
+    Ok(rows.collect::<Result<Vec<T>, _>>()?)
+}
+
+/// Loads each of the named documents, leaving out any which fail to, e.g. for
+/// a row edited to hold an invalid name, so that one bad row doesn't fail a
+/// whole list.
+fn load_each<T, L>(connection: &Connection, kind: DocumentKind, names: &[String], load: L) -> Vec<T>
+where
+    L: Fn(&Connection, &str) -> Result<Option<T>, SqliteError>,
+{
+    names
+        .iter()
+        .filter_map(|name| match load(connection, name) {
+            Ok(document) => document,
+            Err(e) => {
+                warn!("Error loading {kind} '{name}', leaving it out: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn read_list<T: FromSql>(
+    connection: &Connection,
+    (table, column): (&str, &str),
+    kind: DocumentKind,
+    name: &str,
+) -> Result<Vec<T>, SqliteError> {
+    names(
+        connection,
+        &format!(
+            "SELECT {column} FROM {table}
+                WHERE owner_kind = ?1 AND owner_name = ?2 ORDER BY position"
+        ),
+        params![kind_name(kind), name],
+    )
+}
+
+fn write_list<T: ToSql>(
+    tx: &Transaction,
+    (table, column): (&str, &str),
+    kind: DocumentKind,
+    name: &str,
+    values: &[T],
+) -> Result<(), SqliteError> {
+    tx.execute(
+        &format!("DELETE FROM {table} WHERE owner_kind = ?1 AND owner_name = ?2"),
+        params![kind_name(kind), name],
+    )?;
+    let mut insert = tx.prepare_cached(&format!(
+        "INSERT INTO {table} (owner_kind, owner_name, position, {column})
+            VALUES (?1, ?2, ?3, ?4)"
+    ))?;
+    for (position, value) in values.iter().enumerate() {
+        insert.execute(params![kind_name(kind), name, position, value])?;
+    }
+    Ok(())
+}
+
+fn read_statements(
+    connection: &Connection,
+    kind: DocumentKind,
+    name: &str,
+) -> Result<Vec<PolicyStatement>, SqliteError> {
+    read_list::<String>(connection, STATEMENTS, kind, name)?
+        .iter()
+        .map(|s| Ok(json::from_str(s)?))
+        .collect()
+}
+
+fn write_statements(
+    tx: &Transaction,
+    kind: DocumentKind,
+    name: &str,
+    statements: &[PolicyStatement],
+) -> Result<(), SqliteError> {
+    let statements = statements
+        .iter()
+        .map(json::to_string)
+        .collect::<Result<Vec<String>, _>>()?;
+    write_list(tx, STATEMENTS, kind, name, &statements)
+}
+
+/// A user along with their password hash.
+fn load_user(
+    connection: &Connection,
+    login_name: &str,
+) -> Result<Option<(User, Option<String>)>, SqliteError> {
+    let row = connection
+        .query_row(
+            "SELECT full_name, password_hash FROM users WHERE login_name = ?1",
+            [login_name],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    let (full_name, password_hash) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let kind = DocumentKind::User;
+    let user = User {
+        login_name: PrincipalName::parse(login_name)?,
+        full_name,
+        groups: read_list(connection, GROUPS, kind, login_name)?,
+        policy_statements: read_statements(connection, kind, login_name)?,
+        policies: read_list(connection, POLICIES, kind, login_name)?,
+    };
+    Ok(Some((user, password_hash)))
+}
+
+fn insert_user(
+    tx: &Transaction,
+    user: &User,
+    password_hash: Option<&str>,
+) -> Result<(), SqliteError> {
+    tx.execute(
+        "INSERT INTO users (login_name, full_name, password_hash) VALUES (?1, ?2, ?3)",
+        params![user.login_name, user.full_name, password_hash],
+    )?;
+    write_user_lists(tx, user)
+}
+
+fn write_user_lists(tx: &Transaction, user: &User) -> Result<(), SqliteError> {
+    let (kind, name) = (DocumentKind::User, &user.login_name);
+    write_list(tx, GROUPS, kind, name, &user.groups)?;
+    write_list(tx, POLICIES, kind, name, &user.policies)?;
+    write_statements(tx, kind, name, &user.policy_statements)
+}
+
+fn load_group(connection: &Connection, name: &str) -> Result<Option<Group>, SqliteError> {
+    let description = match connection
+        .query_row(
+            "SELECT description FROM groups WHERE name = ?1",
+            [name],
+            |r| r.get(0),
+        )
+        .optional()?
+    {
+        Some(description) => description,
+        None => return Ok(None),
+    };
+    let kind = DocumentKind::Group;
+    Ok(Some(Group {
+        name: PrincipalName::parse(name)?,
+        description,
+        policy_statements: read_statements(connection, kind, name)?,
+        policies: read_list(connection, POLICIES, kind, name)?,
+        parents: read_list(connection, PARENTS, kind, name)?,
+    }))
+}
+
+/// Refuses parents which would make `group` its own ancestor. This is checked
+/// in the transaction making the write so that two writes can't each pass it
+/// and together make a cycle.
+fn refuse_cycle(tx: &Transaction, group: &Group) -> Result<(), SqliteError> {
+    let group_named = |name: &PrincipalName| {
+        load_group(tx, name)
+            .map_err(|e| warn!("Error loading group '{name}': {e}"))
+            .ok()
+            .flatten()
+    };
+    if creates_cycle_with(group, group_named) {
+        return Err(SqliteError::Cycle(group.name.to_string()));
+    }
+    Ok(())
+}
+
+fn insert_group(tx: &Transaction, group: &Group) -> Result<(), SqliteError> {
+    tx.execute(
+        "INSERT INTO groups (name, description) VALUES (?1, ?2)",
+        params![group.name, group.description],
+    )?;
+    write_group_lists(tx, group)
+}
+
+fn write_group_lists(tx: &Transaction, group: &Group) -> Result<(), SqliteError> {
+    let (kind, name) = (DocumentKind::Group, &group.name);
+    write_list(tx, PARENTS, kind, name, &group.parents)?;
+    write_list(tx, POLICIES, kind, name, &group.policies)?;
+    write_statements(tx, kind, name, &group.policy_statements)
+}
+
+fn load_policy(connection: &Connection, name: &str) -> Result<Option<ManagedPolicy>, SqliteError> {
+    let (description, version) = match connection
+        .query_row(
+            "SELECT description, version FROM policies WHERE name = ?1",
+            [name],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    Ok(Some(ManagedPolicy {
+        name: String::from(name),
+        description,
+        version,
+        policy_statements: read_statements(connection, DocumentKind::Policy, name)?,
+    }))
+}
+
+fn insert_policy(tx: &Transaction, policy: &ManagedPolicy) -> Result<(), SqliteError> {
+    tx.execute(
+        "INSERT INTO policies (name, description, version) VALUES (?1, ?2, ?3)",
+        params![policy.name, policy.description, policy.version],
+    )?;
+    write_statements(
+        tx,
+        DocumentKind::Policy,
+        &policy.name,
+        &policy.policy_statements,
+    )
+}
+
+fn load_document(
+    connection: &Connection,
+    kind: DocumentKind,
+    name: &str,
+) -> Result<Option<Value>, SqliteError> {
+    let document = match kind {
+        DocumentKind::User => load_user(connection, name)?
+            .map(|(user, _)| json::to_value(user))
+            .transpose()?,
+        DocumentKind::Group => load_group(connection, name)?
+            .map(json::to_value)
+            .transpose()?,
+        DocumentKind::Policy => load_policy(connection, name)?
+            .map(json::to_value)
+            .transpose()?,
+    };
+    Ok(document)
+}
+
+fn latest_revision(
+    connection: &Connection,
+    kind: DocumentKind,
+    name: &str,
+) -> Result<u64, SqliteError> {
+    Ok(connection.query_row(
+        "SELECT COALESCE(MAX(revision), 0) FROM revisions WHERE kind = ?1 AND name = ?2",
+        params![kind_name(kind), name],
+        |r| r.get(0),
+    )?)
+}
+
+fn revision(number: u64, document: Value, change: &Change) -> Revision {
+    Revision {
+        revision: number,
+        author: change.author.clone(),
+        timestamp: now_as_secs().unwrap_or(0),
+        reason: change.reason.clone(),
+        document,
+    }
+}
+
+fn insert_revision(
+    tx: &Transaction,
+    kind: DocumentKind,
+    name: &str,
+    revision: &Revision,
+) -> Result<(), SqliteError> {
+    tx.execute(
+        "INSERT INTO revisions (kind, name, revision, author, timestamp, reason, document)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            kind_name(kind),
+            name,
+            revision.revision,
+            revision.author,
+            revision.timestamp,
+            revision.reason,
+            json::to_string(&revision.document)?,
+        ],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use super::*;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DIR_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn user(login_name: &str, groups: &[&str]) -> User {
+    User {
+        login_name: login_name.parse().unwrap(),
+        full_name: Some(String::from("Dan")),
+        groups: groups.iter().map(|g| g.parse().unwrap()).collect(),
+        policy_statements: vec![],
+        policies: vec![],
+    }
+}
+
+fn group(description: &str) -> Group {
+    Group {
+        name: "staff".parse().unwrap(),
+        description: Some(String::from(description)),
+        policy_statements: vec![],
+        policies: vec![],
+        parents: vec![],
+    }
+}
+
+fn change(author: &str) -> Change {
+    Change {
+        author: Some(String::from(author)),
+        ..Change::default()
+    }
+}
+
+#[test]
+fn test_user_round_trip() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    let mut dan = user("dan", &["staff", "admins"]);
+    dan.policy_statements =
+        json::from_str(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#).unwrap();
+    store.create_user(&dan, &change("root")).unwrap();
+    store
+        .set_user_password(&"dan".parse().unwrap(), Some("pw"))
+        .unwrap();
+
+    let loaded = store.user_named(&"dan".parse().unwrap()).unwrap();
+    assert_eq!(vec!["staff", "admins"], loaded.groups);
+    assert_eq!(1, loaded.policy_statements.len());
+    assert!(store
+        .authenticate_user(&"dan".parse().unwrap(), "pw")
+        .unwrap()
+        .is_some());
+    assert!(store
+        .authenticate_user(&"dan".parse().unwrap(), "nope")
+        .unwrap()
+        .is_none());
+
+    // Updating the user keeps their password.
+    store
+        .update_user(&user("dan", &["admins"]), &change("root"))
+        .unwrap();
+    assert_eq!(
+        vec!["admins"],
+        store.user_named(&"dan".parse().unwrap()).unwrap().groups
+    );
+    assert!(store
+        .authenticate_user(&"dan".parse().unwrap(), "pw")
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn test_create_existing_and_update_missing_fail() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    store
+        .create_user(&user("dan", &[]), &change("root"))
+        .unwrap();
+    assert!(matches!(
+        store.create_user(&user("dan", &[]), &change("root")),
+        Err(PolicyStoreError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        store.update_user(&user("bob", &[]), &change("root")),
+        Err(PolicyStoreError::NotFound(_))
+    ));
+    assert!(store.history(DocumentKind::User, "bob").unwrap().is_empty());
+}
+
+#[test]
+fn test_only_taken_keys_are_duplicates() {
+    let connection = Connection::open_in_memory().unwrap();
+    connection
+        .execute_batch("CREATE TABLE t (k TEXT PRIMARY KEY NOT NULL, v TEXT NOT NULL)")
+        .unwrap();
+    let insert = |k: &str, v: Option<&str>| {
+        connection
+            .execute("INSERT INTO t VALUES (?1, ?2)", params![k, v])
+            .map_err(SqliteError::from)
+    };
+    insert("a", Some("1")).unwrap();
+    assert!(is_duplicate(&insert("a", Some("2")).unwrap_err()));
+    assert!(!is_duplicate(&insert("b", None).unwrap_err()));
+}
+
+#[test]
+fn test_lists_leave_out_rows_which_dont_load() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    store
+        .create_user(&user("dan", &["staff"]), &change("root"))
+        .unwrap();
+    store
+        .connection
+        .lock()
+        .unwrap()
+        .execute_batch(
+            "INSERT INTO users (login_name) VALUES ('no spaces allowed');
+             INSERT INTO memberships VALUES ('user', 'no spaces allowed', 0, 'staff');",
+        )
+        .unwrap();
+    let logins = |users: Vec<User>| -> Vec<String> {
+        users.into_iter().map(|u| u.login_name.into()).collect()
+    };
+    assert_eq!(vec!["dan"], logins(store.list_users().unwrap()));
+    assert_eq!(
+        vec!["dan"],
+        logins(store.group_members(&"staff".parse().unwrap()).unwrap())
+    );
+}
+
+#[test]
+fn test_cycles_are_refused() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    let parented = |name: &str, parent: &str| Group {
+        name: name.parse().unwrap(),
+        parents: vec![parent.parse().unwrap()],
+        ..group("")
+    };
+    store.create_group(&group("one"), &change("root")).unwrap();
+    store
+        .create_group(&parented("team", "staff"), &change("root"))
+        .unwrap();
+    assert!(matches!(
+        store.update_group(&parented("staff", "team"), &change("root")),
+        Err(PolicyStoreError::Conflict(_))
+    ));
+    assert!(store
+        .group_named(&"staff".parse().unwrap())
+        .unwrap()
+        .parents
+        .is_empty());
+}
+
+#[test]
+fn test_group_members() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    for u in [
+        user("dan", &["staff"]),
+        user("bob", &["other"]),
+        user("amy", &["staff"]),
+    ] {
+        store.create_user(&u, &change("root")).unwrap();
+    }
+    let members = store.group_members(&"staff".parse().unwrap()).unwrap();
+    assert_eq!(
+        vec!["amy", "dan"],
+        members
+            .iter()
+            .map(|u| u.login_name.as_str())
+            .collect::<Vec<&str>>()
+    );
+}
+
+#[test]
+fn test_stale_base_revision_is_refused() {
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    store.create_group(&group("one"), &change("dan")).unwrap();
+    let based_on = |revision| Change {
+        base_revision: Some(revision),
+        ..change("dan")
+    };
+    store.update_group(&group("two"), &based_on(1)).unwrap();
+    assert!(matches!(
+        store.update_group(&group("three"), &based_on(1)),
+        Err(PolicyStoreError::Conflict(_))
+    ));
+
+    let history = store.history(DocumentKind::Group, "staff").unwrap();
+    assert_eq!(2, history.len());
+    assert_eq!("two", history[1].document["description"]);
+    assert_eq!(
+        Some(String::from("two")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .unwrap()
+            .description
+    );
+}
+
+#[test]
+fn test_import_from_file_store() {
+    let dir = env::temp_dir().join(format!(
+        "swaf-sqlite-{}-{}",
+        std::process::id(),
+        DIR_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let files = FilePolicyStore::new(&dir).unwrap();
+    files.create_group(&group("one"), &change("dan")).unwrap();
+    files.update_group(&group("two"), &change("dan")).unwrap();
+    files
+        .create_user(&user("dan", &["staff"]), &change("root"))
+        .unwrap();
+    files
+        .set_user_password(&"dan".parse().unwrap(), Some("pw"))
+        .unwrap();
+
+    let store = SqlitePolicyStore::open_in_memory().unwrap();
+    assert!(store.is_empty().unwrap());
+    store.import(&files).unwrap();
+
+    assert!(!store.is_empty().unwrap());
+    assert!(store
+        .authenticate_user(&"dan".parse().unwrap(), "pw")
+        .unwrap()
+        .is_some());
+    assert_eq!(2, store.revision(DocumentKind::Group, "staff").unwrap());
+    assert_eq!(
+        Some(String::from("two")),
+        store
+            .group_named(&"staff".parse().unwrap())
+            .unwrap()
+            .description
+    );
+}
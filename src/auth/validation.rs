@@ -0,0 +1,239 @@
+use crate::auth::name::PrincipalName;
+use crate::auth::policy::{
+    Group, ManagedPolicy, PolicyStatement, PolicyStore, PolicyVersion, User, KNOWN_ACTIONS,
+    VARIABLE_NAMES,
+};
+use crate::util;
+use rocket::serde::json::Value;
+use rocket::serde::Serialize;
+
+#[cfg(test)]
+#[path = "validation_tests.rs"]
+mod validation_tests;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new<F: Into<String>, M: Into<String>>(field: F, message: M) -> FieldError {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+/// Why a write to the policy store was refused: either the document was
+/// invalid, in which case the client gets the list of problems, or the write
+/// would lock administrators out, or it was based on a stale revision.
+#[derive(Debug)]
+pub enum WriteError {
+    Invalid(ValidationErrors),
+    LockOut(&'static str),
+    /// The write was based on an old revision. Holds the current document.
+    Stale(Value),
+}
+
+/// Turns a list of validation errors into a `WriteError` if it isn't empty.
+pub fn check(errors: Vec<FieldError>) -> Result<(), WriteError> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(WriteError::Invalid(ValidationErrors { errors }))
+    }
+}
+
+pub async fn validate_user<S: PolicyStore>(policy_store: &S, user: &User) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for (i, name) in user.groups.iter().enumerate() {
+        if policy_store.group_named(name).await.is_none() {
+            errors.push(FieldError::new(
+                format!("groups[{i}]"),
+                format!("Group '{name}' does not exist"),
+            ));
+        }
+    }
+    errors.extend(validate_policy_names(policy_store, &user.policies).await);
+    errors.extend(validate_statements(&user.policy_statements));
+    errors
+}
+
+pub async fn validate_group<S: PolicyStore>(policy_store: &S, group: &Group) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for (i, name) in group.parents.iter().enumerate() {
+        if policy_store.group_named(name).await.is_none() {
+            errors.push(FieldError::new(
+                format!("parents[{i}]"),
+                format!("Group '{name}' does not exist"),
+            ));
+        }
+    }
+    if policy_store.creates_cycle(group).await {
+        errors.push(FieldError::new(
+            "parents",
+            "A group cannot be its own ancestor",
+        ));
+    }
+    errors.extend(validate_policy_names(policy_store, &group.policies).await);
+    errors.extend(validate_statements(&group.policy_statements));
+    errors
+}
+
+pub fn validate_policy(policy: &ManagedPolicy) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    // Policy names are file names in the file policy store too, so they follow
+    // the same rules as the names of users and groups.
+    if let Err(e) = PrincipalName::parse(&policy.name) {
+        errors.push(FieldError::new("name", format!("Policy name {}", e.reason)));
+    }
+    errors.extend(validate_statements(&policy.policy_statements));
+    errors
+}
+
+async fn validate_policy_names<S: PolicyStore>(
+    policy_store: &S,
+    names: &[String],
+) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        if policy_store.policy_named(name).await.is_none() {
+            errors.push(FieldError::new(
+                format!("policies[{i}]"),
+                format!("Policy '{name}' does not exist"),
+            ));
+        }
+    }
+    errors
+}
+
+pub fn validate_statements(statements: &[PolicyStatement]) -> Vec<FieldError> {
+    statements
+        .iter()
+        .enumerate()
+        .flat_map(|(i, s)| validate_statement(&format!("policy_statements[{i}]"), s))
+        .collect()
+}
+
+fn validate_statement(prefix: &str, statement: &PolicyStatement) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let (action_field, actions) = match &statement.not_actions {
+        Some(not_actions) => ("not_actions", not_actions),
+        None => ("actions", &statement.actions),
+    };
+    if actions.is_empty() {
+        errors.push(FieldError::new(
+            format!("{prefix}.{action_field}"),
+            "At least one action is required",
+        ));
+    }
+    for (i, pattern) in actions.iter().enumerate() {
+        if let Some(message) = check_action(pattern) {
+            errors.push(FieldError::new(
+                format!("{prefix}.{action_field}[{i}]"),
+                message,
+            ));
+        }
+    }
+
+    let (resource_field, resources) = match &statement.not_resources {
+        Some(not_resources) => ("not_resources", not_resources),
+        None => ("resources", &statement.resources),
+    };
+    if resources.is_empty() {
+        errors.push(FieldError::new(
+            format!("{prefix}.{resource_field}"),
+            "At least one resource is required",
+        ));
+    }
+    for (i, pattern) in resources.iter().enumerate() {
+        if let Some(message) = check_resource(pattern, statement.version) {
+            errors.push(FieldError::new(
+                format!("{prefix}.{resource_field}[{i}]"),
+                message,
+            ));
+        }
+    }
+    errors
+}
+
+fn check_action(pattern: &str) -> Option<String> {
+    if let Some(message) = check_variables(pattern) {
+        return Some(message);
+    }
+    if pattern.contains("${") {
+        // Can't know what this expands to so it can't be checked against
+        // the catalog.
+        return None;
+    }
+    if KNOWN_ACTIONS
+        .iter()
+        .any(|action| util::glob_matches(pattern, action))
+    {
+        None
+    } else {
+        Some(format!("'{pattern}' does not match any known action"))
+    }
+}
+
+fn check_resource(pattern: &str, version: PolicyVersion) -> Option<String> {
+    if let Some(message) = check_variables(pattern) {
+        return Some(message);
+    }
+    if version == PolicyVersion::V2 && has_unterminated_class(pattern) {
+        return Some(format!("'{pattern}' has an unterminated character class"));
+    }
+    None
+}
+
+fn check_variables(pattern: &str) -> Option<String> {
+    let mut rest = pattern;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Some(format!("'{pattern}' has an unterminated variable")),
+        };
+        let name = &rest[start + 2..end];
+        if !VARIABLE_NAMES.contains(&name) {
+            return Some(format!("Unknown policy variable '${{{name}}}'"));
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+fn has_unterminated_class(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => {
+                // A `]` right after the opening bracket (or its negation) is
+                // part of the class rather than closing it.
+                let mut class = chars.clone().peekable();
+                if matches!(class.peek(), Some('!') | Some('^')) {
+                    class.next();
+                }
+                if class.peek() == Some(&']') {
+                    class.next();
+                }
+                if !class.any(|c| c == ']') {
+                    return true;
+                }
+            }
+            _ => (),
+        }
+    }
+    false
+}
@@ -0,0 +1,86 @@
+use super::*;
+use rocket::serde::json;
+
+fn statements(json: &str) -> Vec<PolicyStatement> {
+    json::from_str(json).expect("statements should deserialize")
+}
+
+fn fields(errors: Vec<FieldError>) -> Vec<String> {
+    errors.into_iter().map(|e| e.field).collect()
+}
+
+#[test]
+fn test_valid_statements_have_no_errors() {
+    let s = statements(
+        r#"[
+            {"effect": "Allow", "actions": ["*"], "resources": ["*"]},
+            {"effect": "Allow", "actions": ["file:*", "ListUsers"], "resources": ["home/${user}/*"]},
+            {"effect": "Deny", "not_actions": ["file:Read"], "resources": ["archive/*"]}
+        ]"#,
+    );
+    assert!(validate_statements(&s).is_empty());
+}
+
+#[test]
+fn test_unknown_actions_are_reported() {
+    let s = statements(
+        r#"[{"effect": "Allow", "actions": ["file:Read", "file:read", "Bogus*"], "resources": ["*"]}]"#,
+    );
+    assert_eq!(
+        vec![
+            "policy_statements[0].actions[1]",
+            "policy_statements[0].actions[2]"
+        ],
+        fields(validate_statements(&s))
+    );
+}
+
+#[test]
+fn test_empty_lists_are_reported() {
+    let s = statements(r#"[{"effect": "Allow", "actions": [], "resources": []}]"#);
+    assert_eq!(
+        vec![
+            "policy_statements[0].actions",
+            "policy_statements[0].resources"
+        ],
+        fields(validate_statements(&s))
+    );
+}
+
+#[test]
+fn test_bad_patterns_are_reported() {
+    let s = statements(
+        r#"[
+            {"effect": "Allow", "actions": ["*"], "resources": ["home/${usr}/*", "a/${user"]},
+            {"version": 2, "effect": "Allow", "actions": ["*"], "not_resources": ["log[0-9", "ok[]]"]}
+        ]"#,
+    );
+    assert_eq!(
+        vec![
+            "policy_statements[0].resources[0]",
+            "policy_statements[0].resources[1]",
+            "policy_statements[1].not_resources[0]"
+        ],
+        fields(validate_statements(&s))
+    );
+}
+
+#[test]
+fn test_policy_names_follow_the_rules_for_principal_names() {
+    let policy = |name: &str| ManagedPolicy {
+        name: String::from(name),
+        description: None,
+        version: 0,
+        policy_statements: statements(
+            r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#,
+        ),
+    };
+    assert!(validate_policy(&policy("read-only@files")).is_empty());
+    for name in ["", "../admins", "a b", ".hidden", "Anonymous"] {
+        assert_eq!(
+            vec!["name"],
+            fields(validate_policy(&policy(name))),
+            "{name}"
+        );
+    }
+}
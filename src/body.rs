@@ -0,0 +1,91 @@
+use crate::auth::validation::{FieldError, ValidationErrors, WriteError};
+use crate::error::{note_failure, ApiError};
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::serde::json::{self, Value};
+use rocket::serde::DeserializeOwned;
+use std::ops::Deref;
+
+/// A JSON request body. Unlike `Json`, a body which doesn't deserialize is
+/// refused with the same field-level errors as validation, naming the field
+/// at fault, rather than with a bare status.
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Reads a request body as JSON, within the `json` limit.
+pub async fn read(request: &Request<'_>, data: Data<'_>) -> Result<Value, ApiError> {
+    let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+    let text = match data.open(limit).into_string().await {
+        Ok(text) if text.is_complete() => text.into_inner(),
+        Ok(_) => return Err(Status::PayloadTooLarge.into()),
+        Err(_) => return Err(Status::BadRequest.into()),
+    };
+    json::from_str(&text).map_err(|e| {
+        ApiError::new(
+            Status::BadRequest,
+            "BadRequest",
+            format!("The request is not valid JSON: {e}"),
+        )
+    })
+}
+
+/// An error in a single field of a request body.
+pub fn invalid<F: Into<String>, M: Into<String>>(field: F, message: M) -> ApiError {
+    let error = FieldError {
+        field: field.into(),
+        message: message.into(),
+    };
+    WriteError::Invalid(ValidationErrors {
+        errors: vec![error],
+    })
+    .into()
+}
+
+/// Deserializes a request body, naming the field which couldn't be, e.g.
+/// `policy_statements[0].conditions.time_window.utc_offset`.
+pub fn parse<T: DeserializeOwned>(value: Value) -> Result<T, ApiError> {
+    serde_path_to_error::deserialize(value)
+        .map_err(|e| invalid(e.path().to_string(), e.inner().to_string()))
+}
+
+/// Turns the result of reading a body into a data guard's outcome, noting a
+/// failure for the catchers.
+pub fn outcome<'r, T>(
+    request: &Request<'_>,
+    result: Result<T, ApiError>,
+) -> data::Outcome<'r, T, ApiError> {
+    match result {
+        Ok(body) => data::Outcome::Success(body),
+        Err(e) => {
+            note_failure(request, e.clone());
+            data::Outcome::Failure((e.status(), e))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for JsonBody<T> {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let result = match read(request, data).await {
+            Ok(value) => parse(value).map(JsonBody),
+            Err(e) => Err(e),
+        };
+        outcome(request, result)
+    }
+}
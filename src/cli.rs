@@ -0,0 +1,148 @@
+use crate::audit::{Audit, AuditLog};
+use crate::auth::bundle::{self, Bundle, BundleError, BundleFormat, ImportMode};
+use crate::auth::policy::Change;
+use crate::auth::store;
+use crate::auth::validation::WriteError;
+use crate::config::Config;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+
+const USAGE: &str = "\
+Usage:
+    swaf                     Serve the API and SPA
+    swaf export [--secrets] [--format json|yaml] [FILE]
+                             Write the policy store as a bundle to FILE or stdout
+    swaf import [--mode merge|replace] [--dry-run] [--force] FILE|-
+                             Import a bundle from FILE or stdin";
+
+/// Runs the command given on the command line, against the policy store and
+/// audit log in the configuration the server would use.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let (command, args) = args.split_first().ok_or(USAGE)?;
+    match command.as_str() {
+        "export" => export(args).await,
+        "import" => import(args).await,
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(format!("Unknown command '{command}'\n\n{USAGE}")),
+    }
+}
+
+fn config() -> Result<Config, String> {
+    rocket::Config::figment()
+        .extract()
+        .map_err(|e| format!("Error loading configuration: {e}"))
+}
+
+/// Takes the value following an option such as `--format`.
+fn value<'a>(option: &str, args: &mut impl Iterator<Item = &'a String>) -> Result<&'a str, String> {
+    args.next()
+        .map(String::as_str)
+        .ok_or_else(|| format!("{option} needs a value\n\n{USAGE}"))
+}
+
+/// Refuses an argument which isn't an option, or a second file.
+fn file<'a>(file: &mut Option<&'a str>, arg: &'a str) -> Result<(), String> {
+    if arg.starts_with("--") || file.is_some() {
+        return Err(format!("Unexpected argument '{arg}'\n\n{USAGE}"));
+    }
+    *file = Some(arg);
+    Ok(())
+}
+
+async fn export(args: &[String]) -> Result<(), String> {
+    let mut secrets = false;
+    let mut format = BundleFormat::default();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--secrets" => secrets = true,
+            "--format" => format = value(arg, &mut args)?.parse()?,
+            _ => file(&mut output, arg)?,
+        }
+    }
+
+    let policy_store = store::open(&config()?)?;
+    let bundle = bundle::export(&policy_store, secrets)
+        .await
+        .map_err(|e| e.to_string())?;
+    let text = bundle.to_string(format).map_err(|e| e.to_string())?;
+    match output {
+        Some(path) => fs::write(path, text),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+    .map_err(|e| format!("Error writing the bundle: {e}"))
+}
+
+async fn import(args: &[String]) -> Result<(), String> {
+    let mut mode = ImportMode::default();
+    let mut dry_run = false;
+    let mut force = false;
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mode" => mode = value(arg, &mut args)?.parse()?,
+            "--dry-run" => dry_run = true,
+            "--force" => force = true,
+            _ => file(&mut input, arg)?,
+        }
+    }
+    let input = input.ok_or_else(|| format!("import needs a bundle file\n\n{USAGE}"))?;
+    let text = match input {
+        "-" => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).map(|_| text)
+        }
+        path => fs::read_to_string(path),
+    }
+    .map_err(|e| format!("Error reading the bundle: {e}"))?;
+
+    let config = config()?;
+    let policy_store = store::open(&config)?;
+    let bundle = Bundle::parse(&text).map_err(|e| e.to_string())?;
+    let plan = bundle::plan(&policy_store, &bundle, mode, force)
+        .await
+        .map_err(refused)?;
+    for planned in &plan.changes {
+        println!("{planned}");
+    }
+    if dry_run {
+        println!("{} change(s) not applied (dry run)", plan.changes.len());
+        return Ok(());
+    }
+
+    let author = env::var("USER").ok();
+    let audit_log = AuditLog::new(
+        &config.audit_log,
+        config.audit_log_max_bytes,
+        config.audit_log_keep,
+    )
+    .map_err(|e| format!("Error opening audit log: {e}"))?;
+    let audit = Audit::local(audit_log, author.clone());
+    let change = Change {
+        author,
+        reason: Some(String::from("Imported with swaf import")),
+        base_revision: None,
+    };
+    bundle::apply(&policy_store, &bundle, &plan, &change, |planned| {
+        audit.import(planned)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    println!("{} change(s) applied", plan.changes.len());
+    Ok(())
+}
+
+fn refused(e: BundleError) -> String {
+    match e {
+        BundleError::Refused(WriteError::LockOut(_)) => {
+            format!("{}. Use --force to import it anyway.", e.details())
+        }
+        e => e.details(),
+    }
+}
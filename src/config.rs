@@ -1,11 +1,55 @@
+use crate::auth::condition::Cidr;
 use rocket::serde::Deserialize;
 use std::path::PathBuf;
 
+/// Where users, groups and policies are kept.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum PolicyStoreBackend {
+    /// One JSON file per document under `policy_store_root`.
+    #[default]
+    Files,
+    /// A SQLite database at `policy_store_database`. When the database is
+    /// empty, the file store at `policy_store_root` is imported into it.
+    Sqlite,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct Config {
     pub file_root: PathBuf,
     pub policy_store_root: PathBuf,
+    #[serde(default)]
+    pub policy_store: PolicyStoreBackend,
+    pub policy_store_database: Option<PathBuf>,
+    /// A bundle of users, groups and policies which the policy store is made
+    /// to match at startup and on SIGHUP.
+    pub declared_policy: Option<PathBuf>,
+    /// Whether documents missing from `declared_policy` are deleted.
+    #[serde(default)]
+    pub declared_policy_prune: bool,
     pub hook_root: PathBuf,
     pub hook_shell: String,
+    #[serde(default = "default_audit_log")]
+    pub audit_log: PathBuf,
+    #[serde(default = "default_audit_log_max_bytes")]
+    pub audit_log_max_bytes: u64,
+    #[serde(default = "default_audit_log_keep")]
+    pub audit_log_keep: usize,
+    /// Reverse proxies whose `X-Real-IP` and `X-Forwarded-Proto` headers are
+    /// believed. Requests from anywhere else are judged by their connection.
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+fn default_audit_log() -> PathBuf {
+    PathBuf::from("audit/audit.log")
+}
+
+fn default_audit_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_log_keep() -> usize {
+    10
 }
@@ -0,0 +1,262 @@
+use crate::auth::bundle::BundleError;
+use crate::auth::name::InvalidName;
+use crate::auth::store::PolicyStoreError;
+use crate::auth::validation::WriteError;
+use log::warn;
+use rocket::catcher::Catcher;
+use rocket::http::Status;
+use rocket::request::{Outcome, Request};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{self, Json, Value};
+use rocket::serde::Serialize;
+use std::sync::{Mutex, MutexGuard};
+
+/// An error response from the API. Every error the API gives, whether from a
+/// route or a catcher, has a JSON body with a `code` the client can act on, a
+/// `message` for people and sometimes `details`, e.g. what was invalid.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: Status,
+    body: ErrorBody,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(status: Status, code: &'static str, message: M) -> ApiError {
+        ApiError {
+            status,
+            body: ErrorBody {
+                code,
+                message: message.into(),
+                details: None,
+            },
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.body.details = Some(details);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.body.code
+    }
+}
+
+/// The code and message given for a status when there's nothing more
+/// specific to say.
+fn describe(status: Status) -> (&'static str, &'static str) {
+    match status.code {
+        400 => ("BadRequest", "The request is invalid"),
+        401 => ("NotLoggedIn", "You are not logged in"),
+        403 => ("AccessDenied", "Access denied"),
+        404 => ("NotFound", "Not found"),
+        409 => ("Conflict", "The request conflicts with the current state"),
+        413 => ("PayloadTooLarge", "The request is too large"),
+        422 => ("Invalid", "The request could not be understood"),
+        428 => (
+            "PreconditionRequired",
+            "The revision being updated is required",
+        ),
+        500 => ("InternalError", "Internal error"),
+        _ => ("Error", "The request failed"),
+    }
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        let (code, message) = describe(status);
+        ApiError::new(status, code, message)
+    }
+}
+
+impl From<PolicyStoreError> for ApiError {
+    fn from(e: PolicyStoreError) -> Self {
+        let status = e.status();
+        if status == Status::InternalServerError {
+            // The details are for the log, not the client.
+            warn!("Policy store error: {e}");
+            return ApiError::new(status, e.code(), "The policy store failed");
+        }
+        ApiError::new(status, e.code(), e.to_string())
+    }
+}
+
+impl From<InvalidName> for ApiError {
+    fn from(e: InvalidName) -> Self {
+        PolicyStoreError::from(e).into()
+    }
+}
+
+impl From<WriteError> for ApiError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::Invalid(errors) => {
+                let error = ApiError::new(
+                    Status::UnprocessableEntity,
+                    "Invalid",
+                    "The document is invalid",
+                );
+                match json::to_value(errors) {
+                    Ok(errors) => error.with_details(errors),
+                    Err(_) => error,
+                }
+            }
+            WriteError::LockOut(message) => ApiError::new(Status::Conflict, "LockOut", message),
+            WriteError::Stale(current) => ApiError::new(
+                Status::Conflict,
+                "Stale",
+                "The document has been changed since the revision this update is based on",
+            )
+            .with_details(current),
+        }
+    }
+}
+
+impl From<BundleError> for ApiError {
+    fn from(e: BundleError) -> Self {
+        match e {
+            BundleError::Parse(message) => {
+                ApiError::new(Status::UnprocessableEntity, "InvalidBundle", message)
+            }
+            BundleError::Write(_) => {
+                warn!("{e}");
+                Status::InternalServerError.into()
+            }
+            BundleError::Refused(e) => e.into(),
+            BundleError::Store(e) => e.into(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (self.status, Json(self.body)).respond_to(request)
+    }
+}
+
+/// Why request guards failed, for the catchers to tell the client. Guards
+/// run inside one another, so for each status the first failure noted is the
+/// one which caused the others.
+#[derive(Default)]
+struct GuardFailures(Mutex<Vec<ApiError>>);
+
+fn guard_failures<'r>(request: &'r Request<'_>) -> MutexGuard<'r, Vec<ApiError>> {
+    request
+        .local_cache(GuardFailures::default)
+        .0
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Notes the error a failed guard should give, for the catchers.
+pub fn note_failure(request: &Request<'_>, error: ApiError) {
+    guard_failures(request).push(error);
+}
+
+/// Notes the message of a failed request guard so that it reaches the
+/// client in the catcher's response.
+pub trait Noted {
+    fn noted(self, request: &Request<'_>) -> Self;
+}
+
+impl<T> Noted for Outcome<T, &'static str> {
+    fn noted(self, request: &Request<'_>) -> Self {
+        if let Outcome::Failure((status, message)) = &self {
+            let (code, _) = describe(*status);
+            note_failure(request, ApiError::new(*status, code, *message));
+        }
+        self
+    }
+}
+
+/// Fails a request guard, noting `message` for the catchers.
+pub fn guard_failure<T>(
+    request: &Request<'_>,
+    status: Status,
+    message: &'static str,
+) -> Outcome<T, &'static str> {
+    Outcome::Failure((status, message)).noted(request)
+}
+
+fn caught(status: Status, request: &Request<'_>) -> ApiError {
+    guard_failures(request)
+        .iter()
+        .find(|e| e.status == status)
+        .cloned()
+        .unwrap_or_else(|| status.into())
+}
+
+#[catch(400)]
+fn bad_request(request: &Request) -> ApiError {
+    caught(Status::BadRequest, request)
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> ApiError {
+    caught(Status::Unauthorized, request)
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> ApiError {
+    caught(Status::Forbidden, request)
+}
+
+#[catch(404)]
+fn not_found(request: &Request) -> ApiError {
+    caught(Status::NotFound, request)
+}
+
+#[catch(409)]
+fn conflict(request: &Request) -> ApiError {
+    caught(Status::Conflict, request)
+}
+
+#[catch(413)]
+fn payload_too_large(request: &Request) -> ApiError {
+    caught(Status::PayloadTooLarge, request)
+}
+
+#[catch(422)]
+fn unprocessable_entity(request: &Request) -> ApiError {
+    caught(Status::UnprocessableEntity, request)
+}
+
+#[catch(500)]
+fn internal_server_error(request: &Request) -> ApiError {
+    caught(Status::InternalServerError, request)
+}
+
+/// Any other status, e.g. 428 when an update doesn't say which revision it
+/// is based on.
+#[catch(default)]
+fn any_other(status: Status, request: &Request) -> ApiError {
+    caught(status, request)
+}
+
+/// Catchers giving `ApiError` bodies, to be registered under `/api`.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        conflict,
+        payload_too_large,
+        unprocessable_entity,
+        internal_server_error,
+        any_other
+    ]
+}
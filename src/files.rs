@@ -1,4 +1,5 @@
 use crate::config::Config;
+use crate::error::{guard_failure, Noted};
 use rocket::http::Status;
 use rocket::outcome::{try_outcome, IntoOutcome};
 use rocket::request::{FromRequest, Outcome, Request};
@@ -28,14 +29,16 @@ impl<'r> FromRequest<'r> for RequestedFile {
         let config = try_outcome!(request
             .guard::<&State<Config>>()
             .await
-            .map_failure(|_| (Status::InternalServerError, "Failed to retrieve config")));
+            .map_failure(|_| (Status::InternalServerError, "Failed to retrieve config"))
+            .noted(request));
         let req_path: PathBuf = match request.segments(1..) {
             Ok(path) => path,
-            Err(_) => return Outcome::Failure((Status::BadRequest, "Invalid path")),
+            Err(_) => return guard_failure(request, Status::BadRequest, "Invalid path"),
         };
         realize(&config.file_root, req_path, false)
             .map_err(|_| "Requested path did not realize")
             .into_outcome(Status::BadRequest)
+            .noted(request)
     }
 }
 
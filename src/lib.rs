@@ -1,31 +1,59 @@
-use auth::authorizor::RequestAuthorizor;
-use auth::policy::{Group, PolicyStore, User};
+use audit::{Audit, AuditEvent, AuditLog, AuditQuery, FieldChange};
+use auth::authorizor::{Explanation, RequestAuthorizor, RequestAuthorizorResult};
+use auth::bundle::{self, Bundle, BundleFormat, ImportMode, PlannedChange};
+use auth::condition::RequestContext;
+use auth::declared::DeclaredPolicy;
+use auth::lockout::{self, check_lock_out, Proposed};
+use auth::name::PrincipalName;
+use auth::policy::{
+    Change, DocumentKind, Group, ManagedPolicy, PolicyStore, Revision, User, KNOWN_ACTIONS,
+};
+use auth::revision::{IfMatch, Revisioned, Tagged};
 use auth::session::{Session, SessionCookie};
-use auth::store::files::FilePolicyStore;
+use auth::signed_url::{self, SignedFileRequest, SignedMethod, SignedUrlClaims, UrlSigner};
+use auth::store::{self, BoxedPolicyStore, PolicyStoreError};
+use auth::validation::{check, validate_group, validate_policy, validate_user, WriteError};
 use auth::{FileChildren, RequestedFileDataWritable, RequestedRegularFileDataReadable};
+use body::JsonBody;
 use config::Config;
+use error::ApiError;
+use log::warn;
 use meta::FileMetadata;
+use rocket::data::{Data, Limits, ToByteUnit};
 use rocket::form::{Form, FromForm};
 use rocket::fs::NamedFile;
 use rocket::fs::TempFile;
-use rocket::http::{Cookie, CookieJar, Status};
+use rocket::http::{ContentType, Cookie, CookieJar, Status};
 use rocket::serde::json;
 use rocket::serde::json::Json;
-use rocket::serde::Serialize;
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
 use rocket::tokio::fs;
 use rocket::State;
 use rocket::{Build, Rocket};
+use std::collections::BTreeMap;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use util::now_as_secs;
 
+pub use cli::run as run_command;
+
+mod audit;
 mod auth;
+mod body;
+mod cli;
 mod config;
+mod error;
 mod files;
 mod hook;
 mod meta;
 mod util;
 
+#[cfg(test)]
+#[path = "lib_tests.rs"]
+mod lib_tests;
+
 #[macro_use]
 extern crate rocket;
 
@@ -39,126 +67,1107 @@ fn user_current(session: Session) -> Json<User> {
     Json(session.user)
 }
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct GroupMembership {
+    groups: Vec<PrincipalName>,
+    effective_groups: Vec<PrincipalName>,
+}
+
+async fn group_membership(policy_store: &BoxedPolicyStore, user: User) -> GroupMembership {
+    let effective_groups = policy_store
+        .expand_groups(&user.groups)
+        .await
+        .into_iter()
+        .map(|g| g.name)
+        .collect();
+    GroupMembership {
+        groups: user.groups,
+        effective_groups,
+    }
+}
+
+#[get("/user/current/groups")]
+async fn user_current_groups(
+    session: Session,
+    policy_store: &State<BoxedPolicyStore>,
+) -> Json<GroupMembership> {
+    Json(group_membership(policy_store, session.user).await)
+}
+
+#[get("/user/<login_name>/groups")]
+async fn user_groups(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    login_name: &str,
+) -> Result<Json<GroupMembership>, ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    auth.require("ListUsers", &format!("user:{login_name}"))
+        .ok()?;
+    let user = policy_store.user_named(login_name).await?;
+    Ok(Json(group_membership(policy_store, user).await))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Permissions {
+    resource: String,
+    allowed: BTreeMap<String, bool>,
+}
+
+fn permissions_for(auth: &RequestAuthorizor, resource: &str, actions: &[String]) -> Permissions {
+    let allowed = if actions.is_empty() {
+        KNOWN_ACTIONS
+            .iter()
+            .map(|a| (String::from(*a), auth.permits(a, resource)))
+            .collect()
+    } else {
+        actions
+            .iter()
+            .map(|a| (a.clone(), auth.permits(a, resource)))
+            .collect()
+    };
+    Permissions {
+        resource: String::from(resource),
+        allowed,
+    }
+}
+
+#[get("/user/current/permissions?<resource>&<action>")]
+fn user_current_permissions(
+    auth: RequestAuthorizor,
+    resource: Option<&str>,
+    action: Vec<String>,
+) -> Json<Permissions> {
+    Json(permissions_for(&auth, resource.unwrap_or(""), &action))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PermissionsRequest {
+    resources: Vec<String>,
+    #[serde(default)]
+    actions: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct PermissionsList {
+    permissions: Vec<Permissions>,
+}
+
+#[post(
+    "/user/current/permissions",
+    format = "application/json",
+    data = "<request>"
+)]
+fn user_current_permissions_batch(
+    auth: RequestAuthorizor,
+    request: Json<PermissionsRequest>,
+) -> Json<PermissionsList> {
+    let permissions = request
+        .resources
+        .iter()
+        .map(|r| permissions_for(&auth, r, &request.actions))
+        .collect();
+    Json(PermissionsList { permissions })
+}
+
+/// Explains why an update based on `base_revision` failed: if the document
+/// has been changed since, the client gets the current document to merge
+/// their changes into.
+async fn write_failed<T: Serialize>(
+    policy_store: &BoxedPolicyStore,
+    kind: DocumentKind,
+    name: &str,
+    base_revision: u64,
+    current: Option<T>,
+    error: PolicyStoreError,
+) -> ApiError {
+    if let PolicyStoreError::Conflict(_) = error {
+        if let (Ok(latest), Some(document)) = (policy_store.revision(kind, name).await, current) {
+            if latest != base_revision {
+                return match json::to_value(Revisioned::new(document, latest)) {
+                    Ok(document) => WriteError::Stale(document).into(),
+                    Err(_) => Status::InternalServerError.into(),
+                };
+            }
+        }
+    }
+    error.into()
+}
+
+/// Pairs each document with its latest revision.
+async fn with_revisions<T, F>(
+    policy_store: &BoxedPolicyStore,
+    kind: DocumentKind,
+    documents: Vec<T>,
+    name: F,
+) -> Result<Vec<Revisioned<T>>, PolicyStoreError>
+where
+    T: Send,
+    F: Fn(&T) -> &str,
+{
+    let mut revisioned = Vec::new();
+    for document in documents {
+        let revision = policy_store.revision(kind, name(&document)).await?;
+        revisioned.push(Revisioned::new(document, revision));
+    }
+    Ok(revisioned)
+}
+
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 struct UserList {
-    users: Vec<User>,
+    users: Vec<Revisioned<User>>,
 }
 
 #[get("/users")]
-fn user_list(
+async fn user_list(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-) -> Result<Json<UserList>, Status> {
+    policy_store: &State<BoxedPolicyStore>,
+) -> Result<Json<UserList>, ApiError> {
     auth.require("ListUsers", &"").ok()?;
-    let users = policy_store
-        .list_users()
-        .map_err(|_| Status::InternalServerError)?;
+    let users = policy_store.list_users().await?;
+    let users = with_revisions(policy_store, DocumentKind::User, users, |u| &u.login_name).await?;
     Ok(Json(UserList { users }))
 }
 
+#[get("/user/<login_name>")]
+async fn user_get(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    login_name: &str,
+) -> Result<Tagged<User>, ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    auth.require("ListUsers", &format!("user:{login_name}"))
+        .ok()?;
+    let user = policy_store.user_named(login_name).await?;
+    let revision = policy_store
+        .revision(DocumentKind::User, login_name)
+        .await?;
+    Ok(Tagged::new(user, revision))
+}
+
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 struct GroupList {
-    groups: Vec<Group>,
+    groups: Vec<Revisioned<Group>>,
 }
 
 #[get("/groups")]
-fn group_list(
+async fn group_list(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-) -> Result<Json<GroupList>, Status> {
+    policy_store: &State<BoxedPolicyStore>,
+) -> Result<Json<GroupList>, ApiError> {
     auth.require("ListGroups", &"").ok()?;
-    let groups = policy_store
-        .list_groups()
-        .map_err(|_| Status::InternalServerError)?;
+    let groups = policy_store.list_groups().await?;
+    let groups = with_revisions(policy_store, DocumentKind::Group, groups, |g| &g.name).await?;
     Ok(Json(GroupList { groups }))
 }
 
-#[put("/group", format = "application/json", data = "<group>")]
-fn group_create(
+#[get("/group/<name>")]
+async fn group_get(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-    group: Json<Group>,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+) -> Result<Tagged<Group>, ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    auth.require("ListGroups", &format!("group:{name}")).ok()?;
+    let group = policy_store
+        .group_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let revision = policy_store.revision(DocumentKind::Group, name).await?;
+    Ok(Tagged::new(group, revision))
+}
+
+/// Attaching a policy to a user or group requires AttachPolicy on that policy
+/// in addition to permission to update the user or group.
+fn require_attach(
+    result: RequestAuthorizorResult,
+    old_policies: &[String],
+    new_policies: &[String],
 ) -> Result<(), Status> {
+    new_policies
+        .iter()
+        .filter(|p| !old_policies.contains(p))
+        .fold(result, |r, p| {
+            r.require("AttachPolicy", &format!("policy:{p}"))
+        })
+        .ok()
+}
+
+/// A write may only be forced past the lock-out check by someone who can
+/// currently administer every user and group.
+async fn may_force(
+    policy_store: &BoxedPolicyStore,
+    auth: &RequestAuthorizor,
+    force: Option<bool>,
+) -> bool {
+    force.unwrap_or(false) && lockout::administers(policy_store, auth).await
+}
+
+#[put("/group?<force>", format = "application/json", data = "<group>")]
+async fn group_create(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    group: JsonBody<Group>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
     let group = group.into_inner();
-    auth.require("CreateGroup", &format!("group:{}", group.name))
+    let resource = format!("group:{}", group.name);
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("CreateGroup", &resource);
+    require_attach(result, &[], &group.policies)?;
+    check(validate_group(policy_store.inner(), &group).await)?;
+    // Users may already name the group, e.g. one which was deleted and is
+    // being recreated, so its statements can lock them out. An existing
+    // group is left to the store to refuse.
+    if policy_store.group_named(&group.name).await.is_none() {
+        check_lock_out(
+            &Proposed::new(policy_store.inner()).with_group(group.clone()),
+            force,
+        )
+        .await?;
+    }
+    policy_store.create_group(&group, &change).await?;
+    audit.policy_change("CreateGroup", &resource, None, Some(&group));
+    Ok(())
+}
+
+#[post("/group?<force>", format = "application/json", data = "<group>")]
+async fn group_update(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    if_match: IfMatch,
+    group: Revisioned<Group>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let base_revision = if_match.0.or(group.revision);
+    let base_revision = base_revision.ok_or(Status::PreconditionRequired)?;
+    let group = group.document;
+    let resource = format!("group:{}", group.name);
+    let old = policy_store.group_named(&group.name).await;
+    let old_policies = old.as_ref().map(|g| g.policies.clone()).unwrap_or_default();
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("UpdateGroup", &resource);
+    require_attach(result, &old_policies, &group.policies)?;
+    check(validate_group(policy_store.inner(), &group).await)?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_group(group.clone()),
+        force,
+    )
+    .await?;
+    let change = Change {
+        base_revision: Some(base_revision),
+        ..change
+    };
+    if let Err(e) = policy_store.update_group(&group, &change).await {
+        let current = policy_store.group_named(&group.name).await;
+        let (kind, name) = (DocumentKind::Group, &group.name);
+        return Err(write_failed(policy_store, kind, name, base_revision, current, e).await);
+    }
+    audit.policy_change("UpdateGroup", &resource, old.as_ref(), Some(&group));
+    Ok(())
+}
+
+// TODO: Rather than getting the authorizer here, maybe derive a concrete
+//       AuthenticatedPolicyStore which wraps calls to the underlying store?
+#[put("/user?<force>", format = "application/json", data = "<user>")]
+async fn user_create(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    user: JsonBody<User>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let user = user.into_inner();
+    let resource = format!("user:{}", user.login_name);
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("CreateUser", &resource);
+    require_attach(result, &[], &user.policies)?;
+    check(validate_user(policy_store.inner(), &user).await)?;
+    // An existing user is left to the store to refuse.
+    if policy_store.user_named(&user.login_name).await.is_err() {
+        check_lock_out(
+            &Proposed::new(policy_store.inner()).with_user(user.clone()),
+            force,
+        )
+        .await?;
+    }
+    policy_store.create_user(&user, &change).await?;
+    audit.policy_change("CreateUser", &resource, None, Some(&user));
+    Ok(())
+}
+
+#[post("/user?<force>", format = "application/json", data = "<user>")]
+async fn user_update(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    if_match: IfMatch,
+    user: Revisioned<User>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let base_revision = if_match.0.or(user.revision);
+    let base_revision = base_revision.ok_or(Status::PreconditionRequired)?;
+    let user = user.document;
+    let resource = format!("user:{}", user.login_name);
+    let old = policy_store.user_named(&user.login_name).await.ok();
+    let old_policies = old.as_ref().map(|u| u.policies.clone()).unwrap_or_default();
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("UpdateUser", &resource);
+    require_attach(result, &old_policies, &user.policies)?;
+    check(validate_user(policy_store.inner(), &user).await)?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_user(user.clone()),
+        force,
+    )
+    .await?;
+    let change = Change {
+        base_revision: Some(base_revision),
+        ..change
+    };
+    if let Err(e) = policy_store.update_user(&user, &change).await {
+        let current = policy_store.user_named(&user.login_name).await.ok();
+        let (kind, name) = (DocumentKind::User, &user.login_name);
+        return Err(write_failed(policy_store, kind, name, base_revision, current, e).await);
+    }
+    audit.policy_change("UpdateUser", &resource, old.as_ref(), Some(&user));
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct GroupMembers {
+    members: Vec<PrincipalName>,
+}
+
+#[get("/group/<name>/members")]
+async fn group_members(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+) -> Result<Json<GroupMembers>, ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    let resource = format!("group:{name}");
+    // Either permission will do, so a denial is only recorded when neither
+    // is granted.
+    if !auth.permits("ManageGroupMembers", &resource) {
+        auth.require("ListGroups", &resource).ok()?;
+    }
+    policy_store
+        .group_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let members = policy_store
+        .group_members(name)
+        .await?
+        .into_iter()
+        .map(|u| u.login_name)
+        .collect();
+    Ok(Json(GroupMembers { members }))
+}
+
+/// Adds a user to or removes them from a group, auditing the change to the
+/// user's groups.
+async fn change_membership(
+    policy_store: &BoxedPolicyStore,
+    audit: &Audit,
+    change: &Change,
+    name: &PrincipalName,
+    login_name: &PrincipalName,
+    member: bool,
+) -> Result<(), PolicyStoreError> {
+    let before = policy_store.user_named(login_name).await.ok();
+    if member {
+        policy_store
+            .add_group_member(name, login_name, change)
+            .await?;
+    } else {
+        policy_store
+            .remove_group_member(name, login_name, change)
+            .await?;
+    }
+    let after = policy_store.user_named(login_name).await.ok();
+    audit.policy_change(
+        "ManageGroupMembers",
+        &format!("user:{login_name}"),
+        before.as_ref(),
+        after.as_ref(),
+    );
+    Ok(())
+}
+
+#[put("/group/<name>/member/<login_name>?<force>")]
+async fn group_add_member(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    name: &str,
+    login_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    let login_name = &PrincipalName::parse(login_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("ManageGroupMembers", &format!("group:{name}"))
         .ok()?;
     policy_store
-        .create_group(&group)
-        .map_err(|_| Status::BadRequest)
+        .group_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    // Joining a group with a Deny statement can lock a user out.
+    if let Ok(mut user) = policy_store.user_named(login_name).await {
+        if !user.groups.contains(name) {
+            user.groups.push(name.clone());
+        }
+        check_lock_out(&Proposed::new(policy_store.inner()).with_user(user), force).await?;
+    }
+    change_membership(policy_store, &audit, &change, name, login_name, true).await?;
+    Ok(())
 }
 
-#[post("/group", format = "application/json", data = "<group>")]
-fn group_update(
+#[delete("/group/<name>/member/<login_name>?<force>")]
+async fn group_remove_member(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-    group: Json<Group>,
-) -> Result<(), Status> {
-    let group = group.into_inner();
-    auth.require("UpdateGroup", &format!("group:{}", group.name))
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    name: &str,
+    login_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    let login_name = &PrincipalName::parse(login_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("ManageGroupMembers", &format!("group:{name}"))
+        .ok()?;
+    if let Ok(mut user) = policy_store.user_named(login_name).await {
+        user.groups.retain(|g| g != name);
+        check_lock_out(&Proposed::new(policy_store.inner()).with_user(user), force).await?;
+    }
+    change_membership(policy_store, &audit, &change, name, login_name, false).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct GroupMembersUpdate {
+    #[serde(default)]
+    add: Vec<PrincipalName>,
+    #[serde(default)]
+    remove: Vec<PrincipalName>,
+}
+
+#[post(
+    "/group/<name>/members?<force>",
+    format = "application/json",
+    data = "<update>"
+)]
+async fn group_update_members(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    name: &str,
+    update: JsonBody<GroupMembersUpdate>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("ManageGroupMembers", &format!("group:{name}"))
         .ok()?;
     policy_store
-        .update_group(&group)
-        .map_err(|_| Status::BadRequest)
+        .group_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    // Check every user up front so a bad name doesn't leave a partial update.
+    let mut proposed = Proposed::new(policy_store.inner());
+    for login_name in update.add.iter().chain(&update.remove) {
+        let mut user = proposed.user_named(login_name).await?;
+        if update.remove.contains(login_name) {
+            user.groups.retain(|g| g != name);
+        } else if !user.groups.contains(name) {
+            user.groups.push(name.clone());
+        }
+        proposed = proposed.with_user(user);
+    }
+    check_lock_out(&proposed, force).await?;
+    for login_name in &update.add {
+        change_membership(policy_store, &audit, &change, name, login_name, true).await?;
+    }
+    for login_name in &update.remove {
+        change_membership(policy_store, &audit, &change, name, login_name, false).await?;
+    }
+    Ok(())
 }
 
-// TODO: Should be able to load the PolicyStore trait from the guard.
-// TODO: Rather than getting the authorizer here, maybe derive a concrete
-//       AuthenticatedPolicyStore which wraps calls to the underlying store?
-#[put("/user", format = "application/json", data = "<user>")]
-fn user_create(
+#[put("/user/<login_name>/policy/<policy_name>?<force>")]
+async fn user_attach_policy(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-    user: Json<User>,
-) -> Result<(), Status> {
-    let user = user.into_inner();
-    auth.require("CreateUser", &format!("user:{}", user.login_name))
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    login_name: &str,
+    policy_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdateUser", &format!("user:{login_name}"))
+        .require("AttachPolicy", &format!("policy:{policy_name}"))
         .ok()?;
     policy_store
-        .create_user(&user)
-        .map_err(|_| Status::BadRequest)
+        .policy_named(policy_name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let before = policy_store.user_named(login_name).await?;
+    let mut user = before.clone();
+    if user.policies.iter().any(|p| p == policy_name) {
+        return Ok(());
+    }
+    user.policies.push(String::from(policy_name));
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_user(user.clone()),
+        force,
+    )
+    .await?;
+    policy_store.update_user(&user, &change).await?;
+    audit.policy_change(
+        "UpdateUser",
+        &format!("user:{login_name}"),
+        Some(&before),
+        Some(&user),
+    );
+    Ok(())
 }
 
-#[post("/user", format = "application/json", data = "<user>")]
-fn user_update(
+#[delete("/user/<login_name>/policy/<policy_name>?<force>")]
+async fn user_detach_policy(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
-    user: Json<User>,
-) -> Result<(), Status> {
-    let user = user.into_inner();
-    auth.require("UpdateUser", &format!("user:{}", user.login_name))
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    login_name: &str,
+    policy_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdateUser", &format!("user:{login_name}"))
+        .require("AttachPolicy", &format!("policy:{policy_name}"))
+        .ok()?;
+    let before = policy_store.user_named(login_name).await?;
+    let mut user = before.clone();
+    user.policies.retain(|p| p != policy_name);
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_user(user.clone()),
+        force,
+    )
+    .await?;
+    policy_store.update_user(&user, &change).await?;
+    audit.policy_change(
+        "UpdateUser",
+        &format!("user:{login_name}"),
+        Some(&before),
+        Some(&user),
+    );
+    Ok(())
+}
+
+#[put("/group/<group_name>/policy/<policy_name>?<force>")]
+async fn group_attach_policy(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    group_name: &str,
+    policy_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let group_name = &PrincipalName::parse(group_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdateGroup", &format!("group:{group_name}"))
+        .require("AttachPolicy", &format!("policy:{policy_name}"))
+        .ok()?;
+    policy_store
+        .policy_named(policy_name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let before = policy_store
+        .group_named(group_name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let mut group = before.clone();
+    if group.policies.iter().any(|p| p == policy_name) {
+        return Ok(());
+    }
+    group.policies.push(String::from(policy_name));
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_group(group.clone()),
+        force,
+    )
+    .await?;
+    policy_store.update_group(&group, &change).await?;
+    audit.policy_change(
+        "UpdateGroup",
+        &format!("group:{group_name}"),
+        Some(&before),
+        Some(&group),
+    );
+    Ok(())
+}
+
+#[delete("/group/<group_name>/policy/<policy_name>?<force>")]
+async fn group_detach_policy(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    group_name: &str,
+    policy_name: &str,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let group_name = &PrincipalName::parse(group_name)?;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdateGroup", &format!("group:{group_name}"))
+        .require("AttachPolicy", &format!("policy:{policy_name}"))
+        .ok()?;
+    let before = policy_store
+        .group_named(group_name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let mut group = before.clone();
+    group.policies.retain(|p| p != policy_name);
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_group(group.clone()),
+        force,
+    )
+    .await?;
+    policy_store.update_group(&group, &change).await?;
+    audit.policy_change(
+        "UpdateGroup",
+        &format!("group:{group_name}"),
+        Some(&before),
+        Some(&group),
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct PolicyList {
+    policies: Vec<ManagedPolicy>,
+}
+
+#[get("/policies")]
+async fn policy_list(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+) -> Result<Json<PolicyList>, ApiError> {
+    auth.require("ListPolicies", &"").ok()?;
+    let policies = policy_store.list_policies().await?;
+    Ok(Json(PolicyList { policies }))
+}
+
+#[get("/policy/<name>")]
+async fn policy_get(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+) -> Result<Json<ManagedPolicy>, ApiError> {
+    auth.require("ListPolicies", &format!("policy:{name}"))
         .ok()?;
+    let policy = policy_store
+        .policy_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    Ok(Json(policy))
+}
+
+#[put("/policy", format = "application/json", data = "<policy>")]
+async fn policy_create(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    policy: JsonBody<ManagedPolicy>,
+) -> Result<(), ApiError> {
+    let policy = policy.into_inner();
+    let resource = format!("policy:{}", policy.name);
+    auth.require("CreatePolicy", &resource).ok()?;
+    check(validate_policy(&policy))?;
+    policy_store.create_policy(&policy, &change).await?;
+    // Read it back to pick up the version assigned by the store.
+    let after = policy_store.policy_named(&policy.name).await;
+    audit.policy_change("CreatePolicy", &resource, None, after.as_ref());
+    Ok(())
+}
+
+#[post("/policy?<force>", format = "application/json", data = "<policy>")]
+async fn policy_update(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    policy: JsonBody<ManagedPolicy>,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let policy = policy.into_inner();
+    let resource = format!("policy:{}", policy.name);
+    let before = policy_store.policy_named(&policy.name).await;
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdatePolicy", &resource).ok()?;
+    check(validate_policy(&policy))?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_policy(policy.clone()),
+        force,
+    )
+    .await?;
+    policy_store.update_policy(&policy, &change).await?;
+    let after = policy_store.policy_named(&policy.name).await;
+    audit.policy_change("UpdatePolicy", &resource, before.as_ref(), after.as_ref());
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct History {
+    revisions: Vec<Revision>,
+}
+
+async fn history(
+    policy_store: &BoxedPolicyStore,
+    kind: DocumentKind,
+    name: &str,
+) -> Result<Json<History>, ApiError> {
+    let revisions = policy_store.history(kind, name).await?;
+    Ok(Json(History { revisions }))
+}
+
+async fn revision_document<T: DeserializeOwned>(
+    policy_store: &BoxedPolicyStore,
+    kind: DocumentKind,
+    name: &str,
+    revision: u64,
+) -> Result<T, ApiError> {
+    let document = policy_store
+        .history(kind, name)
+        .await?
+        .into_iter()
+        .find(|r| r.revision == revision)
+        .ok_or(Status::NotFound)?
+        .document;
+    json::from_value(document).map_err(|_| Status::InternalServerError.into())
+}
+
+/// The fields which changed between two revisions of a document. `to`
+/// defaults to the latest revision.
+async fn revision_diff(
+    policy_store: &BoxedPolicyStore,
+    kind: DocumentKind,
+    name: &str,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Json<BTreeMap<String, FieldChange>>, ApiError> {
+    let revisions = policy_store.history(kind, name).await?;
+    let find = |n: u64| revisions.iter().find(|r| r.revision == n);
+    let from = find(from).ok_or(Status::NotFound)?;
+    let to = match to {
+        Some(n) => find(n).ok_or(Status::NotFound)?,
+        None => revisions.last().ok_or(Status::NotFound)?,
+    };
+    Ok(Json(audit::diff(Some(&from.document), Some(&to.document))))
+}
+
+fn rollback_change(change: Change, revision: u64) -> Change {
+    Change {
+        reason: change
+            .reason
+            .or_else(|| Some(format!("Rollback to revision {revision}"))),
+        ..change
+    }
+}
+
+#[get("/user/<login_name>/history")]
+async fn user_history(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    login_name: &str,
+) -> Result<Json<History>, ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    auth.require("ListUsers", &format!("user:{login_name}"))
+        .ok()?;
+    history(policy_store, DocumentKind::User, login_name).await
+}
+
+#[get("/user/<login_name>/diff?<from>&<to>")]
+async fn user_diff(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    login_name: &str,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Json<BTreeMap<String, FieldChange>>, ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    auth.require("ListUsers", &format!("user:{login_name}"))
+        .ok()?;
+    revision_diff(policy_store, DocumentKind::User, login_name, from, to).await
+}
+
+#[post("/user/<login_name>/rollback/<revision>?<force>")]
+async fn user_rollback(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    login_name: &str,
+    revision: u64,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
+    let resource = format!("user:{login_name}");
+    // Authorize before loading anything, so that whether the user or the
+    // revision exists isn't given away.
+    if !auth.is_allowed("UpdateUser", &resource) {
+        return Err(Status::Forbidden.into());
+    }
+    let old = policy_store.user_named(login_name).await?;
+    let user: User =
+        revision_document(policy_store, DocumentKind::User, login_name, revision).await?;
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("UpdateUser", &resource);
+    require_attach(result, &old.policies, &user.policies)?;
+    check(validate_user(policy_store.inner(), &user).await)?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_user(user.clone()),
+        force,
+    )
+    .await?;
+    policy_store
+        .update_user(&user, &rollback_change(change, revision))
+        .await?;
+    audit.policy_change("UpdateUser", &resource, Some(&old), Some(&user));
+    Ok(())
+}
+
+#[get("/group/<name>/history")]
+async fn group_history(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+) -> Result<Json<History>, ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    auth.require("ListGroups", &format!("group:{name}")).ok()?;
+    history(policy_store, DocumentKind::Group, name).await
+}
+
+#[get("/group/<name>/diff?<from>&<to>")]
+async fn group_diff(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Json<BTreeMap<String, FieldChange>>, ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    auth.require("ListGroups", &format!("group:{name}")).ok()?;
+    revision_diff(policy_store, DocumentKind::Group, name, from, to).await
+}
+
+#[post("/group/<name>/rollback/<revision>?<force>")]
+async fn group_rollback(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    name: &str,
+    revision: u64,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let name = &PrincipalName::parse(name)?;
+    let resource = format!("group:{name}");
+    if !auth.is_allowed("UpdateGroup", &resource) {
+        return Err(Status::Forbidden.into());
+    }
+    let old = policy_store
+        .group_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let group: Group = revision_document(policy_store, DocumentKind::Group, name, revision).await?;
+    let force = may_force(policy_store, &auth, force).await;
+    let result = auth.require("UpdateGroup", &resource);
+    require_attach(result, &old.policies, &group.policies)?;
+    check(validate_group(policy_store.inner(), &group).await)?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_group(group.clone()),
+        force,
+    )
+    .await?;
+    policy_store
+        .update_group(&group, &rollback_change(change, revision))
+        .await?;
+    audit.policy_change("UpdateGroup", &resource, Some(&old), Some(&group));
+    Ok(())
+}
+
+#[get("/policy/<name>/history")]
+async fn policy_history(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+) -> Result<Json<History>, ApiError> {
+    auth.require("ListPolicies", &format!("policy:{name}"))
+        .ok()?;
+    history(policy_store, DocumentKind::Policy, name).await
+}
+
+#[get("/policy/<name>/diff?<from>&<to>")]
+async fn policy_diff(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    name: &str,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Json<BTreeMap<String, FieldChange>>, ApiError> {
+    auth.require("ListPolicies", &format!("policy:{name}"))
+        .ok()?;
+    revision_diff(policy_store, DocumentKind::Policy, name, from, to).await
+}
+
+#[post("/policy/<name>/rollback/<revision>?<force>")]
+async fn policy_rollback(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    name: &str,
+    revision: u64,
+    force: Option<bool>,
+) -> Result<(), ApiError> {
+    let resource = format!("policy:{name}");
+    let force = may_force(policy_store, &auth, force).await;
+    auth.require("UpdatePolicy", &resource).ok()?;
+    let old = policy_store
+        .policy_named(name)
+        .await
+        .ok_or(Status::NotFound)?;
+    let policy: ManagedPolicy =
+        revision_document(policy_store, DocumentKind::Policy, name, revision).await?;
+    check(validate_policy(&policy))?;
+    check_lock_out(
+        &Proposed::new(policy_store.inner()).with_policy(policy.clone()),
+        force,
+    )
+    .await?;
     policy_store
-        .update_user(&user)
-        .map_err(|_| Status::BadRequest)
+        .update_policy(&policy, &rollback_change(change, revision))
+        .await?;
+    let after = policy_store.policy_named(name).await;
+    audit.policy_change("UpdatePolicy", &resource, Some(&old), after.as_ref());
+    Ok(())
 }
 
 #[post("/user/<login_name>/password", data = "<password>")]
-fn user_set_password(
+async fn user_set_password(
     auth: RequestAuthorizor,
-    policy_store: &State<FilePolicyStore>,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
     login_name: &str,
     password: &str,
-) -> Result<(), Status> {
+) -> Result<(), ApiError> {
+    let login_name = &PrincipalName::parse(login_name)?;
     let password = if password.is_empty() {
         None
     } else {
         Some(password)
     };
-    auth.require("SetUserPassword", &format!("user:{login_name}"))
+    let resource = format!("user:{login_name}");
+    auth.require("SetUserPassword", &resource).ok()?;
+    policy_store.set_user_password(login_name, password).await?;
+    // The password itself is never recorded.
+    audit.policy_change::<User>("SetUserPassword", &resource, None, None);
+    Ok(())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct SimulationContext {
+    client_ip: Option<IpAddr>,
+    #[serde(default)]
+    https: bool,
+    content_length: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SimulationRequest {
+    user: Option<PrincipalName>,
+    #[serde(default)]
+    groups: Vec<PrincipalName>,
+    action: String,
+    resource: String,
+    #[serde(default)]
+    context: SimulationContext,
+}
+
+#[post("/policy/simulate", format = "application/json", data = "<request>")]
+async fn policy_simulate(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    request: JsonBody<SimulationRequest>,
+) -> Result<Json<Explanation>, ApiError> {
+    let request = request.into_inner();
+    let (principal, mut resources) = match (request.user, request.groups) {
+        (Some(login_name), groups) if groups.is_empty() => (
+            policy_store.user_named(&login_name).await?,
+            vec![format!("user:{login_name}")],
+        ),
+        (None, groups) if !groups.is_empty() => {
+            let resources = groups.iter().map(|g| format!("group:{g}")).collect();
+            let principal = User {
+                login_name: PrincipalName::anonymous(),
+                full_name: None,
+                groups,
+                policy_statements: Vec::new(),
+                policies: Vec::new(),
+            };
+            (principal, resources)
+        }
+        _ => return Err(Status::BadRequest.into()),
+    };
+    let first = resources.remove(0);
+    resources
+        .iter()
+        .fold(auth.require("SimulatePolicy", &first), |r, res| {
+            r.require("SimulatePolicy", res)
+        })
         .ok()?;
-    policy_store
-        .set_user_password(login_name, password)
-        .map_err(|_| Status::BadRequest)
+    let context = RequestContext {
+        client_ip: request.context.client_ip,
+        https: request.context.https,
+        content_length: request.context.content_length,
+        ..RequestContext::unknown()
+    };
+    let simulated = RequestAuthorizor::for_user(policy_store.inner(), principal, context).await;
+    Ok(Json(simulated.explain(&request.action, &request.resource)))
 }
 
-fn add_session_cookie(cookies: &CookieJar, username: &str) -> Result<(), Status> {
+fn add_session_cookie(cookies: &CookieJar, username: &PrincipalName) -> Result<(), Status> {
     let exp = now_as_secs()
         .map(|now| now + 3600)
         .map_err(|_| Status::InternalServerError)?;
     let session_cookie = SessionCookie {
-        username: String::from(username),
+        username: username.clone(),
         expires: exp,
     };
     let session_cookie =
@@ -174,64 +1183,183 @@ struct LoginRequestForm<'r> {
 }
 
 #[post("/login", data = "<login>")]
-fn login(
-    policy_store: &State<FilePolicyStore>,
+async fn login(
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
     cookies: &CookieJar<'_>,
     login: Form<LoginRequestForm<'_>>,
-) -> Result<Json<User>, Status> {
-    let user = policy_store
-        .authenticate_user(login.login_name, login.password)
-        .map_err(|_| Status::Unauthorized)?;
+) -> Result<Json<User>, ApiError> {
+    let authenticated = match PrincipalName::parse(login.login_name) {
+        Ok(login_name) => {
+            policy_store
+                .authenticate_user(&login_name, login.password)
+                .await
+        }
+        // No user can have an invalid name.
+        Err(_) => Ok(None),
+    };
+    let user = match authenticated {
+        Ok(user) => user,
+        Err(PolicyStoreError::NotFound(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    audit.login(login.login_name, user.is_some());
+    let user = user.ok_or(Status::Unauthorized)?;
     add_session_cookie(cookies, &user.login_name)?;
     Ok(Json(user))
 }
 
 #[get("/logout")]
-fn logout(cookies: &CookieJar<'_>) -> &'static str {
+fn logout(audit: Audit, cookies: &CookieJar<'_>) -> &'static str {
+    audit.logout();
     cookies.remove_private(Cookie::named("session"));
     "Ok"
 }
 
-#[get("/file/<_..>")]
-async fn get_file_data(file: RequestedRegularFileDataReadable) -> Result<NamedFile, Status> {
-    NamedFile::open(file.real_path)
+async fn download(
+    audit: &Audit,
+    real_path: &Path,
+    logical_path: &Path,
+) -> Result<NamedFile, ApiError> {
+    let file = NamedFile::open(real_path)
         .await
         .map_err(|e| match e.kind() {
             ErrorKind::NotFound => Status::NotFound,
             _ => Status::InternalServerError,
-        })
+        })?;
+    let size = file.file().metadata().await.map(|m| m.len()).ok();
+    audit.file("file:Read", logical_path, size);
+    Ok(file)
+}
+
+#[get("/file/<_..>")]
+async fn get_file_data(
+    audit: Audit,
+    file: RequestedRegularFileDataReadable,
+) -> Result<NamedFile, ApiError> {
+    download(&audit, &file.real_path, &file.logical_path).await
 }
 
 #[put("/mkdir/<_..>")]
 async fn mkdir(
     file: RequestedFileDataWritable,
     auth: RequestAuthorizor,
-) -> Result<&'static str, Status> {
+    audit: Audit,
+) -> Result<&'static str, ApiError> {
     let parent_path = file.logical_path.parent().ok_or(Status::BadRequest)?;
     auth.require("file:Write", &parent_path).ok()?;
     fs::create_dir(file.real_path)
         .await
-        .and(Ok("Ok"))
-        .or(Err(Status::InternalServerError))
+        .or(Err(Status::InternalServerError))?;
+    audit.file("file:Write", &file.logical_path, None);
+    Ok("Ok")
 }
 
 #[put("/file/<_..>", data = "<file>")]
 async fn upload(
     config: &State<Config>,
+    audit: Audit,
     path: RequestedFileDataWritable,
+    file: TempFile<'_>,
+) -> Result<&'static str, ApiError> {
+    store_upload(config, &audit, &path.real_path, &path.logical_path, file).await
+}
+
+async fn store_upload(
+    config: &Config,
+    audit: &Audit,
+    real_path: &Path,
+    logical_path: &Path,
     mut file: TempFile<'_>,
-) -> Result<&'static str, ()> {
-    file.move_copy_to(&path.real_path).await.map_err(|_| ())?;
+) -> Result<&'static str, ApiError> {
+    let size = file.len();
+    file.move_copy_to(real_path).await.map_err(|e| {
+        warn!("Error storing upload to {real_path:?}: {e}");
+        ApiError::new(
+            Status::InternalServerError,
+            "InternalError",
+            "The upload could not be stored",
+        )
+    })?;
+    audit.file("file:Write", logical_path, Some(size));
     hook::run_hooks(
         &config.hook_shell,
         &config.hook_root,
         "after_upload",
-        vec![("HOOK_UPLOAD_REAL_PATH", &path.real_path)],
+        vec![("HOOK_UPLOAD_REAL_PATH", real_path)],
     )
-    .map_err(|_| ())?;
+    .map_err(|_| {
+        ApiError::new(
+            Status::InternalServerError,
+            "HookFailed",
+            "The upload was stored but the after_upload hook failed",
+        )
+    })?;
     Ok("Ok")
 }
 
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SignUrlRequest {
+    path: PathBuf,
+    method: SignedMethod,
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SignedUrl {
+    url: String,
+    expires: u64,
+}
+
+#[post("/sign", format = "application/json", data = "<request>")]
+fn sign_url(
+    auth: RequestAuthorizor,
+    config: &State<Config>,
+    signer: &State<UrlSigner>,
+    request: JsonBody<SignUrlRequest>,
+) -> Result<Json<SignedUrl>, ApiError> {
+    let request = request.into_inner();
+    let file =
+        files::realize(&config.file_root, &request.path, false).map_err(|_| Status::BadRequest)?;
+    let logical_path = file.logical_path.to_str().ok_or(Status::BadRequest)?;
+    let login_name = String::from(auth.username());
+    auth.require(request.method.action(), &logical_path).ok()?;
+    let ttl = request
+        .expires_in
+        .unwrap_or(signed_url::DEFAULT_TTL_SECS)
+        .min(signed_url::MAX_TTL_SECS);
+    let expires = now_as_secs()
+        .map(|now| now + ttl)
+        .map_err(|_| Status::InternalServerError)?;
+    let claims = SignedUrlClaims {
+        method: request.method,
+        logical_path,
+        expires,
+        login_name: &login_name,
+    };
+    let url = claims.to_url(&signer.sign(&claims));
+    Ok(Json(SignedUrl { url, expires }))
+}
+
+#[get("/signed/<_..>")]
+async fn signed_get_file(audit: Audit, file: SignedFileRequest) -> Result<NamedFile, ApiError> {
+    let audit = audit.attributed_to(&file.login_name);
+    download(&audit, &file.real_path, &file.logical_path).await
+}
+
+#[put("/signed/<_..>", data = "<data>")]
+async fn signed_upload(
+    config: &State<Config>,
+    audit: Audit,
+    file: SignedFileRequest,
+    data: TempFile<'_>,
+) -> Result<&'static str, ApiError> {
+    let audit = audit.attributed_to(&file.login_name);
+    store_upload(config, &audit, &file.real_path, &file.logical_path, data).await
+}
+
 #[get("/meta/<_..>")]
 async fn get_file_meta(meta: FileMetadata) -> Json<FileMetadata> {
     Json(meta)
@@ -242,6 +1370,142 @@ async fn get_file_children(children: FileChildren) -> Json<FileChildren> {
     Json(children)
 }
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AuditEvents {
+    events: Vec<AuditEvent>,
+}
+
+const DEFAULT_AUDIT_LIMIT: usize = 1000;
+
+#[derive(FromForm)]
+struct AuditQueryForm {
+    user: Option<String>,
+    action: Option<String>,
+    path: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Audit events matching all of the given criteria, oldest first. At most
+/// `limit` of the most recent matches are returned.
+#[get("/audit?<query..>")]
+async fn audit_query(
+    auth: RequestAuthorizor,
+    audit: Audit,
+    query: AuditQueryForm,
+) -> Result<Json<AuditEvents>, ApiError> {
+    auth.require("ReadAuditLog", &"audit").ok()?;
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    let query = AuditQuery {
+        user: query.user,
+        action: query.action,
+        path_prefix: query.path,
+        since: query.since,
+        until: query.until,
+    };
+    let mut events = audit.query(query).await.map_err(|e| {
+        warn!("Error querying the audit log: {e}");
+        ApiError::new(
+            Status::InternalServerError,
+            "InternalError",
+            "The audit log could not be read",
+        )
+    })?;
+    events.drain(..events.len().saturating_sub(limit));
+    Ok(Json(AuditEvents { events }))
+}
+
+/// Every user, group and policy as a bundle for `import`. Password hashes are
+/// only included with `secrets`, which needs permission of its own.
+#[get("/export?<secrets>&<format>")]
+async fn policy_store_export(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    secrets: Option<bool>,
+    format: Option<BundleFormat>,
+) -> Result<(ContentType, String), ApiError> {
+    let secrets = secrets.unwrap_or(false);
+    let required = auth.require("ExportPolicyStore", &"policy-store");
+    if secrets {
+        required.require("ExportPolicyStore", &"policy-store:secrets")
+    } else {
+        required
+    }
+    .ok()?;
+    let format = format.unwrap_or_default();
+    let bundle = bundle::export(policy_store.inner(), secrets).await?;
+    let content_type = match format {
+        BundleFormat::Json => ContentType::JSON,
+        BundleFormat::Yaml => ContentType::new("application", "yaml"),
+    };
+    Ok((content_type, bundle.to_string(format)?))
+}
+
+/// The largest bundle `import` reads unless `limits.bundle` says otherwise.
+const DEFAULT_BUNDLE_LIMIT_MIB: u64 = 10;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ImportReport {
+    applied: bool,
+    changes: Vec<PlannedChange>,
+}
+
+/// Imports a bundle made by `export`, in JSON or YAML. The bundle is checked
+/// as a whole before anything is written, and with `dry_run` the changes it
+/// would make are given without making them. A bundle with password hashes
+/// needs permission for `policy-store:secrets` as well.
+#[post("/import?<mode>&<dry_run>&<force>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn policy_store_import(
+    auth: RequestAuthorizor,
+    policy_store: &State<BoxedPolicyStore>,
+    audit: Audit,
+    change: Change,
+    limits: &Limits,
+    data: Data<'_>,
+    mode: Option<ImportMode>,
+    dry_run: Option<bool>,
+    force: Option<bool>,
+) -> Result<Json<ImportReport>, ApiError> {
+    let force = may_force(policy_store, &auth, force).await;
+    if !auth.is_allowed("ImportPolicyStore", &"policy-store") {
+        return Err(Status::Forbidden.into());
+    }
+    let limit = limits
+        .get("bundle")
+        .unwrap_or_else(|| DEFAULT_BUNDLE_LIMIT_MIB.mebibytes());
+    let text = data
+        .open(limit)
+        .into_string()
+        .await
+        .map_err(|_| Status::BadRequest)?;
+    if !text.is_complete() {
+        return Err(Status::PayloadTooLarge.into());
+    }
+    let bundle = Bundle::parse(&text)?;
+    // Setting password hashes takes the same permission as reading them.
+    if bundle.users.iter().any(|u| u.password_hash.is_some()) {
+        auth.require("ImportPolicyStore", &"policy-store:secrets")
+            .ok()?;
+    }
+    let mode = mode.unwrap_or_default();
+    let plan = bundle::plan(policy_store.inner(), &bundle, mode, force).await?;
+    let dry_run = dry_run.unwrap_or(false);
+    if !dry_run {
+        bundle::apply(policy_store.inner(), &bundle, &plan, &change, |planned| {
+            audit.import(planned)
+        })
+        .await?;
+    }
+    Ok(Json(ImportReport {
+        applied: !dry_run,
+        changes: plan.changes,
+    }))
+}
+
 // TODO: Add a configuration for the SPA root rather than relying on the CWD.
 #[get("/<file..>")]
 async fn spa_files(mut file: PathBuf) -> Option<NamedFile> {
@@ -261,19 +1525,58 @@ async fn spa_files(mut file: PathBuf) -> Option<NamedFile> {
 
 pub fn launch() -> Rocket<Build> {
     let rocket = rocket::build();
+    let config: Config = rocket
+        .figment()
+        .extract()
+        .expect("Error loading configuration.");
+    let policy_store = store::open(&config).expect("Error loading policy store");
+    launch_with(rocket, policy_store)
+}
+
+/// Builds the app around the given policy store rather than the one selected by
+/// the configuration, e.g. an in-memory store for tests.
+pub fn launch_with(rocket: Rocket<Build>, policy_store: BoxedPolicyStore) -> Rocket<Build> {
     let figment = rocket.figment();
     let config: Config = figment.extract().expect("Error loading configuration.");
-    let policy_store =
-        FilePolicyStore::new(&config.policy_store_root).expect("Error loading policy store");
+    let url_signer = UrlSigner::from_figment(figment);
+    let audit_log = AuditLog::new(
+        &config.audit_log,
+        config.audit_log_max_bytes,
+        config.audit_log_keep,
+    )
+    .expect("Error opening audit log");
+    let (policy_store, declared_policy) = match &config.declared_policy {
+        Some(path) => {
+            let shared = Arc::new(policy_store);
+            let declared_policy = DeclaredPolicy::new(
+                path.clone(),
+                config.declared_policy_prune,
+                shared.clone(),
+                audit_log.clone(),
+            );
+            (Box::new(shared) as BoxedPolicyStore, Some(declared_policy))
+        }
+        None => (policy_store, None),
+    };
 
+    let rocket = match declared_policy {
+        Some(declared_policy) => rocket.attach(declared_policy),
+        None => rocket,
+    };
     rocket
         .manage(config)
         .manage(policy_store)
+        .manage(url_signer)
+        .manage(audit_log)
         .mount(
             "/api",
             routes![
                 health,
                 user_current,
+                user_current_permissions,
+                user_current_groups,
+                user_groups,
+                user_current_permissions_batch,
                 login,
                 logout,
                 get_file_data,
@@ -281,14 +1584,45 @@ pub fn launch() -> Rocket<Build> {
                 get_file_children,
                 mkdir,
                 upload,
+                sign_url,
+                signed_get_file,
+                signed_upload,
                 user_list,
+                user_get,
                 user_create,
                 user_set_password,
                 user_update,
                 group_list,
+                group_get,
                 group_create,
-                group_update
+                group_update,
+                group_members,
+                group_add_member,
+                group_remove_member,
+                group_update_members,
+                user_attach_policy,
+                user_detach_policy,
+                group_attach_policy,
+                group_detach_policy,
+                policy_list,
+                policy_get,
+                policy_create,
+                policy_update,
+                policy_simulate,
+                user_history,
+                user_diff,
+                user_rollback,
+                group_history,
+                group_diff,
+                group_rollback,
+                policy_history,
+                policy_diff,
+                policy_rollback,
+                audit_query,
+                policy_store_export,
+                policy_store_import
             ],
         )
+        .register("/api", error::catchers())
         .mount("/", routes![spa_files])
 }
@@ -0,0 +1,591 @@
+use super::*;
+use auth::store::sqlite::SqlitePolicyStore;
+use auth::store::{Blocking, BlockingPolicyStore};
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header};
+use rocket::local::blocking::Client;
+use std::env;
+
+fn client(policy_store: SqlitePolicyStore) -> Client {
+    configured_client(policy_store, |figment| figment)
+}
+
+/// A client for an app whose configuration is changed by `configure`.
+fn configured_client(
+    policy_store: SqlitePolicyStore,
+    configure: impl FnOnce(Figment) -> Figment,
+) -> Client {
+    let dir = env::temp_dir().join(format!("swaf-app-{}", std::process::id()));
+    let figment = rocket::Config::figment()
+        .merge(("file_root", dir.join("files")))
+        .merge(("policy_store_root", dir.join("policy")))
+        .merge(("hook_root", dir.join("hooks")))
+        .merge(("hook_shell", "sh"))
+        .merge(("audit_log", dir.join("audit.log")));
+    let figment = configure(figment);
+    let rocket = launch_with(
+        rocket::custom(figment),
+        Box::new(Blocking::new(policy_store)),
+    );
+    Client::tracked(rocket).unwrap()
+}
+
+fn logged_in_client(statements: &str) -> Client {
+    configured_logged_in_client(statements, |figment| figment)
+}
+
+fn configured_logged_in_client(
+    statements: &str,
+    configure: impl FnOnce(Figment) -> Figment,
+) -> Client {
+    let policy_store = SqlitePolicyStore::open_in_memory().unwrap();
+    let dan = User {
+        login_name: "dan".parse().unwrap(),
+        full_name: None,
+        groups: vec![],
+        policy_statements: json::from_str(statements).unwrap(),
+        policies: vec![],
+    };
+    policy_store.create_user(&dan, &Change::default()).unwrap();
+    policy_store
+        .set_user_password(&"dan".parse().unwrap(), Some("pw"))
+        .unwrap();
+    let client = configured_client(policy_store, configure);
+
+    let status = client
+        .post("/api/login")
+        .header(ContentType::Form)
+        .body("login_name=dan&password=pw")
+        .dispatch()
+        .status();
+    assert_eq!(Status::Ok, status);
+    client
+}
+
+#[test]
+fn test_app_uses_the_given_policy_store() {
+    let client = logged_in_client("[]");
+    let current = client.get("/api/user/current").dispatch();
+    assert_eq!("dan", current.into_json::<User>().unwrap().login_name);
+}
+
+#[test]
+fn test_policy_store_errors_map_to_statuses() {
+    let client = logged_in_client(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#);
+
+    let missing = client.get("/api/user/nobody").dispatch();
+    assert_eq!(Status::NotFound, missing.status());
+    let body = missing.into_json::<json::Value>().unwrap();
+    assert_eq!("NotFound", body["code"]);
+    assert_eq!("user 'nobody' does not exist", body["message"]);
+
+    let existing = client
+        .put("/api/user")
+        .header(ContentType::JSON)
+        .body(r#"{"login_name": "dan", "groups": [], "policy_statements": []}"#)
+        .dispatch();
+    assert_eq!(Status::Conflict, existing.status());
+    let body = existing.into_json::<json::Value>().unwrap();
+    assert_eq!("AlreadyExists", body["code"]);
+}
+
+#[test]
+fn test_errors_tell_the_client_what_went_wrong() {
+    let client = client(SqlitePolicyStore::open_in_memory().unwrap());
+    let anonymous = client.get("/api/user/current").dispatch();
+    assert_eq!(Status::Unauthorized, anonymous.status());
+    let body = anonymous.into_json::<json::Value>().unwrap();
+    assert_eq!("NotLoggedIn", body["code"]);
+    assert_eq!("You are not logged in", body["message"]);
+
+    let client = logged_in_client("[]");
+    let denied = client.get("/api/users").dispatch();
+    assert_eq!(Status::Forbidden, denied.status());
+    assert_eq!(
+        "AccessDenied",
+        denied.into_json::<json::Value>().unwrap()["code"]
+    );
+
+    let missing = client.get("/api/no/such/route").dispatch();
+    assert_eq!(Status::NotFound, missing.status());
+    assert_eq!(
+        "NotFound",
+        missing.into_json::<json::Value>().unwrap()["code"]
+    );
+
+    let client = logged_in_client(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#);
+    let invalid = client
+        .put("/api/user")
+        .header(ContentType::JSON)
+        .body(r#"{"login_name": "amy", "groups": ["nobody"], "policy_statements": []}"#)
+        .dispatch();
+    assert_eq!(Status::UnprocessableEntity, invalid.status());
+    let body = invalid.into_json::<json::Value>().unwrap();
+    assert_eq!("Invalid", body["code"]);
+    assert_eq!("groups[0]", body["details"]["errors"][0]["field"]);
+
+    // Statuses without a catcher of their own get the same kind of body.
+    let unbased = client
+        .post("/api/user")
+        .header(ContentType::JSON)
+        .body(r#"{"login_name": "dan", "groups": [], "policy_statements": []}"#)
+        .dispatch();
+    assert_eq!(Status::PreconditionRequired, unbased.status());
+    let body = unbased.into_json::<json::Value>().unwrap();
+    assert_eq!("PreconditionRequired", body["code"]);
+    assert_eq!("The revision being updated is required", body["message"]);
+}
+
+#[test]
+fn test_unreadable_documents_name_the_field_at_fault() {
+    let client = logged_in_client(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#);
+    for (statement, field) in [
+        (
+            r#"{"effect": "Allow", "actions": ["*"], "not_actions": ["Login"], "resources": ["*"]}"#,
+            "policy_statements[0]",
+        ),
+        (
+            r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"], "conditions": {"https": true}}"#,
+            "policy_statements[0].conditions.https",
+        ),
+        (
+            r#"{"version": 3, "effect": "Allow", "actions": ["*"], "resources": ["*"]}"#,
+            "policy_statements[0].version",
+        ),
+        (
+            r#"{"effect": "Allow", "actions": ["*"], "resources": ["*"],
+                "conditions": {"time_window": {"utc_offset": "10:00"}}}"#,
+            "policy_statements[0].conditions.time_window.utc_offset",
+        ),
+    ] {
+        let user =
+            format!(r#"{{"login_name": "amy", "groups": [], "policy_statements": [{statement}]}}"#);
+        let created = client
+            .put("/api/user")
+            .header(ContentType::JSON)
+            .body(&user)
+            .dispatch();
+        // Updates hold the revision alongside the document.
+        let updated = client
+            .post("/api/user")
+            .header(ContentType::JSON)
+            .body(user.replacen('{', r#"{"revision": 1, "#, 1))
+            .dispatch();
+        for response in [created, updated] {
+            assert_eq!(Status::UnprocessableEntity, response.status(), "{user}");
+            let body = response.into_json::<json::Value>().unwrap();
+            assert_eq!("Invalid", body["code"]);
+            assert_eq!(field, body["details"]["errors"][0]["field"], "{user}");
+        }
+    }
+
+    let malformed = client
+        .put("/api/user")
+        .header(ContentType::JSON)
+        .body("{")
+        .dispatch();
+    assert_eq!(Status::BadRequest, malformed.status());
+}
+
+#[test]
+fn test_invalid_names_never_reach_the_store() {
+    let client = logged_in_client(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#);
+    for uri in [
+        "/api/user/.hidden",
+        "/api/user/..%2Fusers%2Froot",
+        "/api/group/a%5Cb",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(Status::UnprocessableEntity, response.status(), "{uri}");
+        assert_eq!(
+            "InvalidName",
+            response.into_json::<json::Value>().unwrap()["code"]
+        );
+    }
+
+    let created = client
+        .put("/api/user")
+        .header(ContentType::JSON)
+        .body(r#"{"login_name": "../root", "groups": [], "policy_statements": []}"#)
+        .dispatch();
+    assert_eq!(Status::UnprocessableEntity, created.status());
+    let body = created.into_json::<json::Value>().unwrap();
+    assert_eq!("login_name", body["details"]["errors"][0]["field"]);
+    assert_eq!(
+        "invalid name '../root': may only contain letters, digits, '.', '_', '-' and '@'",
+        body["details"]["errors"][0]["message"]
+    );
+
+    let added = client
+        .post("/api/group/admins/members")
+        .header(ContentType::JSON)
+        .body(r#"{"add": ["dan", ".hidden"]}"#)
+        .dispatch();
+    assert_eq!(Status::UnprocessableEntity, added.status());
+    let body = added.into_json::<json::Value>().unwrap();
+    assert_eq!("add[1]", body["details"]["errors"][0]["field"]);
+}
+
+#[test]
+fn test_exported_secrets_need_their_own_permission() {
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]},
+            {"effect": "Deny", "actions": ["ExportPolicyStore"], "resources": ["policy-store:secrets"]}]"#,
+    );
+    let exported = client.get("/api/export?format=yaml").dispatch();
+    assert_eq!(Status::Ok, exported.status());
+    assert_eq!(
+        Some(ContentType::new("application", "yaml")),
+        exported.content_type()
+    );
+    let bundle = exported.into_string().unwrap();
+    assert!(bundle.contains("login_name: dan"));
+
+    let secrets = client.get("/api/export?secrets=true").dispatch();
+    assert_eq!(Status::Forbidden, secrets.status());
+
+    let planned = client
+        .post("/api/import?mode=replace&dry_run=true")
+        .body(bundle.replace("full_name: null", "full_name: Dan"))
+        .dispatch();
+    assert_eq!(Status::Ok, planned.status());
+    let planned = planned.into_json::<json::Value>().unwrap();
+    assert_eq!(false, planned["applied"]);
+    assert_eq!("Update", planned["changes"][0]["action"]);
+    assert_eq!(
+        json::Value::Null,
+        client
+            .get("/api/user/dan")
+            .dispatch()
+            .into_json::<json::Value>()
+            .unwrap()["full_name"]
+    );
+}
+
+#[test]
+fn test_imported_secrets_need_their_own_permission() {
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]},
+            {"effect": "Deny", "actions": ["ImportPolicyStore"], "resources": ["policy-store:secrets"]}]"#,
+    );
+    let bundle = client
+        .get("/api/export")
+        .dispatch()
+        .into_json::<json::Value>()
+        .unwrap();
+    assert_eq!(
+        Status::Ok,
+        client
+            .post("/api/import?dry_run=true")
+            .body(bundle.to_string())
+            .dispatch()
+            .status()
+    );
+
+    let mut with_hash = bundle;
+    with_hash["users"][0]["password_hash"] = json::Value::from("$6$forged");
+    let imported = client
+        .post("/api/import")
+        .body(with_hash.to_string())
+        .dispatch();
+    assert_eq!(Status::Forbidden, imported.status());
+    let login = client
+        .post("/api/login")
+        .header(ContentType::Form)
+        .body("login_name=dan&password=pw")
+        .dispatch();
+    assert_eq!(Status::Ok, login.status());
+}
+
+#[test]
+fn test_startup_reconciles_with_the_declared_policy() {
+    let dir = env::temp_dir().join(format!("swaf-app-declared-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let launch = |declared: &str| {
+        let path = dir.join("declared.json");
+        std::fs::write(&path, declared).unwrap();
+        let figment = rocket::Config::figment()
+            .merge(("file_root", dir.join("files")))
+            .merge(("policy_store_root", dir.join("policy")))
+            .merge(("hook_root", dir.join("hooks")))
+            .merge(("hook_shell", "sh"))
+            .merge(("audit_log", dir.join("audit.log")))
+            .merge(("declared_policy", path));
+        let policy_store = SqlitePolicyStore::open_in_memory().unwrap();
+        let rocket = launch_with(
+            rocket::custom(figment),
+            Box::new(Blocking::new(policy_store)),
+        );
+        // Rocket's errors panic when dropped without being looked at.
+        Client::tracked(rocket).map_err(|e| e.kind().to_string())
+    };
+
+    let client = launch(
+        r#"{"version": 1,
+            "users": [{"login_name": "dan", "groups": [], "policy_statements":
+                [{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]}]}"#,
+    )
+    .unwrap();
+    let policy_store = client.rocket().state::<BoxedPolicyStore>().unwrap();
+    assert!(rocket::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(policy_store.user_named(&"dan".parse().unwrap()))
+        .is_ok());
+
+    assert!(launch(r#"{"version": 1, "users": [{"login_name": "dan"}]}"#).is_err());
+}
+
+#[test]
+fn test_joining_a_denying_group_cant_lock_out() {
+    let client = logged_in_client(r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"]}]"#);
+    let created = client
+        .put("/api/group")
+        .header(ContentType::JSON)
+        .body(r#"{"name": "jail", "policy_statements": [{"effect": "Deny", "actions": ["*"], "resources": ["*"]}]}"#)
+        .dispatch();
+    assert_eq!(Status::Ok, created.status());
+
+    let joined = client.put("/api/group/jail/member/dan").dispatch();
+    assert_eq!(Status::Conflict, joined.status());
+    assert_eq!(
+        "LockOut",
+        joined.into_json::<json::Value>().unwrap()["code"]
+    );
+
+    let created = client
+        .put("/api/user")
+        .header(ContentType::JSON)
+        .body(r#"{"login_name": "eve", "groups": [], "policy_statements": [{"effect": "Deny", "actions": ["*"], "resources": ["*"]}]}"#)
+        .dispatch();
+    assert_eq!(Status::Ok, created.status());
+
+    let forced = client
+        .put("/api/group/jail/member/dan?force=true")
+        .dispatch();
+    assert_eq!(Status::Ok, forced.status());
+}
+
+#[test]
+fn test_forwarded_headers_are_only_believed_from_trusted_proxies() {
+    const HTTPS_FROM_PROXY: &str = r#"[{"effect": "Allow", "actions": ["*"], "resources": ["*"],
+        "conditions": {"https_only": true, "source_ip": ["203.0.113.0/24"]}}]"#;
+    let proxy = "10.0.0.2:4000".parse().unwrap();
+    let forwarded = |client: &Client| {
+        client
+            .get("/api/user/dan")
+            .remote(proxy)
+            .header(Header::new("X-Forwarded-Proto", "https"))
+            .header(Header::new("X-Real-IP", "203.0.113.9"))
+            .dispatch()
+            .status()
+    };
+
+    let client = logged_in_client(HTTPS_FROM_PROXY);
+    assert_eq!(Status::Forbidden, forwarded(&client));
+
+    let client = configured_logged_in_client(HTTPS_FROM_PROXY, |figment| {
+        figment.merge(("trusted_proxies", ["10.0.0.0/8"]))
+    });
+    assert_eq!(Status::Ok, forwarded(&client));
+}
+
+/// Creates `home/dan/a.txt` and `home/eve/a.txt` under the test file root,
+/// which is returned.
+fn home_folders() -> PathBuf {
+    let files = env::temp_dir()
+        .join(format!("swaf-app-{}", std::process::id()))
+        .join("files");
+    for user in ["dan", "eve"] {
+        std::fs::create_dir_all(files.join("home").join(user)).unwrap();
+        std::fs::write(files.join("home").join(user).join("a.txt"), user).unwrap();
+    }
+    files
+}
+
+#[test]
+fn test_file_data_is_authorized_by_logical_path() {
+    home_folders();
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["file:Read", "file:Write"], "resources": ["home/${user}/*"]}]"#,
+    );
+    let downloaded = client.get("/api/file/home/dan/a.txt").dispatch();
+    assert_eq!(Status::Ok, downloaded.status());
+    assert_eq!("dan", downloaded.into_string().unwrap());
+    assert_eq!(
+        Status::Forbidden,
+        client.get("/api/file/home/eve/a.txt").dispatch().status()
+    );
+
+    let uploaded = client
+        .put("/api/file/home/dan/b.txt")
+        .body("uploaded")
+        .dispatch();
+    assert_eq!(Status::Ok, uploaded.status());
+    assert_eq!(
+        "uploaded",
+        client
+            .get("/api/file/home/dan/b.txt")
+            .dispatch()
+            .into_string()
+            .unwrap()
+    );
+    assert_eq!(
+        Status::Forbidden,
+        client
+            .put("/api/file/home/eve/b.txt")
+            .body("uploaded")
+            .dispatch()
+            .status()
+    );
+}
+
+#[test]
+fn test_file_data_matches_v2_path_globs() {
+    let files = home_folders();
+    std::fs::create_dir_all(files.join("home").join("dan").join("nested")).unwrap();
+    std::fs::write(
+        files.join("home").join("dan").join("nested").join("c.txt"),
+        "c",
+    )
+    .unwrap();
+    let client = logged_in_client(
+        r#"[{"version": 2, "effect": "Allow", "actions": ["file:Read"], "resources": ["home/*/a.txt", "home/dan/*"]}]"#,
+    );
+    for (path, status) in [
+        ("home/dan/a.txt", Status::Ok),
+        ("home/eve/a.txt", Status::Ok),
+        // `*` stops at `/` in version 2 statements.
+        ("home/dan/nested/c.txt", Status::Forbidden),
+    ] {
+        let response = client.get(format!("/api/file/{path}")).dispatch();
+        assert_eq!(status, response.status(), "{path}");
+    }
+}
+
+#[test]
+fn test_signed_urls_grant_only_what_was_signed() {
+    home_folders();
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["file:Read", "file:Write"], "resources": ["home/dan/*"]}]"#,
+    );
+    let signed = client
+        .post("/api/sign")
+        .header(ContentType::JSON)
+        .body(r#"{"path": "home/dan/a.txt", "method": "GET"}"#)
+        .dispatch()
+        .into_json::<json::Value>()
+        .unwrap();
+    let url = signed["url"].as_str().unwrap();
+    client.get("/api/logout").dispatch();
+
+    let downloaded = client.get(url).dispatch();
+    assert_eq!(Status::Ok, downloaded.status());
+    assert_eq!("dan", downloaded.into_string().unwrap());
+
+    // The signature covers the method and the path.
+    let uploaded = client.put(url).body("overwritten").dispatch();
+    assert_eq!(Status::Forbidden, uploaded.status());
+    let moved = url.replace("home/dan/a.txt", "home/eve/a.txt");
+    assert_eq!(Status::Forbidden, client.get(moved).dispatch().status());
+    let last = if url.ends_with('0') { '1' } else { '0' };
+    let tampered = format!("{}{last}", &url[..url.len() - 1]);
+    assert_eq!(Status::Forbidden, client.get(tampered).dispatch().status());
+
+    let signer = client.rocket().state::<UrlSigner>().unwrap();
+    let expired = SignedUrlClaims {
+        method: SignedMethod::Get,
+        logical_path: "home/dan/a.txt",
+        expires: now_as_secs().unwrap() - 1,
+        login_name: "dan",
+    };
+    let expired = expired.to_url(&signer.sign(&expired));
+    assert_eq!(Status::Forbidden, client.get(expired).dispatch().status());
+}
+
+#[test]
+fn test_file_and_signing_errors_have_bodies() {
+    home_folders();
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["file:Read"], "resources": ["home/dan/*"]}]"#,
+    );
+    let missing = client.get("/api/file/home/dan/missing.txt").dispatch();
+    assert_eq!(Status::NotFound, missing.status());
+    assert_eq!(
+        "NotFound",
+        missing.into_json::<json::Value>().unwrap()["code"]
+    );
+
+    let unsignable = client
+        .post("/api/sign")
+        .header(ContentType::JSON)
+        .body(r#"{"path": "home/dan/a.txt", "method": "DELETE"}"#)
+        .dispatch();
+    assert_eq!(Status::UnprocessableEntity, unsignable.status());
+    let body = unsignable.into_json::<json::Value>().unwrap();
+    assert_eq!("Invalid", body["code"]);
+    assert_eq!("method", body["details"]["errors"][0]["field"]);
+}
+
+#[test]
+fn test_audit_log_has_a_default_path() {
+    let config: Config = Figment::new()
+        .merge(("file_root", "files"))
+        .merge(("policy_store_root", "policy"))
+        .merge(("hook_root", "hooks"))
+        .merge(("hook_shell", "sh"))
+        .extract()
+        .unwrap();
+    assert_eq!(PathBuf::from("audit/audit.log"), config.audit_log);
+}
+
+#[test]
+fn test_listing_members_with_either_permission_records_no_denial() {
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["CreateGroup", "ManageGroupMembers"], "resources": ["group:member-managers"]}]"#,
+    );
+    let created = client
+        .put("/api/group")
+        .header(ContentType::JSON)
+        .body(r#"{"name": "member-managers", "policy_statements": []}"#)
+        .dispatch();
+    assert_eq!(Status::Ok, created.status());
+    let members = client.get("/api/group/member-managers/members").dispatch();
+    assert_eq!(Status::Ok, members.status());
+
+    let denials = client
+        .rocket()
+        .state::<AuditLog>()
+        .unwrap()
+        .query(&AuditQuery {
+            action: Some(String::from("ListGroups")),
+            path_prefix: Some(String::from("group:member-managers")),
+            ..AuditQuery::default()
+        })
+        .unwrap();
+    assert!(denials.is_empty());
+
+    assert_eq!(
+        Status::Forbidden,
+        client.get("/api/group/other/members").dispatch().status()
+    );
+}
+
+#[test]
+fn test_rollback_is_authorized_before_anything_is_looked_up() {
+    let client = logged_in_client(
+        r#"[{"effect": "Allow", "actions": ["UpdateUser", "UpdateGroup", "UpdatePolicy"], "resources": ["user:dan"]}]"#,
+    );
+    for uri in [
+        "/api/user/dan/rollback/99",
+        "/api/user/nobody/rollback/1",
+        "/api/group/nobody/rollback/1",
+        "/api/policy/nobody/rollback/1",
+    ] {
+        let expected = if uri.contains("dan") {
+            Status::NotFound
+        } else {
+            Status::Forbidden
+        };
+        assert_eq!(expected, client.post(uri).dispatch().status(), "{uri}");
+    }
+}
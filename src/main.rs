@@ -1,7 +1,26 @@
 #[macro_use]
 extern crate rocket;
 
-#[launch]
-fn rocket() -> _ {
-    swaf::launch()
+use std::env;
+use std::process::ExitCode;
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        return match swaf::run_command(&args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    match swaf::launch().launch().await {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
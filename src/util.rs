@@ -1,6 +1,10 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 use wildflower::Pattern;
 
+#[cfg(test)]
+#[path = "util_tests.rs"]
+mod util_tests;
+
 pub fn now_as_secs() -> Result<u64, ()> {
     let duration = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -12,3 +16,140 @@ pub fn glob_matches(glob: &str, s: &str) -> bool {
     // For now we're just wrapping wildflower. We may want to improve on this.
     Pattern::new(glob).matches(s)
 }
+
+/// Escapes glob metacharacters so `s` only ever matches itself.
+pub fn glob_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '*' | '?' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+enum PathGlobToken {
+    Literal(char),
+    AnyChar,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Star,
+    DoubleStar,
+    // `**/`, which matches zero or more whole directories.
+    DoubleStarSlash,
+}
+
+impl PathGlobToken {
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            PathGlobToken::Literal(l) => *l == c,
+            PathGlobToken::AnyChar => c != '/',
+            PathGlobToken::Class { negated, ranges } => {
+                c != '/' && ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_path_glob(glob: &str) -> Vec<PathGlobToken> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(PathGlobToken::Literal(chars[i + 1]));
+                i += 2;
+            }
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') {
+                    tokens.push(PathGlobToken::DoubleStarSlash);
+                    i += 3;
+                } else {
+                    tokens.push(PathGlobToken::DoubleStar);
+                    i += 2;
+                }
+            }
+            '*' => {
+                tokens.push(PathGlobToken::Star);
+                i += 1;
+            }
+            '?' => {
+                tokens.push(PathGlobToken::AnyChar);
+                i += 1;
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    tokens.push(class);
+                    i += len + 1;
+                }
+                None => {
+                    tokens.push(PathGlobToken::Literal('['));
+                    i += 1;
+                }
+            },
+            c => {
+                tokens.push(PathGlobToken::Literal(c));
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses a character class following its opening `[`, returning the class
+/// and the number of characters consumed including the closing `]`.
+fn parse_class(chars: &[char]) -> Option<(PathGlobToken, usize)> {
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    let mut i = usize::from(negated);
+    let mut ranges = Vec::new();
+    // A `]` immediately after the opening bracket is taken literally.
+    let start = i;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ']' && i > start {
+            return Some((PathGlobToken::Class { negated, ranges }, i + 1));
+        }
+        if chars.get(i + 1) == Some(&'-') && !matches!(chars.get(i + 2), None | Some(']')) {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Matches `s` against a path-aware glob: `*` and `?` never match `/`, `**`
+/// matches across directories, `**/` matches zero or more directories, and
+/// `[...]` matches a character class (`[!...]` negates it).
+pub fn path_glob_matches(glob: &str, s: &str) -> bool {
+    let tokens = parse_path_glob(glob);
+    let s: Vec<char> = s.chars().collect();
+    let n = s.len();
+    // next[j] holds whether tokens[i + 1..] match s[j..] while computing row i.
+    let mut next: Vec<bool> = (0..=n).map(|j| j == n).collect();
+    for token in tokens.iter().rev() {
+        let mut row = vec![false; n + 1];
+        // Whether some `/` at or after j is followed by a match of the rest.
+        let mut dir_match = false;
+        for j in (0..=n).rev() {
+            row[j] = match token {
+                PathGlobToken::Star => next[j] || (j < n && s[j] != '/' && row[j + 1]),
+                PathGlobToken::DoubleStar => next[j] || (j < n && row[j + 1]),
+                PathGlobToken::DoubleStarSlash => {
+                    dir_match |= j < n && s[j] == '/' && next[j + 1];
+                    next[j] || dir_match
+                }
+                _ => j < n && token.matches_char(s[j]) && next[j + 1],
+            };
+        }
+        next = row;
+    }
+    next[0]
+}
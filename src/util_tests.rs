@@ -0,0 +1,46 @@
+use super::*;
+
+#[test]
+fn test_path_glob_star_stops_at_slash() {
+    assert!(path_glob_matches("projects/*", "projects/a"));
+    assert!(!path_glob_matches("projects/*", "projects/a/b"));
+    assert!(!path_glob_matches("projects/*", "projects"));
+}
+
+#[test]
+fn test_path_glob_double_star_crosses_slash() {
+    assert!(path_glob_matches("projects/**", "projects/a"));
+    assert!(path_glob_matches("projects/**", "projects/a/b/c"));
+    assert!(!path_glob_matches("projects/**", "other/a"));
+}
+
+#[test]
+fn test_path_glob_double_star_slash_matches_zero_or_more_dirs() {
+    assert!(path_glob_matches("a/**/b.txt", "a/b.txt"));
+    assert!(path_glob_matches("a/**/b.txt", "a/x/y/b.txt"));
+    assert!(!path_glob_matches("a/**/b.txt", "a/xb.txt"));
+    assert!(path_glob_matches("**/*.txt", "notes.txt"));
+    assert!(path_glob_matches("**/*.txt", "x/y/notes.txt"));
+}
+
+#[test]
+fn test_path_glob_question_mark() {
+    assert!(path_glob_matches("file?.txt", "file1.txt"));
+    assert!(!path_glob_matches("file?.txt", "file10.txt"));
+    assert!(!path_glob_matches("a?b", "a/b"));
+}
+
+#[test]
+fn test_path_glob_character_classes() {
+    assert!(path_glob_matches("log[0-9].txt", "log7.txt"));
+    assert!(!path_glob_matches("log[0-9].txt", "logx.txt"));
+    assert!(path_glob_matches("log[!0-9].txt", "logx.txt"));
+    assert!(path_glob_matches("[]a]", "]"));
+    assert!(path_glob_matches("a[", "a["));
+}
+
+#[test]
+fn test_path_glob_escapes() {
+    assert!(path_glob_matches(&glob_escape("a*[b]"), "a*[b]"));
+    assert!(!path_glob_matches(&glob_escape("a*"), "abc"));
+}